@@ -0,0 +1,60 @@
+//! Minimal HTTP/3 client for trying the experimental listener on localhost:
+//!
+//! ```bash
+//! $ syserve ca create
+//! $ syserve --ca --http3 &
+//! $ cargo run --example h3_client --features http3 -- \
+//!     --cacert ~/.config/syserve/ca/ca.pem https://localhost:7878/
+//! ```
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use argparse::{ArgumentParser, Store, StoreConst};
+
+fn main() {
+    let mut url = String::new();
+    let mut ca = PathBuf::new();
+    let mut method = String::from("GET");
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("Send a request over HTTP/3 and print the response");
+        parser
+            .refer(&mut url)
+            .add_argument("url", Store, "URL to request, e.g. https://localhost:7878/")
+            .required();
+        parser
+            .refer(&mut ca)
+            .add_option(
+                &["--cacert"],
+                Store,
+                "PEM certificate the server certificate is verified against",
+            )
+            .required();
+        parser.refer(&mut method).add_option(
+            &["-I", "--head"],
+            StoreConst(String::from("HEAD")),
+            "Send a HEAD request",
+        );
+        parser.parse_args_or_exit();
+    }
+
+    let result = fs::read_to_string(&ca)
+        .map_err(|e| syserve::Error::Fs(ca.clone(), e))
+        .and_then(|ca| syserve::http3::fetch(&method, &url, &ca));
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("HTTP/3 {}", response.status);
+    for (name, value) in &response.headers {
+        println!("{}: {}", name, value);
+    }
+    println!();
+    io::stdout().write_all(&response.body).unwrap();
+}
@@ -0,0 +1,267 @@
+//! Running a build command when sources change
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::diagnostics::{self, Line};
+
+/// Progress of builds, shared with connection handlers waiting for a build to finish
+struct State {
+    /// Whether a build is in progress
+    building: bool,
+    /// Error overlay of the last finished build if it failed, see `diagnostics::overlay`.
+    /// `None` before the first build
+    failure: Option<String>,
+}
+
+/// Runs the build command, one build at a time
+pub struct Builder {
+    /// Shell command line, e.g. `trunk build`
+    command: String,
+    /// Paths whose changes do not trigger builds, e.g. the build output
+    ignored: Vec<PathBuf>,
+    state: Mutex<State>,
+    /// Notified whenever a build finishes
+    finished: Condvar,
+}
+
+impl Builder {
+    pub fn new(command: String, ignored: Vec<PathBuf>) -> Builder {
+        Builder {
+            command,
+            ignored,
+            state: Mutex::new(State {
+                building: false,
+                failure: None,
+            }),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Whether a build is in progress
+    pub fn is_building(&self) -> bool {
+        self.state.lock().unwrap().building
+    }
+
+    /// Whether the last finished build succeeded
+    pub fn last_succeeded(&self) -> bool {
+        self.state.lock().unwrap().failure.is_none()
+    }
+
+    /// Error overlay of the last build, if it failed
+    pub fn failure(&self) -> Option<String> {
+        self.state.lock().unwrap().failure.clone()
+    }
+
+    /// Block until no build is in progress, for at most `timeout`.
+    /// Returns whether the build finished in time.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .finished
+            .wait_timeout_while(state, timeout, |state| state.building)
+            .unwrap();
+        !state.building
+    }
+
+    /// Build once, then again after every batch of source changes.
+    /// Changes arriving during a build are coalesced into a single following build.
+    /// `finished` is called after every build with its error overlay if it failed.
+    pub fn run_on_changes<F>(&self, changes: Receiver<Vec<PathBuf>>, mut finished: F)
+    where
+        F: FnMut(Option<&str>),
+    {
+        self.run();
+        finished(self.failure().as_deref());
+
+        while let Ok(batch) = changes.recv() {
+            let mut relevant = self.relevant(&batch);
+            // Builds may take a while, gather everything that queued up in the meantime
+            while let Ok(batch) = changes.try_recv() {
+                relevant += self.relevant(&batch);
+            }
+
+            if relevant > 0 {
+                info!("{} source files changed, rebuilding", relevant);
+                self.run();
+                finished(self.failure().as_deref());
+            }
+        }
+    }
+
+    /// Number of changed paths that are not ignored
+    fn relevant(&self, paths: &[PathBuf]) -> usize {
+        paths
+            .iter()
+            .filter(|path| !self.ignored.iter().any(|ignored| path.starts_with(ignored)))
+            .count()
+    }
+
+    /// Run the build command, streaming its output to the log.
+    /// Returns whether it succeeded.
+    pub fn run(&self) -> bool {
+        self.state.lock().unwrap().building = true;
+        info!("Build started: {}", self.command);
+        let start = Instant::now();
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let failure = match self.execute(&output) {
+            Ok(status) if status.success() => {
+                info!("Build succeeded in {:.1?}", start.elapsed());
+                None
+            }
+            Ok(status) => {
+                error!("Build failed ({}) after {:.1?}", status, start.elapsed());
+                Some(diagnostics::overlay(&output.lock().unwrap()))
+            }
+            Err(e) => {
+                error!("Build failed: could not run {:?}: {}", self.command, e);
+                let message = format!("Could not run {:?}: {}", self.command, e);
+                Some(diagnostics::overlay(&[Line::Text(message)]))
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.building = false;
+        state.failure = failure;
+        self.finished.notify_all();
+
+        state.failure.is_none()
+    }
+
+    /// Spawn the command through the shell and wait for it, logging its output and
+    /// collecting it in `output`
+    fn execute(&self, output: &Arc<Mutex<Vec<Line>>>) -> io::Result<ExitStatus> {
+        let mut child = shell(&self.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout = child.stdout.take().map(|out| {
+            let output = Arc::clone(output);
+            thread::spawn(move || collect_lines(out, &output))
+        });
+        let stderr = child.stderr.take().map(|err| {
+            let output = Arc::clone(output);
+            thread::spawn(move || collect_lines(err, &output))
+        });
+        for reader in stdout.into_iter().chain(stderr) {
+            let _ = reader.join();
+        }
+
+        child.wait()
+    }
+}
+
+/// Command running `command` through the platform shell
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
+
+/// Log and collect every line of build output.
+/// Compiler messages in JSON are logged as rendered by the compiler, other JSON
+/// messages of cargo are not logged.
+fn collect_lines<R: Read>(output: R, lines: &Mutex<Vec<Line>>) {
+    for line in BufReader::new(output).split(b'\n') {
+        let line = match line {
+            Ok(line) => Line::parse(String::from_utf8_lossy(&line).trim_end()),
+            Err(e) => {
+                warn!("Error reading build output: {}", e);
+                return;
+            }
+        };
+
+        match &line {
+            Line::Text(text) => info!("build | {}", text),
+            Line::Diagnostic(diagnostic) => {
+                let text = diagnostic.rendered.as_ref().unwrap_or(&diagnostic.message);
+                for text in text.trim_end().lines() {
+                    info!("build | {}", text);
+                }
+            }
+            Line::Message => {}
+        }
+        lines.lock().unwrap().push(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn changes_during_a_build_are_coalesced_into_one_build() {
+        let dir = std::env::temp_dir().join(format!("syserve-build-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dist")).unwrap();
+        let log = dir.join("builds.log");
+        let command = format!(
+            "echo start >> '{0}' && sleep 0.3 && echo end >> '{0}'",
+            log.display()
+        );
+        let builder = Arc::new(Builder::new(command, vec![dir.join("dist")]));
+
+        let (changes, changes_rx) = mpsc::channel();
+        let (finished, finished_rx) = mpsc::channel();
+        {
+            let builder = Arc::clone(&builder);
+            thread::spawn(move || {
+                builder.run_on_changes(changes_rx, |failure| {
+                    let _ = finished.send(failure.is_none());
+                })
+            });
+        }
+        let timeout = Duration::from_secs(10);
+        assert_eq!(finished_rx.recv_timeout(timeout), Ok(true));
+
+        // Changes to ignored paths do not trigger builds
+        changes.send(vec![dir.join("dist/app.js")]).unwrap();
+        changes.send(vec![dir.join("src/lib.rs")]).unwrap();
+        while !builder.is_building() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!builder.wait(Duration::from_millis(10)));
+        changes.send(vec![dir.join("src/main.rs")]).unwrap();
+        changes
+            .send(vec![dir.join("src/lib.rs"), dir.join("dist/index.html")])
+            .unwrap();
+        assert_eq!(finished_rx.recv_timeout(timeout), Ok(true));
+        assert_eq!(finished_rx.recv_timeout(timeout), Ok(true));
+        assert!(builder.wait(Duration::from_millis(0)));
+
+        drop(changes);
+        assert!(finished_rx.recv_timeout(Duration::from_secs(1)).is_err());
+        // Builds never overlap
+        assert_eq!(fs::read_to_string(&log).unwrap(), "start\nend\n".repeat(3));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_builds_are_reported() {
+        let builder = Builder::new(String::from("echo broken >&2 && exit 3"), Vec::new());
+        assert!(!builder.run());
+        assert!(!builder.last_succeeded());
+        let overlay = builder.failure().unwrap();
+        assert!(overlay.contains("broken"), "{}", overlay);
+    }
+}
@@ -0,0 +1,358 @@
+//! Local certificate authority: a CA kept in the user's configuration directory,
+//! issuing certificates for development host names on demand
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    Issuer, KeyPair, KeyUsagePurpose,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use time::{Duration, OffsetDateTime};
+
+use crate::cli::{CaAction, CaArgs};
+use crate::error::{Error, Result};
+use crate::tls::{self, Identity};
+
+/// File name of the CA certificate, in PEM
+pub const CERT_FILE: &str = "ca.pem";
+/// File name of the CA private key, in PEM
+pub const KEY_FILE: &str = "ca-key.pem";
+
+/// Common name of the CA certificate
+const CA_NAME: &str = "syserve development CA";
+/// Validity of the CA certificate
+const CA_VALIDITY: Duration = Duration::days(10 * 365);
+/// Validity of issued certificates, below the 398 days accepted by browsers
+const LEAF_VALIDITY: Duration = Duration::days(397);
+/// Maximum number of certificates issued on demand, bounding the memory and key
+/// generation time spent on host names sent by clients
+const MAX_ISSUED: usize = 64;
+
+/// Default directory of the CA: `ca` in the syserve configuration directory,
+/// `$SYSERVE_CONFIG_DIR`, `$XDG_CONFIG_HOME/syserve` or `~/.config/syserve`
+/// (`%APPDATA%\syserve` on Windows)
+pub fn default_dir() -> Result<PathBuf> {
+    let config = env::var_os("SYSERVE_CONFIG_DIR")
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join("syserve"))
+            } else {
+                env::var_os("XDG_CONFIG_HOME")
+                    .map(|dir| PathBuf::from(dir).join("syserve"))
+                    .or_else(|| {
+                        env::var_os("HOME").map(|dir| PathBuf::from(dir).join(".config/syserve"))
+                    })
+            }
+        })
+        .ok_or_else(|| {
+            Error::Config(String::from(
+                "no configuration directory found for the local CA, use --ca-dir",
+            ))
+        })?;
+
+    Ok(config.join("ca"))
+}
+
+/// Run the `syserve ca` subcommand
+pub fn run(args: CaArgs) -> Result<()> {
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => default_dir()?,
+    };
+
+    match args.action {
+        CaAction::Create => {
+            let (ca, created) = LocalCa::open_or_create(&dir)?;
+            if !created {
+                println!("A local CA already exists in {}", dir.display());
+            }
+            println!(
+                "CA certificate: {}\n\
+                 Add it to the trust store of your system or browser to trust certificates \
+                 of syserve --ca",
+                ca.cert_path().display()
+            );
+        }
+        CaAction::Export => {
+            if !dir.join(CERT_FILE).is_file() {
+                return Err(Error::Config(format!(
+                    "no local CA in {:?}, create one with `syserve ca create`",
+                    dir
+                )));
+            }
+            let ca = LocalCa::open(&dir)?;
+            let contents = if args.der {
+                ca.cert_der()
+            } else {
+                ca.cert_pem().as_bytes()
+            };
+            match args.output {
+                Some(path) => fs::write(&path, contents).map_err(|e| Error::Fs(path, e))?,
+                None => io::stdout().write_all(contents).map_err(Error::Io)?,
+            }
+        }
+        CaAction::Path => println!("{}", dir.display()),
+    }
+
+    Ok(())
+}
+
+/// A certificate authority whose certificate and key are stored in a directory
+pub struct LocalCa {
+    dir: PathBuf,
+    cert_pem: String,
+    cert_der: CertificateDer<'static>,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+impl LocalCa {
+    /// Load the CA stored in `dir`
+    pub fn open(dir: &Path) -> Result<LocalCa> {
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+        let cert_pem =
+            fs::read_to_string(&cert_path).map_err(|e| Error::Fs(cert_path.clone(), e))?;
+        let key_pem = fs::read_to_string(&key_path).map_err(|e| Error::Fs(key_path, e))?;
+
+        let key = KeyPair::from_pem(&key_pem)
+            .map_err(|e| Error::Tls(format!("invalid CA private key: {}", e)))?;
+        let issuer = Issuer::from_ca_cert_pem(&cert_pem, key)
+            .map_err(|e| Error::Tls(format!("invalid CA certificate: {}", e)))?;
+        let cert_der = tls::parse_certs(&cert_pem)?.remove(0);
+
+        Ok(LocalCa {
+            dir: dir.to_path_buf(),
+            cert_pem,
+            cert_der,
+            issuer,
+        })
+    }
+
+    /// Load the CA stored in `dir`, creating it first if there is none.
+    /// Returns the CA and whether it was created
+    pub fn open_or_create(dir: &Path) -> Result<(LocalCa, bool)> {
+        if dir.join(CERT_FILE).is_file() && dir.join(KEY_FILE).is_file() {
+            return Ok((LocalCa::open(dir)?, false));
+        }
+
+        info!("Creating a local certificate authority in {:?}", dir);
+        let generate_error = |e: rcgen::Error| Error::Tls(format!("could not create a CA: {}", e));
+        let key = KeyPair::generate().map_err(generate_error)?;
+
+        let mut params = CertificateParams::default();
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, CA_NAME);
+        name.push(DnType::OrganizationName, "syserve");
+        params.distinguished_name = name;
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + CA_VALIDITY;
+        let cert = params.self_signed(&key).map_err(generate_error)?;
+
+        let fs_error = |path: PathBuf| move |e| Error::Fs(path, e);
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+        fs::create_dir_all(dir).map_err(fs_error(dir.to_path_buf()))?;
+        tls::write_private(&key_path, &key.serialize_pem()).map_err(fs_error(key_path))?;
+        fs::write(&cert_path, cert.pem()).map_err(fs_error(cert_path))?;
+
+        Ok((LocalCa::open(dir)?, true))
+    }
+
+    /// Directory the CA is stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the CA certificate, to be added to trust stores
+    pub fn cert_path(&self) -> PathBuf {
+        self.dir.join(CERT_FILE)
+    }
+
+    /// CA certificate in PEM
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// CA certificate in DER
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert_der
+    }
+
+    /// Certificate chain, issued by the CA for `names`, and its new private key
+    pub fn issue(&self, names: &[String]) -> Result<Identity> {
+        let issue_error = |e: rcgen::Error| {
+            Error::Tls(format!(
+                "could not issue a certificate for {}: {}",
+                names.join(", "),
+                e
+            ))
+        };
+        let key = KeyPair::generate().map_err(issue_error)?;
+
+        let mut params = CertificateParams::new(names.to_vec()).map_err(issue_error)?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, names.first().map_or("localhost", |s| s));
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + LEAF_VALIDITY;
+        let cert = params.signed_by(&key, &self.issuer).map_err(issue_error)?;
+
+        Ok((
+            vec![cert.der().clone(), self.cert_der.clone()],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+        ))
+    }
+}
+
+/// Certificate resolver serving certificates of a `LocalCa`: one for the configured
+/// names, and new ones issued on the first connection to other subdomains of
+/// `localhost` (see `issues_on_demand`). Other host names get the default certificate.
+pub struct Resolver {
+    ca: LocalCa,
+    default: Arc<CertifiedKey>,
+    /// Certificates issued on demand, by host name, at most `MAX_ISSUED`
+    issued: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+    names: Vec<String>,
+}
+
+impl Resolver {
+    /// Resolver whose default certificate covers `names`
+    pub fn new(ca: LocalCa, names: Vec<String>) -> Result<Resolver> {
+        info!(
+            "Issuing a certificate for {} from the local CA in {:?}",
+            names.join(", "),
+            ca.dir()
+        );
+        let default = certified_key(ca.issue(&names)?)?;
+        Ok(Resolver {
+            ca,
+            default,
+            issued: Mutex::new(HashMap::new()),
+            names,
+        })
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = match client_hello.server_name() {
+            Some(name) if !self.names.iter().any(|n| n.eq_ignore_ascii_case(name)) => {
+                name.to_ascii_lowercase()
+            }
+            _ => return Some(Arc::clone(&self.default)),
+        };
+
+        if !issues_on_demand(&name) {
+            warn!(
+                "Not issuing a certificate for {}, add it with --san to serve it",
+                name
+            );
+            return Some(Arc::clone(&self.default));
+        }
+        {
+            let issued = self.issued.lock().unwrap();
+            if let Some(key) = issued.get(&name) {
+                return Some(Arc::clone(key));
+            }
+            if issued.len() >= MAX_ISSUED {
+                warn!(
+                    "Not issuing a certificate for {}, {} were issued already",
+                    name, MAX_ISSUED
+                );
+                return Some(Arc::clone(&self.default));
+            }
+        }
+
+        // Keys are generated without holding the lock, other handshakes go on meanwhile
+        info!("Issuing a certificate for {} from the local CA", name);
+        match self
+            .ca
+            .issue(std::slice::from_ref(&name))
+            .and_then(certified_key)
+        {
+            Ok(key) => {
+                let mut issued = self.issued.lock().unwrap();
+                if issued.len() < MAX_ISSUED {
+                    issued.insert(name, Arc::clone(&key));
+                }
+                Some(key)
+            }
+            Err(e) => {
+                warn!("{}", e);
+                Some(Arc::clone(&self.default))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("dir", &self.ca.dir)
+            .field("names", &self.names)
+            .finish()
+    }
+}
+
+/// Whether a certificate is issued on demand for `name`: only for subdomains of
+/// `localhost`, which resolve to the loopback interface (RFC 6761). Clients on the
+/// network must not get certificates signed by the trusted CA for arbitrary domains.
+fn issues_on_demand(name: &str) -> bool {
+    name.len() <= 253
+        && name
+            .strip_suffix(".localhost")
+            .is_some_and(|subdomain| subdomain.split('.').all(|label| !label.is_empty()))
+}
+
+fn certified_key((certs, key): Identity) -> Result<Arc<CertifiedKey>> {
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| Error::Tls(format!("unsupported private key: {}", e)))?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issues_only_for_localhost_subdomains() {
+        let cases: &[(&str, bool)] = &[
+            ("api.localhost", true),
+            ("a.b.localhost", true),
+            ("localhost", false),
+            (".localhost", false),
+            ("a..localhost", false),
+            ("..localhost", false),
+            ("notlocalhost", false),
+            ("localhost.example.com", false),
+            ("www.google.com", false),
+            ("192.168.1.20", false),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(issues_on_demand(name), *expected, "{:?}", name);
+        }
+        let long = format!("{}.localhost", "a".repeat(250));
+        assert!(!issues_on_demand(&long));
+    }
+}
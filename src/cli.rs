@@ -1,8 +1,12 @@
 //! Command line arguments, parsing, and verification
 
-use argparse::{ArgumentParser, Store, StoreOption};
+use argparse::{ArgumentParser, Collect, Store, StoreFalse, StoreOption, StoreTrue};
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::sandbox::SymlinkPolicy;
+use crate::url::TrailingSlash;
 
 #[derive(Clone)]
 /// Struct for cli arguments.
@@ -11,32 +15,173 @@ pub struct Args {
     pub dir: PathBuf,
     /// Index file name in `dir`.
     pub index: String,
-    /// 404 file name in `dir`. If `None`, the 404 error message is returned
+    /// Index file names looked up in requested directories. If empty, `index`
+    /// and `index.htm` are used
+    pub dir_indexes: Vec<String>,
+    /// Handling of trailing slashes on directories and files
+    pub trailing_slash: TrailingSlash,
+    /// List directories without an index file
+    pub listing: bool,
+    /// Include hidden files in directory listings
+    pub show_hidden: bool,
+    /// 404 page in `dir`, served with a 404 status. If `None`, the 404 error message is returned
     pub not_found: Option<String>,
+    /// Serve `index` for navigations to missing paths, for yew-router apps
+    pub spa: bool,
+    /// Globs of paths always treated as client-side routes in SPA mode
+    pub spa_routes: Vec<String>,
     /// Address for serving
     pub address: SocketAddr,
     /// IP for serving
     pub ip: String,
     /// Port for serving
     pub port: u16,
+    /// Seconds an idle persistent connection is kept open. `0` disables keep-alive
+    pub keep_alive: u64,
+    /// Maximum size in bytes of a request line and headers
+    pub max_header_size: usize,
+    /// Maximum number of request headers
+    pub max_headers: usize,
+    /// Maximum size in bytes of a request body
+    pub max_body_size: usize,
+    /// Send file bodies with `sendfile(2)` (Linux only)
+    pub sendfile: bool,
+    /// Compress compressible files on the fly for clients accepting gzip, deflate or
+    /// Brotli (with the `brotli` feature)
+    pub compress: bool,
+    /// Minimum size in bytes of files compressed on the fly
+    pub compress_min_size: u64,
+    /// Seconds given to requests in progress to finish on shutdown
+    pub grace_period: u64,
+    /// How symbolic links inside `dir` are treated
+    pub symlinks: SymlinkPolicy,
+    /// Reload pages in the browser when files in `dir` change
+    pub live_reload: bool,
+    /// Command run when sources change, e.g. `trunk build`
+    pub build: Option<String>,
+    /// Sources watched for `build`. If empty, `src` and `Cargo.toml` are used
+    pub watch: Vec<PathBuf>,
+    /// Serve over HTTPS (requires the `tls` feature)
+    pub tls: bool,
+    /// PEM certificate chain for `tls`. If `None`, a self-signed certificate is generated
+    pub cert: Option<PathBuf>,
+    /// PEM private key of `cert`
+    pub key: Option<PathBuf>,
+    /// Host names and IP addresses added to the generated certificate, besides `localhost`
+    pub sans: Vec<String>,
+    /// Directory where the generated certificate is kept for reuse
+    pub cert_dir: Option<PathBuf>,
+    /// Serve over HTTPS with certificates issued by the local CA
+    pub ca: bool,
+    /// Directory of the local CA. If `None`, `ca` in the configuration directory is used
+    pub ca_dir: Option<PathBuf>,
+    /// PEM bundle of the CAs client certificates are verified against.
+    /// If `None`, clients are not asked for certificates
+    pub client_ca: Option<PathBuf>,
+    /// Handling of clients without a valid certificate when `client_ca` is set
+    pub client_auth: ClientAuth,
+    /// Plain HTTP port redirecting every request to HTTPS. If `None`, only `port` is bound
+    pub redirect_port: Option<u16>,
+    /// Status of redirects to HTTPS, `301` or `308`
+    pub redirect_status: u16,
+    /// `max-age` in seconds of the `Strict-Transport-Security` header sent over HTTPS.
+    /// If `None`, the header is not sent
+    pub hsts: Option<u64>,
+    /// Offer HTTP/2 to TLS clients with ALPN, besides HTTP/1.1
+    pub http2: bool,
+    /// Accept HTTP/2 with prior knowledge (h2c) on plain TCP connections
+    pub h2c: bool,
+    /// Also serve HTTP/3 over QUIC on `port` (UDP, requires the `http3` feature)
+    pub http3: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Args {
     /// Generate `Args` instance with default values:
     /// - `dir`: current directory (`./`)
     /// - `index`: `index.html` in the current directory
+    /// - `dir_indexes`: none (`index` and `index.htm`)
+    /// - `trailing_slash`: `TrailingSlash::Redirect`
+    /// - `listing`: `false`
+    /// - `show_hidden`: `false`
     /// - `not_found`: `None` (404 error is returned)
+    /// - `spa`: `false`
+    /// - `spa_routes`: none
     /// - `address`: 127.0.0.1:7878
     /// - `ip`: 127.0.0.1
     /// - `port`: 7878
+    /// - `keep_alive`: 5 seconds
+    /// - `max_header_size`: 16384 bytes
+    /// - `max_headers`: 64
+    /// - `max_body_size`: 1048576 bytes
+    /// - `sendfile`: `false`
+    /// - `compress`: `true`
+    /// - `compress_min_size`: 1024 bytes
+    /// - `grace_period`: 10 seconds
+    /// - `symlinks`: `SymlinkPolicy::FollowWithinRoot`
+    /// - `live_reload`: `false`
+    /// - `build`: `None` (nothing is built)
+    /// - `watch`: none (`src` and `Cargo.toml`)
+    /// - `tls`: `false`
+    /// - `cert`, `key`: `None` (a self-signed certificate is generated)
+    /// - `sans`: none
+    /// - `cert_dir`: `None` (the generated certificate is not kept)
+    /// - `ca`: `false`
+    /// - `ca_dir`: `None` (the configuration directory)
+    /// - `client_ca`: `None` (no client certificates)
+    /// - `client_auth`: `ClientAuth::Require`
+    /// - `redirect_port`: `None` (no redirect listener)
+    /// - `redirect_status`: 308
+    /// - `hsts`: `None` (no `Strict-Transport-Security` header)
+    /// - `http2`: `true`
+    /// - `h2c`: `false`
+    /// - `http3`: `false`
     pub fn new() -> Args {
         Args {
             dir: PathBuf::from("./"),
             index: String::from("index.html"),
+            dir_indexes: Vec::new(),
+            trailing_slash: TrailingSlash::default(),
+            listing: false,
+            show_hidden: false,
             not_found: None,
+            spa: false,
+            spa_routes: Vec::new(),
             address: "127.0.0.1:7878".parse().unwrap(),
             ip: String::from("127.0.0.1"),
             port: 7878,
+            keep_alive: 5,
+            max_header_size: 16 * 1024,
+            max_headers: 64,
+            max_body_size: 1024 * 1024,
+            sendfile: false,
+            compress: true,
+            compress_min_size: 1024,
+            grace_period: 10,
+            symlinks: SymlinkPolicy::default(),
+            live_reload: false,
+            build: None,
+            watch: Vec::new(),
+            tls: false,
+            cert: None,
+            key: None,
+            sans: Vec::new(),
+            cert_dir: None,
+            ca: false,
+            ca_dir: None,
+            client_ca: None,
+            client_auth: ClientAuth::default(),
+            redirect_port: None,
+            redirect_status: 308,
+            hsts: None,
+            http2: true,
+            h2c: false,
+            http3: false,
         }
     }
 
@@ -60,9 +205,46 @@ impl Args {
             return Err("Index file does not exist in given directory");
         };
         // 404 check
-        if !self.not_found.is_none() && !self.dir.join(self.not_found.as_ref().unwrap()).is_file() {
+        if self.not_found.is_some() && !self.dir.join(self.not_found.as_ref().unwrap()).is_file() {
             return Err("404 file does not exist in given directory");
         };
+        // Watched sources check
+        if self.watch.iter().any(|path| !path.exists()) {
+            return Err("Watched path does not exist");
+        };
+        // Certificate check
+        if self.cert.is_some() != self.key.is_some() {
+            return Err("Certificate and key must be given together");
+        };
+        if self.ca && self.cert.is_some() {
+            return Err("A certificate cannot be given with --ca");
+        };
+        // Client CA check
+        if let Some(client_ca) = &self.client_ca {
+            if !client_ca.is_file() {
+                return Err("Client CA bundle does not exist");
+            }
+            if !self.tls && !self.ca {
+                return Err("Client certificates require --tls or --ca");
+            }
+        };
+        // HTTPS redirect check
+        if (self.redirect_port.is_some() || self.hsts.is_some()) && !self.tls && !self.ca {
+            return Err("Redirects to HTTPS and HSTS require --tls or --ca");
+        };
+        if self.redirect_port == Some(self.port) {
+            return Err("Redirect port must differ from the HTTPS port");
+        };
+        if self.redirect_status != 301 && self.redirect_status != 308 {
+            return Err("Redirect status must be 301 or 308");
+        };
+        // HTTP/2 check
+        if self.h2c && (self.tls || self.ca) {
+            return Err("--h2c only applies to plain HTTP, HTTPS uses ALPN");
+        };
+        if self.http3 && !self.tls && !self.ca {
+            return Err("HTTP/3 requires --tls or --ca");
+        };
         // Address check
         let address: String = format!("{}:{}", self.ip, self.port);
         if address.parse::<SocketAddr>().is_err() {
@@ -89,10 +271,40 @@ impl Args {
             Store,
             "Index file to be served. Default: index.html",
         );
+        parser.refer(&mut self.dir_indexes).add_option(
+            &["--dir-index"],
+            Collect,
+            "Index file looked up in requested directories (repeatable). Default: the index file and index.htm",
+        );
+        parser.refer(&mut self.trailing_slash).add_option(
+            &["--trailing-slash"],
+            Store,
+            "Trailing slash policy: redirect, ignore or strict. Default: redirect",
+        );
+        parser.refer(&mut self.listing).add_option(
+            &["-l", "--listing"],
+            StoreTrue,
+            "List directories without an index file as HTML or JSON",
+        );
+        parser.refer(&mut self.show_hidden).add_option(
+            &["--show-hidden"],
+            StoreTrue,
+            "Include hidden files in directory listings",
+        );
         parser.refer(&mut self.not_found).add_option(
             &["-n", "--404", "--not-found"],
             StoreOption,
-            "File to be served in case of 404",
+            "Page to be served with a 404 status for missing files",
+        );
+        parser.refer(&mut self.spa).add_option(
+            &["-s", "--spa"],
+            StoreTrue,
+            "Serve the index file for navigations to missing paths (client-side routes)",
+        );
+        parser.refer(&mut self.spa_routes).add_option(
+            &["--spa-route"],
+            Collect,
+            "Glob of paths always served the index file in SPA mode, e.g. /app/** (repeatable)",
         );
         parser.refer(&mut self.ip).add_option(
             &["-a", "--addr"],
@@ -104,7 +316,269 @@ impl Args {
             Store,
             "Port at which files are served. Default: 7878",
         );
+        parser.refer(&mut self.keep_alive).add_option(
+            &["-k", "--keep-alive"],
+            Store,
+            "Seconds to keep idle connections open (0 disables keep-alive). Default: 5",
+        );
+        parser.refer(&mut self.max_header_size).add_option(
+            &["--max-header-size"],
+            Store,
+            "Maximum size in bytes of request headers. Default: 16384",
+        );
+        parser.refer(&mut self.max_headers).add_option(
+            &["--max-headers"],
+            Store,
+            "Maximum number of request headers. Default: 64",
+        );
+        parser.refer(&mut self.max_body_size).add_option(
+            &["--max-body-size"],
+            Store,
+            "Maximum size in bytes of request bodies. Default: 1048576",
+        );
+        parser.refer(&mut self.sendfile).add_option(
+            &["--sendfile"],
+            StoreTrue,
+            "Send file bodies with zero-copy sendfile(2) (Linux only)",
+        );
+        parser.refer(&mut self.compress).add_option(
+            &["--no-compress"],
+            StoreFalse,
+            "Do not compress responses on the fly",
+        );
+        parser.refer(&mut self.compress_min_size).add_option(
+            &["--compress-min-size"],
+            Store,
+            "Minimum size in bytes of files compressed on the fly. Default: 1024",
+        );
+        parser.refer(&mut self.grace_period).add_option(
+            &["-g", "--grace-period"],
+            Store,
+            "Seconds given to requests in progress to finish on shutdown. Default: 10",
+        );
+        parser.refer(&mut self.symlinks).add_option(
+            &["--symlinks"],
+            Store,
+            "Symbolic links to follow: follow, within-root or deny. Default: within-root",
+        );
+        parser.refer(&mut self.live_reload).add_option(
+            &["-w", "--live-reload"],
+            StoreTrue,
+            "Reload pages in the browser when served files change",
+        );
+        parser.refer(&mut self.build).add_option(
+            &["-b", "--build"],
+            StoreOption,
+            "Command run when sources change, e.g. \"trunk build\"",
+        );
+        parser.refer(&mut self.watch).add_option(
+            &["--watch"],
+            Collect,
+            "Source file or directory watched for --build (repeatable). Default: src and Cargo.toml",
+        );
+        parser.refer(&mut self.tls).add_option(
+            &["-t", "--tls"],
+            StoreTrue,
+            "Serve over HTTPS (requires the tls feature)",
+        );
+        parser.refer(&mut self.cert).add_option(
+            &["--cert"],
+            StoreOption,
+            "PEM certificate chain for HTTPS. Default: a generated self-signed certificate",
+        );
+        parser.refer(&mut self.key).add_option(
+            &["--key"],
+            StoreOption,
+            "PEM private key of the certificate",
+        );
+        parser.refer(&mut self.sans).add_option(
+            &["--san"],
+            Collect,
+            "Host name or IP address added to the generated certificate (repeatable)",
+        );
+        parser.refer(&mut self.cert_dir).add_option(
+            &["--cert-dir"],
+            StoreOption,
+            "Directory where the generated certificate is kept and reused",
+        );
+        parser.refer(&mut self.ca).add_option(
+            &["--ca"],
+            StoreTrue,
+            "Serve over HTTPS with certificates issued by the local CA (see syserve ca)",
+        );
+        parser.refer(&mut self.ca_dir).add_option(
+            &["--ca-dir"],
+            StoreOption,
+            "Directory of the local CA. Default: ca in the syserve configuration directory",
+        );
+        parser.refer(&mut self.client_ca).add_option(
+            &["--client-ca"],
+            StoreOption,
+            "PEM bundle of CAs client certificates are verified against (mutual TLS)",
+        );
+        parser.refer(&mut self.client_auth).add_option(
+            &["--client-auth"],
+            Store,
+            "Clients without a valid certificate: optional (allowed), require (TLS alert) \
+             or require-http (403). Default: require",
+        );
+        parser.refer(&mut self.redirect_port).add_option(
+            &["--redirect-port"],
+            StoreOption,
+            "Plain HTTP port redirecting every request to HTTPS, e.g. 8080",
+        );
+        parser.refer(&mut self.redirect_status).add_option(
+            &["--redirect-status"],
+            Store,
+            "Status of redirects to HTTPS: 301 or 308. Default: 308",
+        );
+        parser.refer(&mut self.hsts).add_option(
+            &["--hsts"],
+            StoreOption,
+            "Send Strict-Transport-Security with this max-age in seconds over HTTPS",
+        );
+        parser.refer(&mut self.http2).add_option(
+            &["--no-http2"],
+            StoreFalse,
+            "Only offer HTTP/1.1 over TLS, not HTTP/2",
+        );
+        parser.refer(&mut self.h2c).add_option(
+            &["--h2c"],
+            StoreTrue,
+            "Accept HTTP/2 with prior knowledge over plain HTTP",
+        );
+        parser.refer(&mut self.http3).add_option(
+            &["--http3"],
+            StoreTrue,
+            "Also serve HTTP/3 over QUIC on the same port, UDP (experimental, requires \
+             the http3 feature)",
+        );
 
         parser.parse_args_or_exit();
     }
 }
+
+/// Handling of clients without a valid certificate, with mutual TLS
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ClientAuth {
+    /// Ask for a certificate, but also serve anonymous clients
+    Optional,
+    /// Abort the handshake with a TLS alert
+    #[default]
+    Require,
+    /// Complete the handshake and answer requests with 403 Forbidden, which browsers
+    /// show as a page rather than a connection error
+    RequireHttp,
+}
+
+impl FromStr for ClientAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "optional" => Ok(ClientAuth::Optional),
+            "require" => Ok(ClientAuth::Require),
+            "require-http" => Ok(ClientAuth::RequireHttp),
+            _ => Err(format!(
+                "invalid client authentication {:?}, expected optional, require or require-http",
+                s
+            )),
+        }
+    }
+}
+
+/// Action of the `syserve ca` subcommand
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaAction {
+    /// Create the local CA if there is none, and show where its certificate is
+    Create,
+    /// Write the CA certificate, to be added to trust stores
+    Export,
+    /// Print the directory of the CA
+    Path,
+}
+
+impl FromStr for CaAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(CaAction::Create),
+            "export" => Ok(CaAction::Export),
+            "path" => Ok(CaAction::Path),
+            _ => Err(format!(
+                "invalid action {:?}, expected create, export or path",
+                s
+            )),
+        }
+    }
+}
+
+/// Arguments of the `syserve ca` subcommand, managing the local certificate authority
+#[derive(Clone)]
+pub struct CaArgs {
+    pub action: CaAction,
+    /// Directory of the CA. If `None`, `ca` in the configuration directory is used
+    pub dir: Option<PathBuf>,
+    /// Export the certificate in DER instead of PEM
+    pub der: bool,
+    /// File the certificate is exported to. If `None`, it is written to stdout
+    pub output: Option<PathBuf>,
+}
+
+impl Default for CaArgs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CaArgs {
+    /// Generate `CaArgs` instance with default values:
+    /// - `action`: `CaAction::Create`
+    /// - `dir`: `None` (the configuration directory)
+    /// - `der`: `false`
+    /// - `output`: `None` (stdout)
+    pub fn new() -> CaArgs {
+        CaArgs {
+            action: CaAction::Create,
+            dir: None,
+            der: false,
+            output: None,
+        }
+    }
+
+    /// Store the arguments following `syserve ca` using the `argparse` crate.
+    /// `args` are the command line arguments without the program name and `ca`
+    pub fn store(&mut self, args: Vec<String>) {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("Manage the local certificate authority used by --ca");
+
+        parser.refer(&mut self.action).add_argument(
+            "action",
+            Store,
+            "create: create the CA, export: write its certificate, path: print its directory. \
+             Default: create",
+        );
+        parser.refer(&mut self.dir).add_option(
+            &["--ca-dir"],
+            StoreOption,
+            "Directory of the CA. Default: ca in the syserve configuration directory",
+        );
+        parser.refer(&mut self.der).add_option(
+            &["--der"],
+            StoreTrue,
+            "Export the certificate in DER instead of PEM",
+        );
+        parser.refer(&mut self.output).add_option(
+            &["-o", "--output"],
+            StoreOption,
+            "File the certificate is exported to. Default: stdout",
+        );
+
+        let mut argv = vec![String::from("syserve ca")];
+        argv.extend(args);
+        if let Err(code) = parser.parse(argv, &mut std::io::stdout(), &mut std::io::stderr()) {
+            std::process::exit(code);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Content codings: negotiation with `Accept-Encoding` and compression of response
+//! bodies while they are sent
+
+use std::io::Read;
+
+use flate2::read::{GzEncoder, ZlibEncoder};
+
+/// Size of the buffers of the encoders
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Brotli quality used on the fly, a trade-off between ratio and speed (0 to 11)
+#[cfg(feature = "brotli")]
+const BROTLI_QUALITY: u32 = 5;
+
+/// Base 2 logarithm of the Brotli window size
+#[cfg(feature = "brotli")]
+const BROTLI_WINDOW: u32 = 22;
+
+/// Content codings of precompressed sidecar files, e.g. `app.wasm.br`, with their
+/// extensions, in order of preference
+pub const SIDECARS: &[(&str, &str)] = &[("br", "br"), ("zstd", "zst"), ("gzip", "gz")];
+
+/// Content coding applied to responses on the fly
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// `br`, requires the `brotli` feature
+    #[cfg(feature = "brotli")]
+    Brotli,
+    /// `gzip`
+    Gzip,
+    /// `deflate`, i.e. the zlib format
+    Deflate,
+}
+
+impl Encoding {
+    /// Supported codings, in order of preference
+    pub const ALL: &'static [Encoding] = &[
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli,
+        Encoding::Gzip,
+        Encoding::Deflate,
+    ];
+
+    /// Name of the coding in `Accept-Encoding` and `Content-Encoding`
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Coding to apply for the codings a client accepts, if any is preferred to the
+    /// identity
+    pub fn negotiate(accepted: &AcceptEncoding) -> Option<Encoding> {
+        let name = accepted.best(Encoding::ALL.iter().map(|encoding| encoding.name()))?;
+        Encoding::ALL
+            .iter()
+            .copied()
+            .find(|encoding| encoding.name() == name)
+    }
+
+    /// Reader of the contents of `reader` compressed with this coding. Compression is
+    /// streamed through fixed-size buffers, so memory stays bounded.
+    pub fn encoder<R: Read + Send + 'static>(self, reader: R) -> Box<dyn Read + Send> {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => Box::new(brotli::CompressorReader::new(
+                reader,
+                BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            )),
+            Encoding::Gzip => Box::new(GzEncoder::new(
+                std::io::BufReader::with_capacity(BUFFER_SIZE, reader),
+                flate2::Compression::default(),
+            )),
+            Encoding::Deflate => Box::new(ZlibEncoder::new(
+                std::io::BufReader::with_capacity(BUFFER_SIZE, reader),
+                flate2::Compression::default(),
+            )),
+        }
+    }
+}
+
+/// Codings of an `Accept-Encoding` header with their quality values
+pub struct AcceptEncoding {
+    /// Lowercase coding names (or `*`) and quality values, in order
+    codings: Vec<(String, f32)>,
+}
+
+impl AcceptEncoding {
+    /// Parse a header value, e.g. `gzip, br;q=0.9, *;q=0`. Malformed quality values count
+    /// as `1`.
+    pub fn parse(header: &str) -> AcceptEncoding {
+        let codings = header
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let name = params.next()?.trim().to_ascii_lowercase();
+                if name.is_empty() {
+                    return None;
+                }
+                let quality = params
+                    .filter_map(|param| {
+                        let (key, value) = param.split_once('=')?;
+                        if key.trim().eq_ignore_ascii_case("q") {
+                            value.trim().parse::<f32>().ok()
+                        } else {
+                            None
+                        }
+                    })
+                    .next()
+                    .unwrap_or(1.0)
+                    .clamp(0.0, 1.0);
+                Some((name, quality))
+            })
+            .collect();
+        AcceptEncoding { codings }
+    }
+
+    /// Quality value of `coding`: its own, else that of `*`, else `1` for `identity`
+    /// and `0` (not acceptable) for other codings
+    pub fn quality(&self, coding: &str) -> f32 {
+        let find = |name: &str| {
+            self.codings
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, quality)| *quality)
+        };
+        find(coding)
+            .or_else(|| find("*"))
+            .unwrap_or(if coding == "identity" { 1.0 } else { 0.0 })
+    }
+
+    /// Coding of `codings` (in the server's order of preference) with the highest
+    /// quality value, the first one on ties. `None` if none is acceptable or the
+    /// identity has a higher quality value.
+    pub fn best<'a, I: IntoIterator<Item = &'a str>>(&self, codings: I) -> Option<&'a str> {
+        let mut best: Option<(&str, f32)> = None;
+        for coding in codings {
+            let quality = self.quality(coding);
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((coding, quality));
+            }
+        }
+        let (coding, quality) = best?;
+        if quality < self.quality("identity") {
+            return None;
+        }
+        Some(coding)
+    }
+}
+
+/// Whether responses with the MIME type `mime` (as from `utils::extension_to_mime`)
+/// benefit from compression: text and uncompressed structured or binary formats,
+/// not images, media or archives that are compressed already
+pub fn is_compressible(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence,
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/wasm"
+                | "image/x-icon"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use super::*;
+
+    /// Preferred coding of the server, `br` if supported
+    #[cfg(feature = "brotli")]
+    const FIRST: &str = "br";
+    #[cfg(not(feature = "brotli"))]
+    const FIRST: &str = "gzip";
+
+    #[test]
+    fn negotiates_codings() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("gzip", Some("gzip")),
+            ("GZIP, deflate;q=0.5", Some("gzip")),
+            ("deflate, gzip;q=0.9", Some("deflate")),
+            // `q=0` excludes a coding
+            ("gzip;q=0, deflate", Some("deflate")),
+            ("gzip;q=0", None),
+            // `*` applies to the codings not listed
+            ("*", Some(FIRST)),
+            ("*;q=0.5, deflate", Some("deflate")),
+            ("*;q=0, gzip;q=0.1", Some("gzip")),
+            ("*;q=0", None),
+            // The identity is preferred on ties and when rated higher
+            ("identity;q=0, gzip", Some("gzip")),
+            ("identity;q=0", None),
+            ("gzip;q=0.5, identity;q=0.6", None),
+            ("gzip;q=0.5, identity;q=0.5", Some("gzip")),
+            // Ties are resolved in the server's order of preference
+            ("deflate, gzip", Some("gzip")),
+            ("deflate;q=0.8, gzip;q=0.8, identity;q=0.5", Some("gzip")),
+            // An unlisted identity counts as `1`
+            ("deflate;q=0.8, gzip;q=0.8", None),
+            // Malformed quality values count as 1, out of range ones are clamped
+            ("gzip;q=abc, deflate;q=0.5", Some("gzip")),
+            ("deflate;q=2, gzip;q=0.9, identity;q=0.1", Some("deflate")),
+            ("gzip;q=-1", None),
+            ("", None),
+            (" , ;q=1", None),
+            ("compress, zstd", None),
+        ];
+        for (header, expected) in cases {
+            let negotiated = Encoding::negotiate(&AcceptEncoding::parse(header));
+            assert_eq!(negotiated.map(Encoding::name), *expected, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn picks_the_best_of_given_codings() {
+        let accepted = AcceptEncoding::parse("gzip;q=0.5, br;q=0.8, zstd, identity;q=0.1");
+        assert_eq!(accepted.best(vec!["br", "zstd", "gzip"]), Some("zstd"));
+        assert_eq!(accepted.best(vec!["gzip", "br"]), Some("br"));
+        assert_eq!(accepted.best(vec!["deflate"]), None);
+        assert_eq!(accepted.quality("identity"), 0.1);
+        assert_eq!(AcceptEncoding::parse("gzip").quality("identity"), 1.0);
+    }
+
+    #[test]
+    fn recognizes_compressible_types() {
+        let cases: &[(&str, bool)] = &[
+            ("text/html; charset=utf-8", true),
+            ("text/css", true),
+            ("application/javascript", true),
+            ("application/json", true),
+            ("application/wasm", true),
+            ("image/svg+xml", true),
+            ("application/manifest+json", true),
+            ("image/x-icon", true),
+            ("image/png", false),
+            ("image/jpeg", false),
+            ("font/woff2", false),
+            ("application/zip", false),
+            ("application/octet-stream", false),
+            ("video/mp4", false),
+        ];
+        for (mime, expected) in cases {
+            assert_eq!(is_compressible(mime), *expected, "{}", mime);
+        }
+    }
+
+    #[test]
+    fn encoders_round_trip() {
+        let contents = "fn main() { println!(\"hello\"); }\n".repeat(1000);
+        let compress = |encoding: Encoding| {
+            let mut compressed = Vec::new();
+            encoding
+                .encoder(Cursor::new(contents.clone()))
+                .read_to_end(&mut compressed)
+                .unwrap();
+            assert!(compressed.len() < contents.len() / 10, "{:?}", encoding);
+            compressed
+        };
+
+        let mut decoded = String::new();
+        GzDecoder::new(&compress(Encoding::Gzip)[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, contents);
+
+        let mut decoded = String::new();
+        ZlibDecoder::new(&compress(Encoding::Deflate)[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, contents);
+
+        #[cfg(feature = "brotli")]
+        {
+            let mut decoded = String::new();
+            brotli::Decompressor::new(&compress(Encoding::Brotli)[..], 4096)
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, contents);
+        }
+    }
+}
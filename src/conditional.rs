@@ -0,0 +1,281 @@
+//! Validators (`ETag`, `Last-Modified`) and conditional request evaluation
+
+use std::fs::Metadata;
+use std::time::SystemTime;
+
+extern crate httpdate;
+
+use httpdate::HttpDate;
+
+use crate::request::Request;
+
+/// Validators of a file, sent with every file response
+pub struct Validators {
+    /// Strong entity tag derived from the file size and modification time, including quotes
+    pub etag: String,
+    /// Modification time of the file, if the platform provides it
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn new(metadata: &Metadata) -> Validators {
+        let last_modified = metadata.modified().ok();
+        let mtime = last_modified
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        Validators {
+            etag: format!(
+                "\"{:x}-{:x}.{:x}\"",
+                metadata.len(),
+                mtime.as_secs(),
+                mtime.subsec_nanos()
+            ),
+            last_modified,
+        }
+    }
+
+    /// Validators of a representation derived from the file, e.g. with a script injected.
+    /// `variant` is appended to the entity tag so that it differs from the one of the file
+    pub fn variant(mut self, variant: &str) -> Validators {
+        self.etag
+            .insert_str(self.etag.len() - 1, &format!("-{}", variant));
+        self
+    }
+
+    /// `Last-Modified` header value
+    pub fn last_modified_header(&self) -> Option<String> {
+        self.last_modified.map(httpdate::fmt_http_date)
+    }
+
+    /// Whether `date` (an HTTP-date with second precision) is before the modification time
+    fn modified_since(&self, date: SystemTime) -> bool {
+        self.last_modified
+            .is_some_and(|modified| HttpDate::from(modified) > HttpDate::from(date))
+    }
+}
+
+/// Outcome of evaluating the preconditions of a request
+#[derive(Debug, PartialEq)]
+pub enum Precondition {
+    /// No precondition failed, the request is served normally
+    Proceed,
+    /// The cached representation of the client is current (304)
+    NotModified,
+    /// A precondition of the client failed (412)
+    Failed,
+}
+
+/// Evaluate `If-Match`, `If-Unmodified-Since`, `If-None-Match` and `If-Modified-Since`
+/// in the order given by RFC 7232, section 6.
+pub fn evaluate(req: &Request, validators: &Validators) -> Precondition {
+    let safe = req.method == "GET" || req.method == "HEAD";
+
+    if let Some(if_match) = req.header("if-match") {
+        if !etag_list_matches(if_match, &validators.etag, false) {
+            return Precondition::Failed;
+        }
+    } else if let Some(date) = parse_date(req.header("if-unmodified-since")) {
+        if validators.modified_since(date) {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(if_none_match) = req.header("if-none-match") {
+        if etag_list_matches(if_none_match, &validators.etag, true) {
+            return if safe {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if let Some(date) = parse_date(req.header("if-modified-since")) {
+        if safe && !validators.modified_since(date) {
+            return Precondition::NotModified;
+        }
+    }
+
+    Precondition::Proceed
+}
+
+/// Whether the `If-Range` precondition of a request holds, i.e. the header is absent,
+/// its entity tag strongly matches or its date equals the modification time.
+pub fn if_range_holds(req: &Request, validators: &Validators) -> bool {
+    match req.header("if-range").map(str::trim) {
+        None => true,
+        Some(value) if value.starts_with('"') || value.starts_with("W/") => {
+            etag_matches(value, &validators.etag, false)
+        }
+        Some(value) => match (httpdate::parse_http_date(value), validators.last_modified) {
+            (Ok(date), Some(modified)) => HttpDate::from(date) == HttpDate::from(modified),
+            _ => false,
+        },
+    }
+}
+
+/// Whether a comma separated list of entity tags (or `*`) matches `etag`
+fn etag_list_matches(list: &str, etag: &str, weak: bool) -> bool {
+    list.trim() == "*"
+        || list
+            .split(',')
+            .any(|candidate| etag_matches(candidate.trim(), etag, weak))
+}
+
+/// Compare two entity tags. Weak comparison ignores the `W/` prefix,
+/// strong comparison fails if either tag is weak.
+fn etag_matches(a: &str, b: &str, weak: bool) -> bool {
+    if weak {
+        a.trim_start_matches("W/") == b.trim_start_matches("W/")
+    } else {
+        !a.starts_with("W/") && !b.starts_with("W/") && a == b
+    }
+}
+
+/// Parse an optional HTTP-date header value, ignoring invalid dates
+fn parse_date(value: Option<&str>) -> Option<SystemTime> {
+    value.and_then(|v| httpdate::parse_http_date(v.trim()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use Precondition::*;
+
+    const ETAG: &str = "\"10-5f5e100.0\"";
+    const MODIFIED: &str = "Sat, 03 Mar 1973 09:46:40 GMT";
+    const BEFORE: &str = "Sat, 03 Mar 1973 09:46:39 GMT";
+    const AFTER: &str = "Sat, 03 Mar 1973 09:46:41 GMT";
+
+    /// Header names and values of a request
+    type Headers = &'static [(&'static str, &'static str)];
+
+    fn validators() -> Validators {
+        Validators {
+            etag: String::from(ETAG),
+            last_modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(100_000_000)),
+        }
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: String::from(method),
+            path: String::from("/"),
+            version: 1,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Vec::new(),
+            client_subject: None,
+        }
+    }
+
+    #[test]
+    fn evaluates_preconditions_in_order() {
+        let cases: &[(&str, Headers, Precondition)] = &[
+            ("GET", &[], Proceed),
+            // If-Match, strong comparison
+            ("GET", &[("If-Match", ETAG)], Proceed),
+            ("GET", &[("If-Match", "*")], Proceed),
+            (
+                "GET",
+                &[("If-Match", "\"other\", \"10-5f5e100.0\"")],
+                Proceed,
+            ),
+            ("GET", &[("If-Match", "\"other\"")], Failed),
+            ("GET", &[("If-Match", "W/\"10-5f5e100.0\"")], Failed),
+            // If-Unmodified-Since, only without If-Match
+            ("GET", &[("If-Unmodified-Since", MODIFIED)], Proceed),
+            ("GET", &[("If-Unmodified-Since", BEFORE)], Failed),
+            ("GET", &[("If-Unmodified-Since", "not a date")], Proceed),
+            (
+                "GET",
+                &[("If-Match", ETAG), ("If-Unmodified-Since", BEFORE)],
+                Proceed,
+            ),
+            // If-None-Match, weak comparison
+            ("GET", &[("If-None-Match", ETAG)], NotModified),
+            (
+                "HEAD",
+                &[("If-None-Match", "W/\"10-5f5e100.0\"")],
+                NotModified,
+            ),
+            ("GET", &[("If-None-Match", "*")], NotModified),
+            ("GET", &[("If-None-Match", "\"other\"")], Proceed),
+            ("POST", &[("If-None-Match", ETAG)], Failed),
+            // If-Modified-Since, only without If-None-Match and for GET and HEAD
+            ("GET", &[("If-Modified-Since", MODIFIED)], NotModified),
+            ("GET", &[("If-Modified-Since", AFTER)], NotModified),
+            ("GET", &[("If-Modified-Since", BEFORE)], Proceed),
+            ("POST", &[("If-Modified-Since", MODIFIED)], Proceed),
+            (
+                "GET",
+                &[
+                    ("If-None-Match", "\"other\""),
+                    ("If-Modified-Since", MODIFIED),
+                ],
+                Proceed,
+            ),
+            (
+                "GET",
+                &[("If-None-Match", ETAG), ("If-Modified-Since", BEFORE)],
+                NotModified,
+            ),
+            // A failed If-Match or If-Unmodified-Since takes precedence over a 304
+            (
+                "GET",
+                &[("If-Match", "\"other\""), ("If-None-Match", ETAG)],
+                Failed,
+            ),
+            (
+                "GET",
+                &[
+                    ("If-Unmodified-Since", BEFORE),
+                    ("If-Modified-Since", MODIFIED),
+                ],
+                Failed,
+            ),
+        ];
+        for (method, headers, expected) in cases {
+            assert_eq!(
+                &evaluate(&request(method, headers), &validators()),
+                expected,
+                "{} {:?}",
+                method,
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn evaluates_if_range() {
+        let cases: &[(Headers, bool)] = &[
+            (&[], true),
+            (&[("If-Range", ETAG)], true),
+            (&[("If-Range", "\"other\"")], false),
+            (&[("If-Range", "W/\"10-5f5e100.0\"")], false),
+            (&[("If-Range", MODIFIED)], true),
+            (&[("If-Range", AFTER)], false),
+            (&[("If-Range", "not a date")], false),
+        ];
+        for (headers, expected) in cases {
+            assert_eq!(
+                if_range_holds(&request("GET", headers), &validators()),
+                *expected,
+                "{:?}",
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn variants_have_distinct_etags() {
+        assert_eq!(validators().variant("gzip").etag, "\"10-5f5e100.0-gzip\"");
+        assert_eq!(
+            validators().variant("reload").variant("br").etag,
+            "\"10-5f5e100.0-reload-br\""
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Compiler diagnostics captured from build output, rendered for the in-browser error overlay
+
+use serde_json::Value;
+
+use crate::utils::escape_html;
+
+/// Id of the overlay element, used by the live reload script to replace or remove it
+pub const OVERLAY_ID: &str = "__syserve-overlay";
+
+/// Lines of plain output shown when a failed build reported no diagnostics
+const MAX_OUTPUT_LINES: usize = 200;
+
+/// A compiler message, from cargo's `--message-format=json` output
+pub struct Diagnostic {
+    /// `error`, `warning`, `note`, ...
+    pub level: String,
+    pub message: String,
+    /// File, line and column of the primary span
+    pub location: Option<(String, u64, u64)>,
+    /// Message as printed by the compiler, with ANSI colors for
+    /// `--message-format=json-diagnostic-rendered-ansi`
+    pub rendered: Option<String>,
+}
+
+impl Diagnostic {
+    /// Whether the diagnostic is an error (including internal compiler errors)
+    pub fn is_error(&self) -> bool {
+        self.level.starts_with("error")
+    }
+}
+
+/// A line of build output
+pub enum Line {
+    /// A compiler message
+    Diagnostic(Diagnostic),
+    /// Any other JSON message of cargo, e.g. `compiler-artifact`
+    Message,
+    /// Plain output
+    Text(String),
+}
+
+impl Line {
+    /// Parse a line of output. Both cargo messages (`"reason": "compiler-message"`)
+    /// and bare rustc diagnostics (`--error-format=json`) are recognized.
+    pub fn parse(line: &str) -> Line {
+        if !line.starts_with('{') {
+            return Line::Text(line.to_string());
+        }
+        let value: Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(_) => return Line::Text(line.to_string()),
+        };
+
+        let message = match value["reason"].as_str() {
+            Some("compiler-message") => &value["message"],
+            Some(_) => return Line::Message,
+            None if value["$message_type"] == "diagnostic" => &value,
+            None => return Line::Text(line.to_string()),
+        };
+
+        let location = message["spans"].as_array().and_then(|spans| {
+            let span = spans.iter().find(|span| span["is_primary"] == true)?;
+            Some((
+                span["file_name"].as_str()?.to_string(),
+                span["line_start"].as_u64()?,
+                span["column_start"].as_u64()?,
+            ))
+        });
+
+        Line::Diagnostic(Diagnostic {
+            level: message["level"].as_str().unwrap_or("error").to_string(),
+            message: message["message"].as_str().unwrap_or_default().to_string(),
+            location,
+            rendered: message["rendered"].as_str().map(String::from),
+        })
+    }
+}
+
+/// Overlay element for a failed build, listing its errors.
+/// Without error diagnostics, e.g. for commands other than cargo, the end of the
+/// plain output is shown instead.
+pub fn overlay(lines: &[Line]) -> String {
+    let mut html = format!(
+        "<div id=\"{}\" style=\"position:fixed;top:0;right:0;bottom:0;left:0;z-index:2147483647;\
+         overflow:auto;box-sizing:border-box;padding:2em;background:rgba(0,0,0,0.9);\
+         color:#e5e5e5;font:14px/1.4 monospace;text-align:left\">\n\
+         <button onclick=\"this.parentNode.remove()\" style=\"float:right;font-size:1.5em;\
+         background:none;border:none;color:inherit;cursor:pointer\" title=\"Dismiss\">&times;</button>\n\
+         <h2 style=\"margin-top:0;color:#f14c4c\">Build failed</h2>\n",
+        OVERLAY_ID
+    );
+
+    let errors: Vec<&Diagnostic> = lines
+        .iter()
+        .filter_map(|line| match line {
+            Line::Diagnostic(diagnostic) if diagnostic.is_error() => Some(diagnostic),
+            _ => None,
+        })
+        .collect();
+
+    for error in &errors {
+        html.push_str("<div style=\"margin-bottom:1.5em\">\n");
+        if let Some((file, line, column)) = &error.location {
+            html.push_str(&format!(
+                "<div style=\"color:#29b8db\">{}:{}:{}</div>\n",
+                escape_html(file),
+                line,
+                column
+            ));
+        }
+        html.push_str(&format!(
+            "<div><b style=\"color:#f14c4c\">{}</b>: {}</div>\n",
+            escape_html(&error.level),
+            escape_html(&error.message)
+        ));
+        if let Some(rendered) = &error.rendered {
+            html.push_str(&format!(
+                "<pre style=\"white-space:pre-wrap\">{}</pre>\n",
+                ansi_to_html(rendered)
+            ));
+        }
+        html.push_str("</div>\n");
+    }
+
+    if errors.is_empty() {
+        let text: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        let tail = &text[text.len().saturating_sub(MAX_OUTPUT_LINES)..];
+        html.push_str(&format!(
+            "<pre style=\"white-space:pre-wrap\">{}</pre>\n",
+            ansi_to_html(&tail.join("\n"))
+        ));
+    }
+
+    html.push_str("</div>");
+    html
+}
+
+/// Colors of the 16 basic ANSI colors, for a dark background
+const PALETTE: [&str; 16] = [
+    "#666666", "#cd3131", "#0dbc79", "#e5e510", "#2472c8", "#bc3fbc", "#11a8cd", "#e5e5e5",
+    "#666666", "#f14c4c", "#23d18b", "#f5f543", "#3b8eea", "#d670d6", "#29b8db", "#ffffff",
+];
+
+/// Escape text for HTML, turning ANSI SGR sequences (bold and foreground colors)
+/// into `<span>`s. Other escape sequences are dropped.
+pub fn ansi_to_html(s: &str) -> String {
+    let mut html = String::with_capacity(s.len());
+    let mut bold = false;
+    let mut color: Option<String> = None;
+    // Style of the open `<span>`. Spans are only opened once text follows.
+    let mut open: Option<String> = None;
+    // Text in the current style, escaped once the style changes
+    let mut text = String::new();
+
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            let style = match (bold, &color) {
+                (false, None) => None,
+                (bold, color) => Some(format!(
+                    "{}{}",
+                    if bold { "font-weight:bold;" } else { "" },
+                    color
+                        .as_ref()
+                        .map_or(String::new(), |color| format!("color:{}", color))
+                )),
+            };
+            if style != open {
+                html.push_str(&escape_html(&text));
+                text.clear();
+                if open.is_some() {
+                    html.push_str("</span>");
+                }
+                if let Some(style) = &style {
+                    html.push_str(&format!("<span style=\"{}\">", style));
+                }
+                open = style;
+            }
+
+            text.push(c);
+            continue;
+        }
+
+        if chars.peek() != Some(&'[') {
+            continue;
+        }
+        chars.next();
+        let mut params = String::new();
+        let mut command = None;
+        for c in chars.by_ref() {
+            if c.is_ascii_digit() || c == ';' {
+                params.push(c);
+            } else {
+                command = Some(c);
+                break;
+            }
+        }
+        if command != Some('m') {
+            continue;
+        }
+
+        let codes: Vec<u16> = params
+            .split(';')
+            .map(|code| code.parse().unwrap_or(0))
+            .collect();
+        let mut codes = codes.iter();
+        while let Some(code) = codes.next() {
+            match code {
+                0 => {
+                    bold = false;
+                    color = None;
+                }
+                1 => bold = true,
+                22 => bold = false,
+                30..=37 => color = Some(PALETTE[(code - 30) as usize].to_string()),
+                90..=97 => color = Some(PALETTE[(code - 90 + 8) as usize].to_string()),
+                39 => color = None,
+                // 256 colors (only the basic 16 are mapped) and RGB colors
+                38 => match codes.next() {
+                    Some(5) => {
+                        color = codes
+                            .next()
+                            .and_then(|n| PALETTE.get(*n as usize))
+                            .map(|color| color.to_string())
+                    }
+                    Some(2) => {
+                        let rgb: Vec<&u16> = codes.by_ref().take(3).collect();
+                        if let [r, g, b] = rgb[..] {
+                            color = Some(format!("rgb({},{},{})", r, g, b));
+                        }
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
+    html.push_str(&escape_html(&text));
+    if open.is_some() {
+        html.push_str("</span>");
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO_MESSAGE: &str = r#"{"reason":"compiler-message","package_id":"app 0.1.0","message":{"$message_type":"diagnostic","level":"error","message":"mismatched types","spans":[{"file_name":"src/other.rs","line_start":1,"column_start":1,"is_primary":false},{"file_name":"src/main.rs","line_start":4,"column_start":18,"is_primary":true}],"rendered":"\u001b[1m\u001b[38;5;9merror[E0308]\u001b[0m: mismatched types\n"}}"#;
+
+    #[test]
+    fn parses_cargo_and_rustc_messages() {
+        let diagnostic = match Line::parse(CARGO_MESSAGE) {
+            Line::Diagnostic(diagnostic) => diagnostic,
+            _ => panic!("not a diagnostic"),
+        };
+        assert!(diagnostic.is_error());
+        assert_eq!(diagnostic.message, "mismatched types");
+        assert_eq!(
+            diagnostic.location,
+            Some((String::from("src/main.rs"), 4, 18))
+        );
+        assert_eq!(
+            diagnostic.rendered.as_deref(),
+            Some("\x1b[1m\x1b[38;5;9merror[E0308]\x1b[0m: mismatched types\n")
+        );
+
+        let rustc = r#"{"$message_type":"diagnostic","level":"warning","message":"unused variable","spans":[],"rendered":null}"#;
+        match Line::parse(rustc) {
+            Line::Diagnostic(diagnostic) => {
+                assert!(!diagnostic.is_error());
+                assert_eq!(diagnostic.location, None);
+                assert_eq!(diagnostic.rendered, None);
+            }
+            _ => panic!("not a diagnostic"),
+        }
+
+        let artifact = r#"{"reason":"compiler-artifact","package_id":"app 0.1.0"}"#;
+        assert!(matches!(Line::parse(artifact), Line::Message));
+        for text in &["   Compiling app v0.1.0", "{not json", r#"{"other":1}"#, ""] {
+            assert!(
+                matches!(Line::parse(text), Line::Text(t) if t == *text),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn converts_ansi_colors() {
+        let cases: &[(&str, &str)] = &[
+            ("plain", "plain"),
+            (
+                "\x1b[1;31merror\x1b[0m: x",
+                "<span style=\"font-weight:bold;color:#cd3131\">error</span>: x",
+            ),
+            (
+                "\x1b[94mnote\x1b[39m",
+                "<span style=\"color:#3b8eea\">note</span>",
+            ),
+            (
+                "\x1b[38;5;12ma\x1b[38;2;1;2;3mb\x1b[m",
+                "<span style=\"color:#3b8eea\">a</span><span style=\"color:rgb(1,2,3)\">b</span>",
+            ),
+            ("\x1b[1m\x1b[22mtext", "text"),
+            // Sequences other than SGR are dropped, trailing styles open no span
+            ("\x1b[2Kdone\x1b[1m", "done"),
+        ];
+        for (ansi, html) in cases {
+            assert_eq!(ansi_to_html(ansi), *html, "{:?}", ansi);
+        }
+    }
+
+    #[test]
+    fn escapes_compiler_output() {
+        assert_eq!(
+            ansi_to_html("\x1b[31m<script>&\"'\x1b[0m</b>"),
+            "<span style=\"color:#cd3131\">&lt;script&gt;&amp;&quot;&#39;</span>&lt;/b&gt;"
+        );
+
+        let lines = vec![Line::Diagnostic(Diagnostic {
+            level: String::from("error"),
+            message: String::from("expected `<T>`, found \"</div><script>\""),
+            location: Some((String::from("src/<a>.rs"), 1, 2)),
+            rendered: Some(String::from("<img src=x onerror=alert(1)>")),
+        })];
+        let html = overlay(&lines);
+        assert!(!html.contains("<script>"));
+        assert!(!html.contains("<img"));
+        assert!(html.contains("src/&lt;a&gt;.rs:1:2"));
+        assert!(html.contains("expected `&lt;T&gt;`, found &quot;&lt;/div&gt;&lt;script&gt;&quot;"));
+
+        // Without error diagnostics, the plain output is shown
+        let lines = vec![
+            Line::Message,
+            Line::Text(String::from("sh: 1: <trunk>: not found")),
+        ];
+        assert!(overlay(&lines).contains("sh: 1: &lt;trunk&gt;: not found"));
+    }
+}
@@ -0,0 +1,88 @@
+//! Errors returned by the server
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+extern crate httparse;
+
+/// Errors that can occur while serving
+#[derive(Debug)]
+pub enum Error {
+    /// The listener could not be bound to the address
+    Bind(SocketAddr, io::Error),
+    /// Reading from or writing to a connection failed
+    Io(io::Error),
+    /// A request could not be parsed
+    Parse(httparse::Error),
+    /// A file could not be accessed
+    Fs(PathBuf, io::Error),
+    /// A directory was requested as a file
+    IsDirectory(PathBuf),
+    /// TLS could not be set up, e.g. because of an invalid certificate
+    Tls(String),
+    /// The options cannot be used together or with the features syserve was built with
+    Config(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Status line of the response sent for this error
+    pub fn status(&self) -> &'static str {
+        match self {
+            Error::Parse(_) => "400 BAD REQUEST",
+            Error::IsDirectory(_) => "403 FORBIDDEN",
+            Error::Fs(_, e) => match e.kind() {
+                ErrorKind::NotFound => "404 NOT FOUND",
+                ErrorKind::PermissionDenied => "403 FORBIDDEN",
+                _ => "500 INTERNAL SERVER ERROR",
+            },
+            Error::Bind(..) | Error::Io(_) | Error::Tls(_) | Error::Config(_) => {
+                "500 INTERNAL SERVER ERROR"
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Bind(address, e) => match e.kind() {
+                ErrorKind::AddrInUse => write!(f, "port {} already in use", address.port()),
+                ErrorKind::AddrNotAvailable => {
+                    write!(f, "address {} is not available on this machine", address.ip())
+                }
+                ErrorKind::PermissionDenied => write!(
+                    f,
+                    "permission denied binding to {} (ports below 1024 may need elevated privileges)",
+                    address
+                ),
+                _ => write!(f, "could not bind to {}: {}", address, e),
+            },
+            Error::Io(e) => write!(f, "connection error: {}", e),
+            Error::Parse(e) => write!(f, "invalid request: {}", e),
+            Error::Fs(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            Error::IsDirectory(path) => write!(f, "{:?} is a directory", path),
+            Error::Tls(message) => write!(f, "TLS error: {}", message),
+            Error::Config(message) => write!(f, "invalid configuration: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind(_, e) | Error::Io(e) | Error::Fs(_, e) => Some(e),
+            Error::Parse(e) => Some(e),
+            Error::IsDirectory(_) | Error::Tls(_) | Error::Config(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
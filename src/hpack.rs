@@ -0,0 +1,845 @@
+//! HPACK (RFC 7541), the header compression of HTTP/2.
+//!
+//! The decoder keeps the dynamic table of a connection and rejects malformed header
+//! blocks with an error. Responses are encoded with literals only, without the
+//! dynamic table or Huffman coding.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Size of the dynamic table. This is the default of `SETTINGS_HEADER_TABLE_SIZE`,
+/// which is never changed.
+pub const TABLE_SIZE: usize = 4096;
+
+/// Overhead counted for each field, in the size of the dynamic table and in the
+/// size of header lists (`SETTINGS_MAX_HEADER_LIST_SIZE`)
+pub const FIELD_OVERHEAD: usize = 32;
+
+/// A header field: name and value
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// Reasons a header block is rejected
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The block ends in the middle of a field
+    Truncated,
+    /// An integer is too large
+    IntegerOverflow,
+    /// An index refers to no entry of the tables
+    InvalidIndex,
+    /// A Huffman-coded string contains EOS or is not padded with its first bits
+    InvalidHuffman,
+    /// A dynamic table size update is larger than `TABLE_SIZE` or follows a field
+    InvalidSizeUpdate,
+    /// The header list is larger than allowed. The whole block was decoded, so the
+    /// dynamic table stays in sync with the client's.
+    TooLarge,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated header block"),
+            DecodeError::IntegerOverflow => write!(f, "integer too large"),
+            DecodeError::InvalidIndex => write!(f, "invalid table index"),
+            DecodeError::InvalidHuffman => write!(f, "invalid Huffman code"),
+            DecodeError::InvalidSizeUpdate => write!(f, "invalid table size update"),
+            DecodeError::TooLarge => write!(f, "header list too large"),
+        }
+    }
+}
+
+/// Decoder of the header blocks sent on a connection
+pub struct Decoder {
+    /// Dynamic table, newest entry first
+    table: VecDeque<Field>,
+    /// Size of the entries in the dynamic table
+    size: usize,
+    /// Maximum size of the dynamic table, as set by the client's last size update
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// Decode a complete header block into its fields, in order.
+    /// Header lists larger than `max_list_size` are decoded to update the dynamic
+    /// table, then rejected with `DecodeError::TooLarge`.
+    pub fn decode(
+        &mut self,
+        block: &[u8],
+        max_list_size: usize,
+    ) -> Result<Vec<Field>, DecodeError> {
+        let mut input = block;
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut started = false;
+
+        while let Some(&first) = input.first() {
+            let field = if first & 0x80 != 0 {
+                // Indexed field
+                let index = decode_integer(&mut input, 7)?;
+                self.get(index)?
+            } else if first & 0x40 != 0 {
+                // Literal field with incremental indexing
+                let field = self.literal(&mut input, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                // Size updates come first in a block
+                if started {
+                    return Err(DecodeError::InvalidSizeUpdate);
+                }
+                let max_size = decode_integer(&mut input, 5)?;
+                if max_size > TABLE_SIZE {
+                    return Err(DecodeError::InvalidSizeUpdate);
+                }
+                self.max_size = max_size;
+                self.evict(0);
+                continue;
+            } else {
+                // Literal field without indexing, or never indexed
+                self.literal(&mut input, 4)?
+            };
+
+            started = true;
+            list_size += field.0.len() + field.1.len() + FIELD_OVERHEAD;
+            if list_size <= max_list_size {
+                fields.push(field);
+            }
+        }
+
+        if list_size > max_list_size {
+            return Err(DecodeError::TooLarge);
+        }
+        Ok(fields)
+    }
+
+    /// Entry of the static table (from 1) or the dynamic table (after the static one)
+    fn get(&self, index: usize) -> Result<Field, DecodeError> {
+        if index == 0 {
+            return Err(DecodeError::InvalidIndex);
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        self.table
+            .get(index - 1 - STATIC_TABLE.len())
+            .cloned()
+            .ok_or(DecodeError::InvalidIndex)
+    }
+
+    /// Literal field whose name is indexed with a prefix of `prefix_bits` bits, or
+    /// follows as a string if the index is 0
+    fn literal(&self, input: &mut &[u8], prefix_bits: u8) -> Result<Field, DecodeError> {
+        let index = decode_integer(input, prefix_bits)?;
+        let name = match index {
+            0 => decode_string(input)?,
+            index => self.get(index)?.0,
+        };
+        let value = decode_string(input)?;
+        Ok((name, value))
+    }
+
+    /// Add an entry to the dynamic table, evicting the oldest ones to make room.
+    /// Entries larger than the table empty it.
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + FIELD_OVERHEAD;
+        self.evict(size);
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Evict the oldest entries until `additional` bytes fit in the dynamic table
+    fn evict(&mut self, additional: usize) {
+        while self.size + additional > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + FIELD_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Integer with a prefix of `prefix_bits` bits, see RFC 7541 section 5.1
+fn decode_integer(input: &mut &[u8], prefix_bits: u8) -> Result<usize, DecodeError> {
+    let (&first, rest) = input.split_first().ok_or(DecodeError::Truncated)?;
+    *input = rest;
+    let max = (1 << prefix_bits) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+
+    // Up to 4 more bytes, which is plenty for sizes and indices
+    let mut shift = 0;
+    loop {
+        if shift > 21 {
+            return Err(DecodeError::IntegerOverflow);
+        }
+        let (&byte, rest) = input.split_first().ok_or(DecodeError::Truncated)?;
+        *input = rest;
+        value += ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// String literal, possibly Huffman-coded, see RFC 7541 section 5.2
+fn decode_string(input: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = input.first().ok_or(DecodeError::Truncated)? & 0x80 != 0;
+    let length = decode_integer(input, 7)?;
+    if length > input.len() {
+        return Err(DecodeError::Truncated);
+    }
+    let (s, rest) = input.split_at(length);
+    *input = rest;
+    if huffman {
+        huffman_decode(s)
+    } else {
+        Ok(s.to_vec())
+    }
+}
+
+/// Marks the symbols among the children of `huffman_tree`
+const LEAF: u16 = 0x8000;
+/// Symbol ending Huffman-coded strings, which must not appear in them
+const EOS: u16 = 256;
+
+/// Decoding tree of the Huffman code: the children of each node for bits 0 and 1,
+/// each the index of another node, a symbol marked with `LEAF`, or 0 for none.
+/// The root is the first node.
+fn huffman_tree() -> &'static [[u16; 2]] {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0; 2]];
+        for (symbol, &(code, length)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for position in (0..length).rev() {
+                let bit = ((code >> position) & 1) as usize;
+                if position == 0 {
+                    tree[node][bit] = LEAF | symbol as u16;
+                    break;
+                }
+                if tree[node][bit] == 0 {
+                    tree.push([0; 2]);
+                    tree[node][bit] = (tree.len() - 1) as u16;
+                }
+                node = tree[node][bit] as usize;
+            }
+        }
+        tree
+    })
+}
+
+/// Decode a Huffman-coded string, see RFC 7541 section 5.2
+fn huffman_decode(s: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(s.len() * 8 / 5);
+    let mut node = 0;
+    // Bits read since the last symbol, and whether they were all 1
+    let mut depth = 0;
+    let mut ones = true;
+
+    for byte in s {
+        for position in (0..8).rev() {
+            let bit = (byte >> position) & 1;
+            depth += 1;
+            ones &= bit == 1;
+            match tree[node][bit as usize] {
+                0 => return Err(DecodeError::InvalidHuffman),
+                child if child & LEAF != 0 => {
+                    if child & !LEAF == EOS {
+                        return Err(DecodeError::InvalidHuffman);
+                    }
+                    decoded.push((child & !LEAF) as u8);
+                    node = 0;
+                    depth = 0;
+                    ones = true;
+                }
+                child => node = child as usize,
+            }
+        }
+    }
+
+    // Padding is made of the first bits of EOS, all 1, and shorter than a byte
+    if depth > 7 || !ones {
+        return Err(DecodeError::InvalidHuffman);
+    }
+    Ok(decoded)
+}
+
+/// Append a literal field without indexing, with a new name and no Huffman coding
+pub fn encode_field(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    encode_string(block, name);
+    encode_string(block, value);
+}
+
+/// Append a string literal, without Huffman coding
+fn encode_string(block: &mut Vec<u8>, s: &[u8]) {
+    encode_integer(block, s.len(), 7);
+    block.extend_from_slice(s);
+}
+
+/// Append an integer with a prefix of `prefix_bits` bits
+fn encode_integer(block: &mut Vec<u8>, value: usize, prefix_bits: u8) {
+    let max = (1 << prefix_bits) - 1;
+    if value < max {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max as u8);
+    let mut value = value - max;
+    while value >= 128 {
+        block.push((value % 128) as u8 | 0x80);
+        value /= 128;
+    }
+    block.push(value as u8);
+}
+
+/// Static table, see RFC 7541 appendix A
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Huffman code of each symbol (the 256 octets, then EOS): the code in its least
+/// significant bits, and its length in bits. See RFC 7541 appendix B.
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn fields(list: &[(&str, &str)]) -> Vec<Field> {
+        list.iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            .collect()
+    }
+
+    /// Decode the requests of RFC 7541 appendix C.3 or C.4 with one decoder
+    fn decode_requests(blocks: [&str; 3]) {
+        let expected = [
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ]),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ]),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ]),
+        ];
+        let table_sizes = [57, 110, 164];
+
+        let mut decoder = Decoder::default();
+        for i in 0..3 {
+            let decoded = decoder.decode(&hex(blocks[i]), usize::MAX);
+            assert_eq!(decoded, Ok(expected[i].clone()), "request {}", i + 1);
+            assert_eq!(decoder.size, table_sizes[i], "request {}", i + 1);
+        }
+        assert_eq!(
+            decoder.table,
+            fields(&[
+                ("custom-key", "custom-value"),
+                ("cache-control", "no-cache"),
+                (":authority", "www.example.com"),
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_requests() {
+        decode_requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    #[test]
+    fn decodes_huffman_coded_requests() {
+        decode_requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    #[test]
+    fn decodes_integers() {
+        let cases: &[(&[u8], u8, usize)] = &[
+            (&[0x0a], 5, 10),
+            (&[0x1f, 0x9a, 0x0a], 5, 1337),
+            (&[0x2a], 8, 42),
+            (&[0x7f, 0x00], 7, 127),
+        ];
+        for (bytes, prefix_bits, value) in cases {
+            let mut input = *bytes;
+            assert_eq!(decode_integer(&mut input, *prefix_bits), Ok(*value));
+            assert!(input.is_empty());
+
+            let mut encoded = Vec::new();
+            encode_integer(&mut encoded, *value, *prefix_bits);
+            assert_eq!(&encoded, bytes);
+        }
+    }
+
+    #[test]
+    fn huffman_code_round_trips() {
+        // Every octet, then padding with the first bits of EOS
+        let mut bits: Vec<bool> = Vec::new();
+        for &(code, length) in &HUFFMAN_CODES[..256] {
+            bits.extend(
+                (0..length)
+                    .rev()
+                    .map(|position| (code >> position) & 1 == 1),
+            );
+        }
+        while !bits.len().is_multiple_of(8) {
+            bits.push(true);
+        }
+        let coded: Vec<u8> = bits
+            .chunks(8)
+            .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8))
+            .collect();
+
+        let octets: Vec<u8> = (0..=255).collect();
+        assert_eq!(huffman_decode(&coded), Ok(octets));
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        let cases: &[(&str, DecodeError)] = &[
+            // Index 0, and past the end of the empty dynamic table
+            ("80", DecodeError::InvalidIndex),
+            ("be", DecodeError::InvalidIndex),
+            ("7f00", DecodeError::InvalidIndex),
+            // Literal value longer than the block
+            ("0003 6b65 7905 76", DecodeError::Truncated),
+            ("1f", DecodeError::Truncated),
+            ("00", DecodeError::Truncated),
+            // Integer continued for too long
+            ("ff ffff ffff 0f", DecodeError::IntegerOverflow),
+            // EOS in a string, padding of 0 bits, padding longer than 7 bits
+            ("0001 6184 ffff ffff", DecodeError::InvalidHuffman),
+            ("0001 6181 18", DecodeError::InvalidHuffman),
+            ("0001 6182 1fff", DecodeError::InvalidHuffman),
+            // Size update above the table size, and after a field
+            ("3fe2 1f", DecodeError::InvalidSizeUpdate),
+            ("8220", DecodeError::InvalidSizeUpdate),
+        ];
+        for (block, error) in cases {
+            let mut decoder = Decoder::default();
+            assert_eq!(
+                decoder.decode(&hex(block), usize::MAX).as_ref(),
+                Err(error),
+                "{}",
+                block
+            );
+        }
+    }
+
+    #[test]
+    fn evicts_old_entries() {
+        let mut decoder = Decoder::default();
+        // Size update to 64 bytes, then two literals with indexing of 42 bytes each
+        let block = hex("3f21 4003 6b65 7907 7661 6c75 6520 3140 036b 6579 0776 616c 7565 2032");
+        assert_eq!(
+            decoder.decode(&block, usize::MAX),
+            Ok(fields(&[("key", "value 1"), ("key", "value 2")]))
+        );
+        assert_eq!(decoder.table, fields(&[("key", "value 2")]));
+        assert_eq!(decoder.size, 42);
+        assert_eq!(
+            decoder.decode(&hex("bf"), usize::MAX),
+            Err(DecodeError::InvalidIndex)
+        );
+
+        // An entry larger than the table empties it
+        let mut block = hex("4003 6b65 791e");
+        block.extend_from_slice(&[b'x'; 30]);
+        assert_eq!(decoder.decode(&block, usize::MAX).unwrap().len(), 1);
+        assert!(decoder.table.is_empty());
+        assert_eq!(decoder.size, 0);
+    }
+
+    #[test]
+    fn large_header_lists_keep_the_table_in_sync() {
+        let mut decoder = Decoder::default();
+        let first = hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d");
+        assert_eq!(decoder.decode(&first, 100), Err(DecodeError::TooLarge));
+        // The next block refers to the entry added by the rejected one
+        let second = hex("8286 84be 5808 6e6f 2d63 6163 6865");
+        let decoded = decoder.decode(&second, usize::MAX).unwrap();
+        assert_eq!(
+            decoded[3],
+            (b":authority".to_vec(), b"www.example.com".to_vec())
+        );
+    }
+
+    #[test]
+    fn encodes_literal_fields() {
+        let mut block = Vec::new();
+        encode_field(&mut block, b"content-type", b"text/html");
+        encode_field(&mut block, b"x-long", &[b'a'; 300]);
+        let decoded = Decoder::default().decode(&block, usize::MAX).unwrap();
+        assert_eq!(
+            decoded,
+            vec![
+                (b"content-type".to_vec(), b"text/html".to_vec()),
+                (b"x-long".to_vec(), vec![b'a'; 300]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1692 @@
+//! HTTP/2 (RFC 9113), negotiated with ALPN over TLS or started with prior knowledge
+//! over plain TCP.
+//!
+//! A connection is served by a single loop that reads frames and interleaves the DATA
+//! frames of all open responses within the flow control windows granted by the client.
+//! Requests are answered in threads of their own as soon as their headers are complete,
+//! so that a response waiting for a build or a slow disk does not hold up other streams.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::hpack::{self, DecodeError};
+use crate::reload;
+use crate::request::{Limits, Request};
+use crate::response::{BodyReader, Response};
+use crate::shutdown::Shutdown;
+use crate::stream::Stream;
+
+/// Connection preface sent by clients before their first frame
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Settings
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Size of frame headers
+const FRAME_HEADER_SIZE: usize = 9;
+/// Largest frame accepted from clients, and sent until they allow larger ones
+const DEFAULT_FRAME_SIZE: usize = 16384;
+/// Flow control window of new streams and connections
+const DEFAULT_WINDOW: i64 = 65535;
+/// Largest flow control window allowed
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// Streams a client may have open at once
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// Body bytes sent on a stream before moving on to the next one
+const BURST_SIZE: usize = 64 * 1024;
+/// Interval at which event streams and `Handler::is_busy` are checked while no frames
+/// arrive
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Interval at which frames are read while waiting for responses being produced
+const RESPONSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Time given to clients sending the connection preface in several packets
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Answers the requests of HTTP/2 and HTTP/3 connections
+pub trait Handler {
+    /// Response to a request
+    fn respond(&self, req: &Request) -> Response;
+
+    /// Whether the request is for an event stream, answered by `attach` instead
+    fn is_event_stream(&self, req: &Request) -> bool;
+
+    /// Keep sending events on a new event stream, until it is closed
+    fn attach(&self, events: EventStream);
+
+    /// Whether other connections are waiting for a worker, in which case idle
+    /// connections are closed
+    fn is_busy(&self) -> bool;
+}
+
+/// Settings of a connection
+pub struct Options {
+    /// Limits on the size of request headers
+    pub limits: Limits,
+    /// Time after which a connection without activity is closed. Open event streams
+    /// do not count as activity.
+    pub idle_timeout: Duration,
+    /// Once triggered, no new streams are accepted and the connection is closed when
+    /// the open ones are done
+    pub shutdown: Shutdown,
+    /// Subject of the client certificate, set on every request, see `Request::client_subject`
+    pub client_subject: Option<String>,
+}
+
+/// Body of an open event stream, written from other threads.
+/// Writes fail once the stream or its connection is closed.
+pub struct EventStream {
+    sender: Option<Sender<Vec<u8>>>,
+}
+
+impl EventStream {
+    /// Event stream, and the receiver of what is written to it
+    pub(crate) fn new() -> (EventStream, Receiver<Vec<u8>>) {
+        let (sender, receiver) = mpsc::channel();
+        let events = EventStream {
+            sender: Some(sender),
+        };
+        (events, receiver)
+    }
+}
+
+impl Write for EventStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.sender {
+            Some(sender) if sender.send(buf.to_vec()).is_ok() => Ok(buf.len()),
+            _ => Err(ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl reload::Client for EventStream {
+    fn close(&mut self) {
+        self.sender = None;
+    }
+}
+
+/// Byte stream a connection is served over
+pub trait Transport: Write {
+    /// Read, waiting for at most `wait` for data to arrive or not at all if `None`.
+    /// Nothing arriving in time is an error of kind `WouldBlock` or `TimedOut`.
+    fn read_within(&mut self, buf: &mut [u8], wait: Option<Duration>) -> io::Result<usize>;
+}
+
+impl Transport for Stream {
+    fn read_within(&mut self, buf: &mut [u8], wait: Option<Duration>) -> io::Result<usize> {
+        match wait {
+            Some(timeout) => self.tcp().set_read_timeout(Some(timeout))?,
+            None => self.tcp().set_nonblocking(true)?,
+        }
+        let result = self.read(buf);
+        if wait.is_none() {
+            self.tcp().set_nonblocking(false)?;
+        }
+        result
+    }
+}
+
+/// Whether a plain TCP client starts with the connection preface, i.e. speaks HTTP/2
+/// with prior knowledge. The preface is left to be read.
+pub fn has_preface(stream: &TcpStream) -> io::Result<bool> {
+    let mut buf = [0; PREFACE.len()];
+    let mut waited = Duration::from_millis(0);
+    loop {
+        let n = match stream.peek(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        };
+        if n == 0 || buf[..n] != PREFACE[..n] {
+            return Ok(false);
+        }
+        if n == PREFACE.len() {
+            return Ok(true);
+        }
+        if waited >= PREFACE_TIMEOUT {
+            return Ok(false);
+        }
+        // Only the start of the preface arrived
+        thread::sleep(Duration::from_millis(10));
+        waited += Duration::from_millis(10);
+    }
+}
+
+/// Serve an HTTP/2 connection until the client closes it, it stays idle for
+/// `Options::idle_timeout`, it is idle while other connections wait for a worker
+/// (see `Handler::is_busy`) or the server shuts down.
+/// Protocol errors of the client are answered with `GOAWAY` and returned.
+pub fn serve<H: Handler + Sync, T: Transport>(
+    stream: T,
+    handler: &H,
+    options: Options,
+) -> io::Result<()> {
+    thread::scope(|scope| serve_in(scope, stream, handler, options))
+}
+
+/// Serve a connection, producing responses in threads of `scope`
+fn serve_in<'s, 'h, H: Handler + Sync, T: Transport>(
+    scope: &'s thread::Scope<'s, 'h>,
+    stream: T,
+    handler: &'h H,
+    options: Options,
+) -> io::Result<()> {
+    let (responder, responses) = mpsc::channel();
+    let mut connection = Connection {
+        stream,
+        scope,
+        handler,
+        options,
+        decoder: hpack::Decoder::default(),
+        input: Vec::new(),
+        output: Vec::new(),
+        continuation: None,
+        incoming: HashMap::new(),
+        responding: HashSet::new(),
+        responder,
+        responses,
+        outgoing: VecDeque::new(),
+        last_stream_id: 0,
+        window: DEFAULT_WINDOW,
+        initial_window: DEFAULT_WINDOW,
+        max_frame_size: DEFAULT_FRAME_SIZE,
+        reading: true,
+        going_away: false,
+        last_activity: Instant::now(),
+    };
+
+    match connection.run() {
+        Ok(()) => Ok(()),
+        Err(Failure::Io(e)) => Err(e),
+        Err(Failure::Protocol(code, reason)) => {
+            connection.go_away(code, reason);
+            let _ = connection.flush();
+            Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("HTTP/2 protocol violation, {}", reason),
+            ))
+        }
+    }
+}
+
+/// Reasons a connection ends early
+enum Failure {
+    Io(io::Error),
+    /// Connection error with its code, see RFC 9113 section 7
+    Protocol(u32, &'static str),
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure::Io(e)
+    }
+}
+
+fn protocol_error(reason: &'static str) -> Failure {
+    Failure::Protocol(PROTOCOL_ERROR, reason)
+}
+
+/// A response being sent
+struct Outgoing {
+    stream_id: u32,
+    /// Flow control window of the stream
+    window: i64,
+    source: Source,
+}
+
+/// Data of a response being sent
+enum Source {
+    /// Response body, with the number of bytes left if known
+    Body(BodyReader, Option<u64>),
+    /// Events received but not sent yet, and whether the event stream was closed
+    Events(Receiver<Vec<u8>>, Vec<u8>, bool),
+}
+
+/// A response produced for a stream, and whether it answers a `HEAD` request
+type Produced = (u32, Response, bool);
+
+struct Connection<'s, 'h, H: Handler, T: Transport> {
+    stream: T,
+    /// Scope of the threads producing responses
+    scope: &'s thread::Scope<'s, 'h>,
+    handler: &'h H,
+    options: Options,
+    decoder: hpack::Decoder,
+    /// Bytes received but not yet parsed into frames
+    input: Vec<u8>,
+    /// Frames not yet written
+    output: Vec<u8>,
+    /// Header block continued in `CONTINUATION` frames: stream, block so far,
+    /// and whether the stream ends with it
+    continuation: Option<(u32, Vec<u8>, bool)>,
+    /// Requests waiting for the end of their body, which is discarded
+    incoming: HashMap<u32, Request>,
+    /// Streams whose response is being produced
+    responding: HashSet<u32>,
+    /// Channel of the responses produced
+    responder: Sender<Produced>,
+    responses: Receiver<Produced>,
+    /// Responses being sent, in round-robin order
+    outgoing: VecDeque<Outgoing>,
+    /// Highest stream opened by the client
+    last_stream_id: u32,
+    /// Flow control window of the connection
+    window: i64,
+    /// Window of new streams, from the client's settings
+    initial_window: i64,
+    /// Largest frame the client accepts
+    max_frame_size: usize,
+    /// Whether the client may still send frames
+    reading: bool,
+    /// Whether `GOAWAY` was sent or received, after which no new streams are accepted
+    going_away: bool,
+    /// When a frame was last received or response body data last sent
+    last_activity: Instant,
+}
+
+impl<'s, 'h, H: Handler + Sync, T: Transport> Connection<'s, 'h, H, T> {
+    fn run(&mut self) -> Result<(), Failure> {
+        self.read_preface()?;
+
+        let mut settings = Vec::new();
+        for (id, value) in &[
+            (
+                SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                self.options.limits.max_header_size as u32,
+            ),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &settings);
+        // Frames may have arrived along with the preface
+        self.handle_input()?;
+
+        loop {
+            self.receive_responses(None);
+            self.send_data()?;
+            self.flush()?;
+
+            if self.options.shutdown.is_triggered() && !self.going_away {
+                self.go_away(NO_ERROR, "");
+                self.flush()?;
+            }
+            let open = !self.incoming.is_empty()
+                || !self.responding.is_empty()
+                || !self.outgoing.is_empty();
+            if self.going_away && !open {
+                return Ok(());
+            }
+            if !self.reading {
+                // Nothing is left to wait for once the windows are exhausted
+                if self.can_send() {
+                    continue;
+                }
+                if !self.responding.is_empty() {
+                    self.receive_responses(Some(RESPONSE_POLL_INTERVAL));
+                    continue;
+                }
+                return Ok(());
+            }
+
+            // Sendable data must not wait for frames
+            if self.can_send() {
+                self.receive(None)?;
+                continue;
+            }
+            // Responses being produced are waited for, without missing frames
+            if !self.responding.is_empty() {
+                self.receive_responses(Some(RESPONSE_POLL_INTERVAL));
+                self.receive(None)?;
+                continue;
+            }
+
+            // Event streams are polled, but do not keep the connection and its worker
+            let idle = self.last_activity.elapsed();
+            if idle >= self.options.idle_timeout {
+                self.go_away(NO_ERROR, "");
+                self.flush()?;
+                return Ok(());
+            }
+            if self.is_idle() && self.handler.is_busy() {
+                info!("Closing idle HTTP/2 connection, other connections are waiting for a worker");
+                self.go_away(NO_ERROR, "");
+                self.flush()?;
+                return Ok(());
+            }
+            self.receive(Some(POLL_INTERVAL.min(self.options.idle_timeout - idle)))?;
+        }
+    }
+
+    /// Wait for the connection preface, see `PREFACE`
+    fn read_preface(&mut self) -> Result<(), Failure> {
+        while self.input.len() < PREFACE.len() {
+            let idle_timeout = self.options.idle_timeout;
+            if !self.read(Some(idle_timeout))? {
+                return Err(Failure::Io(ErrorKind::TimedOut.into()));
+            }
+            if !self.reading {
+                return Err(Failure::Io(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        if &self.input[..PREFACE.len()] != PREFACE {
+            return Err(protocol_error("invalid connection preface"));
+        }
+        self.input.drain(..PREFACE.len());
+        Ok(())
+    }
+
+    /// Read from the connection, waiting for at most `wait` or not at all if `None`.
+    /// Returns `false` if nothing arrived in time.
+    fn read(&mut self, wait: Option<Duration>) -> Result<bool, Failure> {
+        let mut buf = [0; DEFAULT_FRAME_SIZE];
+        match self.stream.read_within(&mut buf, wait) {
+            Ok(0) => {
+                self.reading = false;
+                Ok(true)
+            }
+            Ok(n) => {
+                self.input.extend_from_slice(&buf[..n]);
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                Ok(false)
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => Ok(true),
+            Err(e) => Err(Failure::Io(e)),
+        }
+    }
+
+    /// Read and handle the frames that arrive within `wait`, see `read`
+    fn receive(&mut self, wait: Option<Duration>) -> Result<bool, Failure> {
+        if !self.read(wait)? {
+            return Ok(false);
+        }
+        self.last_activity = Instant::now();
+        self.handle_input()?;
+        Ok(true)
+    }
+
+    /// Handle the complete frames received so far
+    fn handle_input(&mut self) -> Result<(), Failure> {
+        while self.input.len() >= FRAME_HEADER_SIZE {
+            let length = u32::from_be_bytes([0, self.input[0], self.input[1], self.input[2]]);
+            let length = length as usize;
+            if length > DEFAULT_FRAME_SIZE {
+                return Err(Failure::Protocol(FRAME_SIZE_ERROR, "frame too large"));
+            }
+            if self.input.len() < FRAME_HEADER_SIZE + length {
+                break;
+            }
+
+            let frame: Vec<u8> = self.input.drain(..FRAME_HEADER_SIZE + length).collect();
+            let stream_id =
+                u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]) & !(1 << 31);
+            self.handle_frame(frame[3], frame[4], stream_id, &frame[FRAME_HEADER_SIZE..])?;
+        }
+        Ok(())
+    }
+
+    fn handle_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> Result<(), Failure> {
+        if self.continuation.is_some() && kind != CONTINUATION {
+            return Err(protocol_error("expected a CONTINUATION frame"));
+        }
+
+        match kind {
+            DATA => self.on_data(flags, stream_id, payload),
+            HEADERS => self.on_headers(flags, stream_id, payload),
+            CONTINUATION => self.on_continuation(flags, stream_id, payload),
+            // Responses are sent in round-robin order regardless of priorities
+            PRIORITY => Ok(()),
+            RST_STREAM => {
+                if stream_id == 0 {
+                    return Err(protocol_error("RST_STREAM on the connection"));
+                }
+                self.incoming.remove(&stream_id);
+                self.responding.remove(&stream_id);
+                self.outgoing.retain(|out| out.stream_id != stream_id);
+                Ok(())
+            }
+            SETTINGS => self.on_settings(flags, stream_id, payload),
+            PUSH_PROMISE => Err(protocol_error("PUSH_PROMISE sent by a client")),
+            PING => {
+                if stream_id != 0 {
+                    return Err(protocol_error("PING on a stream"));
+                }
+                if payload.len() != 8 {
+                    return Err(Failure::Protocol(FRAME_SIZE_ERROR, "invalid PING"));
+                }
+                if flags & ACK == 0 {
+                    self.write_frame(PING, ACK, 0, payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(stream_id, payload),
+            // Unknown frame types must be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, flags: u8, stream_id: u32, payload: &[u8]) -> Result<(), Failure> {
+        if stream_id == 0 {
+            return Err(protocol_error("DATA on the connection"));
+        }
+
+        // Request bodies are not used, their window is given back right away
+        let length = payload.len() as u32;
+        if length > 0 {
+            self.write_frame(WINDOW_UPDATE, 0, 0, &length.to_be_bytes());
+            if self.incoming.contains_key(&stream_id) && flags & END_STREAM == 0 {
+                self.write_frame(WINDOW_UPDATE, 0, stream_id, &length.to_be_bytes());
+            }
+        }
+
+        if flags & END_STREAM != 0 {
+            if let Some(req) = self.incoming.remove(&stream_id) {
+                self.respond(stream_id, req);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, flags: u8, stream_id: u32, payload: &[u8]) -> Result<(), Failure> {
+        if stream_id.is_multiple_of(2) {
+            return Err(protocol_error("HEADERS on an invalid stream"));
+        }
+        if stream_id <= self.last_stream_id && !self.incoming.contains_key(&stream_id) {
+            return Err(protocol_error("HEADERS on a closed stream"));
+        }
+
+        let mut block = payload;
+        if flags & PADDED != 0 {
+            let padding = *block
+                .first()
+                .ok_or_else(|| protocol_error("invalid padding"))?;
+            block = &block[1..];
+            if padding as usize > block.len() {
+                return Err(protocol_error("invalid padding"));
+            }
+            block = &block[..block.len() - padding as usize];
+        }
+        if flags & PRIORITY_FLAG != 0 {
+            if block.len() < 5 {
+                return Err(protocol_error("invalid priority"));
+            }
+            block = &block[5..];
+        }
+
+        let end_stream = flags & END_STREAM != 0;
+        if flags & END_HEADERS != 0 {
+            self.on_header_block(stream_id, block, end_stream)
+        } else {
+            self.continuation = Some((stream_id, block.to_vec(), end_stream));
+            Ok(())
+        }
+    }
+
+    fn on_continuation(
+        &mut self,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> Result<(), Failure> {
+        let (id, mut block, end_stream) = match self.continuation.take() {
+            Some(continuation) if continuation.0 == stream_id => continuation,
+            _ => return Err(protocol_error("unexpected CONTINUATION frame")),
+        };
+        block.extend_from_slice(payload);
+        if block.len() > 2 * self.options.limits.max_header_size {
+            return Err(Failure::Protocol(
+                ENHANCE_YOUR_CALM,
+                "header block too large",
+            ));
+        }
+
+        if flags & END_HEADERS != 0 {
+            self.on_header_block(id, &block, end_stream)
+        } else {
+            self.continuation = Some((id, block, end_stream));
+            Ok(())
+        }
+    }
+
+    /// Handle a complete header block: the headers of a new request, or trailers
+    fn on_header_block(
+        &mut self,
+        stream_id: u32,
+        block: &[u8],
+        end_stream: bool,
+    ) -> Result<(), Failure> {
+        // Blocks are always decoded, as they update the decoder's table
+        let headers = match self
+            .decoder
+            .decode(block, self.options.limits.max_header_size)
+        {
+            Ok(headers) => Some(headers),
+            Err(DecodeError::TooLarge) => None,
+            Err(_) => return Err(Failure::Protocol(COMPRESSION_ERROR, "invalid header block")),
+        };
+
+        if self.incoming.contains_key(&stream_id) {
+            // Trailers of a request body
+            if !end_stream {
+                return Err(protocol_error("trailers without END_STREAM"));
+            }
+            if let Some(req) = self.incoming.remove(&stream_id) {
+                self.respond(stream_id, req);
+            }
+            return Ok(());
+        }
+
+        self.last_stream_id = stream_id;
+        let open = self.incoming.len() + self.responding.len() + self.outgoing.len();
+        if self.going_away || open >= MAX_CONCURRENT_STREAMS {
+            self.write_frame(RST_STREAM, 0, stream_id, &REFUSED_STREAM.to_be_bytes());
+            return Ok(());
+        }
+
+        let headers = match headers {
+            Some(headers) if headers.len() <= self.options.limits.max_headers => headers,
+            _ => {
+                warn!("Request header fields too large");
+                let response = Response::new("431 REQUEST HEADER FIELDS TOO LARGE");
+                self.send_response(stream_id, response, false);
+                return Ok(());
+            }
+        };
+
+        let req = match self.request(headers) {
+            Ok(req) => req,
+            Err(reason) => {
+                warn!("Malformed HTTP/2 request: {}", reason);
+                self.write_frame(RST_STREAM, 0, stream_id, &PROTOCOL_ERROR.to_be_bytes());
+                return Ok(());
+            }
+        };
+
+        if end_stream {
+            self.respond(stream_id, req);
+        } else {
+            self.incoming.insert(stream_id, req);
+        }
+        Ok(())
+    }
+
+    /// Request from decoded headers. `:authority` is turned into a `host` header.
+    fn request(&self, headers: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Request, &'static str> {
+        let mut method = None;
+        let mut path = None;
+        let mut authority = None;
+        let mut fields = Vec::new();
+
+        for (name, value) in headers {
+            let name = String::from_utf8(name).map_err(|_| "invalid header name")?;
+            let value = String::from_utf8_lossy(&value).into_owned();
+            match name.as_str() {
+                ":method" => method = Some(value),
+                ":path" => path = Some(value),
+                ":authority" => authority = Some(value),
+                ":scheme" | ":protocol" => {}
+                name if name.starts_with(':') => return Err("unknown pseudo-header"),
+                name if name.bytes().any(|b| b.is_ascii_uppercase()) => {
+                    return Err("uppercase header name")
+                }
+                _ => fields.push((name, value)),
+            }
+        }
+
+        if let Some(authority) = authority {
+            if !fields.iter().any(|(name, _)| name == "host") {
+                fields.push((String::from("host"), authority));
+            }
+        }
+
+        Ok(Request {
+            method: method.ok_or("missing :method")?,
+            path: path.ok_or("missing :path")?,
+            version: 1,
+            headers: fields,
+            body: Vec::new(),
+            client_subject: self.options.client_subject.clone(),
+        })
+    }
+
+    fn on_settings(&mut self, flags: u8, stream_id: u32, payload: &[u8]) -> Result<(), Failure> {
+        if stream_id != 0 {
+            return Err(protocol_error("SETTINGS on a stream"));
+        }
+        if flags & ACK != 0 {
+            return Ok(());
+        }
+        if !payload.len().is_multiple_of(6) {
+            return Err(Failure::Protocol(FRAME_SIZE_ERROR, "invalid SETTINGS"));
+        }
+
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(Failure::Protocol(FLOW_CONTROL_ERROR, "window too large"));
+                    }
+                    for out in self.outgoing.iter_mut() {
+                        out.window += value - self.initial_window;
+                    }
+                    self.initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16384..=16_777_215).contains(&value) {
+                        return Err(protocol_error("invalid maximum frame size"));
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                _ => {}
+            }
+        }
+
+        self.write_frame(SETTINGS, ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, stream_id: u32, payload: &[u8]) -> Result<(), Failure> {
+        if payload.len() != 4 {
+            return Err(Failure::Protocol(FRAME_SIZE_ERROR, "invalid WINDOW_UPDATE"));
+        }
+        let increment = (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]])
+            & !(1 << 31)) as i64;
+
+        if stream_id == 0 {
+            if increment == 0 {
+                return Err(protocol_error("empty WINDOW_UPDATE"));
+            }
+            self.window += increment;
+            if self.window > MAX_WINDOW {
+                return Err(Failure::Protocol(FLOW_CONTROL_ERROR, "window too large"));
+            }
+            return Ok(());
+        }
+
+        let position = self
+            .outgoing
+            .iter()
+            .position(|out| out.stream_id == stream_id);
+        if let Some(position) = position {
+            let out = &mut self.outgoing[position];
+            out.window += increment;
+            if increment == 0 || out.window > MAX_WINDOW {
+                let code = if increment == 0 {
+                    PROTOCOL_ERROR
+                } else {
+                    FLOW_CONTROL_ERROR
+                };
+                self.outgoing.remove(position);
+                self.write_frame(RST_STREAM, 0, stream_id, &code.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer a complete request
+    fn respond(&mut self, stream_id: u32, req: Request) {
+        if self.handler.is_event_stream(&req) {
+            let (events, receiver) = EventStream::new();
+            let fields = vec![
+                (String::from(":status"), String::from("200")),
+                (
+                    String::from("content-type"),
+                    String::from("text/event-stream"),
+                ),
+                (String::from("cache-control"), String::from("no-cache")),
+            ];
+            self.write_headers(stream_id, &fields, false);
+            self.outgoing.push_back(Outgoing {
+                stream_id,
+                window: self.initial_window,
+                source: Source::Events(receiver, Vec::new(), false),
+            });
+            self.handler.attach(events);
+            return;
+        }
+
+        let handler = self.handler;
+        let responder = self.responder.clone();
+        self.responding.insert(stream_id);
+        self.scope.spawn(move || {
+            let response = handler.respond(&req);
+            let _ = responder.send((stream_id, response, req.method == "HEAD"));
+        });
+    }
+
+    /// Send the headers of the responses produced so far, waiting for at most `wait`
+    /// for one if there are none. Responses of streams reset meanwhile are dropped.
+    fn receive_responses(&mut self, wait: Option<Duration>) {
+        let first = match wait {
+            Some(timeout) => self.responses.recv_timeout(timeout).ok(),
+            None => self.responses.try_recv().ok(),
+        };
+        let produced: Vec<Produced> = first.into_iter().chain(self.responses.try_iter()).collect();
+        for (stream_id, response, head_only) in produced {
+            if self.responding.remove(&stream_id) {
+                self.send_response(stream_id, response, head_only);
+            }
+        }
+    }
+
+    /// Send the headers of a response, queueing its body
+    fn send_response(&mut self, stream_id: u32, response: Response, head_only: bool) {
+        let mut fields = vec![(String::from(":status"), response.status[..3].to_string())];
+        for (name, value) in &response.headers {
+            let name = name.to_ascii_lowercase();
+            if is_connection_header(&name) {
+                continue;
+            }
+            fields.push((name, value.clone()));
+        }
+        let length = response.body.len();
+        if let (Some(length), false) = (length, response.status.starts_with("304")) {
+            fields.push((String::from("content-length"), length.to_string()));
+        }
+
+        let end_stream = head_only || length == Some(0);
+        self.write_headers(stream_id, &fields, end_stream);
+        if !end_stream {
+            self.outgoing.push_back(Outgoing {
+                stream_id,
+                window: self.initial_window,
+                source: Source::Body(response.body.into_reader(), length),
+            });
+        }
+    }
+
+    /// Whether some response has data that can be sent right away
+    fn can_send(&self) -> bool {
+        self.window > 0
+            && self.outgoing.iter().any(|out| {
+                out.window > 0
+                    && match &out.source {
+                        Source::Body(..) => true,
+                        Source::Events(_, pending, closed) => !pending.is_empty() || *closed,
+                    }
+            })
+    }
+
+    /// Whether no request is being received or answered. Event streams may be open.
+    fn is_idle(&self) -> bool {
+        self.incoming.is_empty()
+            && self.responding.is_empty()
+            && self.continuation.is_none()
+            && self
+                .outgoing
+                .iter()
+                .all(|out| matches!(out.source, Source::Events(..)))
+    }
+
+    /// Send the next part of every response, within the flow control windows
+    fn send_data(&mut self) -> Result<(), Failure> {
+        let mut buf = vec![0; BURST_SIZE];
+        for _ in 0..self.outgoing.len() {
+            let mut out = match self.outgoing.pop_front() {
+                Some(out) => out,
+                None => break,
+            };
+            if !self.send_stream(&mut out, &mut buf) {
+                self.outgoing.push_back(out);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send up to `BURST_SIZE` bytes of a response. Returns whether it is complete.
+    fn send_stream(&mut self, out: &mut Outgoing, buf: &mut [u8]) -> bool {
+        let mut budget = (self.window.min(out.window).max(0) as usize).min(buf.len());
+
+        match &mut out.source {
+            Source::Body(reader, remaining) => {
+                while budget > 0 {
+                    let size = budget.min(self.max_frame_size);
+                    let n = match read_full(reader, &mut buf[..size]) {
+                        Ok(n) if n > 0 || matches!(remaining, None | Some(0)) => n,
+                        Ok(_) => {
+                            warn!("Response body ended early");
+                            self.reset(out.stream_id, INTERNAL_ERROR);
+                            return true;
+                        }
+                        Err(e) => {
+                            warn!("Error reading response body: {}", e);
+                            self.reset(out.stream_id, INTERNAL_ERROR);
+                            return true;
+                        }
+                    };
+                    // Streamed bodies end when the reader does
+                    let done = match remaining {
+                        Some(remaining) => {
+                            *remaining = remaining.saturating_sub(n as u64);
+                            *remaining == 0
+                        }
+                        None => n < size,
+                    };
+                    let flags = if done { END_STREAM } else { 0 };
+                    self.write_frame(DATA, flags, out.stream_id, &buf[..n]);
+                    self.last_activity = Instant::now();
+                    self.window -= n as i64;
+                    out.window -= n as i64;
+                    budget -= n;
+                    if done {
+                        return true;
+                    }
+                }
+                false
+            }
+            Source::Events(receiver, pending, closed) => {
+                loop {
+                    match receiver.try_recv() {
+                        Ok(bytes) => pending.extend_from_slice(&bytes),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            *closed = true;
+                            break;
+                        }
+                    }
+                }
+                while budget > 0 && !pending.is_empty() {
+                    let size = budget.min(self.max_frame_size).min(pending.len());
+                    let chunk: Vec<u8> = pending.drain(..size).collect();
+                    self.write_frame(DATA, 0, out.stream_id, &chunk);
+                    self.window -= size as i64;
+                    out.window -= size as i64;
+                    budget -= size;
+                }
+                if *closed && pending.is_empty() {
+                    self.write_frame(DATA, END_STREAM, out.stream_id, &[]);
+                    return true;
+                }
+                false
+            }
+        }
+    }
+
+    fn reset(&mut self, stream_id: u32, code: u32) {
+        self.write_frame(RST_STREAM, 0, stream_id, &code.to_be_bytes());
+    }
+
+    fn go_away(&mut self, code: u32, reason: &str) {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(reason.as_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload);
+        self.going_away = true;
+    }
+
+    /// Queue a header block in a `HEADERS` frame and as many `CONTINUATION` frames
+    /// as needed
+    fn write_headers(&mut self, stream_id: u32, fields: &[(String, String)], end_stream: bool) {
+        let mut block = Vec::new();
+        for (name, value) in fields {
+            hpack::encode_field(&mut block, name.as_bytes(), value.as_bytes());
+        }
+
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, stream_id, chunk);
+            kind = CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        self.output
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        self.output.push(kind);
+        self.output.push(flags);
+        self.output.extend_from_slice(&stream_id.to_be_bytes());
+        self.output.extend_from_slice(payload);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        self.stream.write_all(&self.output)?;
+        self.stream.flush()?;
+        self.output.clear();
+        Ok(())
+    }
+}
+
+/// Whether a lowercase header name is connection-specific, which HTTP/2 and HTTP/3 forbid
+pub(crate) fn is_connection_header(name: &str) -> bool {
+    matches!(
+        name,
+        "connection" | "keep-alive" | "transfer-encoding" | "upgrade"
+    )
+}
+
+/// Read until `buf` is full or the reader ends. Returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+
+    use super::*;
+    use crate::response::Body;
+
+    /// Time to wait for frames that are expected
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    /// Time to wait for frames that are not expected
+    const QUIET: Duration = Duration::from_millis(200);
+
+    const CANCEL: u32 = 0x8;
+
+    /// Handler answering `/bytes/<n>` with `n` bytes, `/blocked` once `release` gets a
+    /// message and other paths with `hello`, keeping the event streams it is given
+    #[derive(Default)]
+    struct Files {
+        events: Mutex<Vec<EventStream>>,
+        busy: AtomicBool,
+        release: Mutex<Option<Receiver<()>>>,
+    }
+
+    impl Handler for Files {
+        fn respond(&self, req: &Request) -> Response {
+            let body = match req.path.strip_prefix("/bytes/") {
+                Some(n) => vec![b'x'; n.parse().unwrap()],
+                None if req.path == "/blocked" => {
+                    let release = self.release.lock().unwrap();
+                    release.as_ref().unwrap().recv().unwrap();
+                    b"released".to_vec()
+                }
+                None => b"hello".to_vec(),
+            };
+            Response::new("200 OK").body(Body::Bytes(body), "text/plain")
+        }
+
+        fn is_event_stream(&self, req: &Request) -> bool {
+            req.path == reload::EVENTS_PATH
+        }
+
+        fn attach(&self, events: EventStream) {
+            self.events.lock().unwrap().push(events);
+        }
+
+        fn is_busy(&self) -> bool {
+            self.busy.load(Ordering::SeqCst)
+        }
+    }
+
+    /// In-memory transport, receiving what the client sends and sending back frames
+    struct Pipe {
+        receiver: Receiver<Vec<u8>>,
+        received: Vec<u8>,
+        sender: Sender<Vec<u8>>,
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.sender.send(buf.to_vec()) {
+                Ok(()) => Ok(buf.len()),
+                Err(_) => Err(ErrorKind::BrokenPipe.into()),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Pipe {
+        fn read_within(&mut self, buf: &mut [u8], wait: Option<Duration>) -> io::Result<usize> {
+            if self.received.is_empty() {
+                let bytes = match wait {
+                    Some(timeout) => self.receiver.recv_timeout(timeout),
+                    None => self.receiver.try_recv().map_err(|e| match e {
+                        TryRecvError::Empty => RecvTimeoutError::Timeout,
+                        TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+                    }),
+                };
+                match bytes {
+                    Ok(bytes) => self.received = bytes,
+                    Err(RecvTimeoutError::Timeout) => return Err(ErrorKind::TimedOut.into()),
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.received.len());
+            buf[..n].copy_from_slice(&self.received[..n]);
+            self.received.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Frame {
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: Vec<u8>,
+    }
+
+    /// Response read by `Client::response`
+    struct Reply {
+        status: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    /// Client of a connection served in a thread over a `Pipe`
+    struct Client {
+        sender: Option<Sender<Vec<u8>>>,
+        receiver: Receiver<Vec<u8>>,
+        /// Bytes received but not yet parsed into frames
+        input: Vec<u8>,
+        decoder: hpack::Decoder,
+        connection: JoinHandle<io::Result<()>>,
+    }
+
+    fn options() -> Options {
+        Options {
+            limits: Limits::default(),
+            idle_timeout: TIMEOUT,
+            shutdown: Shutdown::new(),
+            client_subject: None,
+        }
+    }
+
+    /// Payload of a `SETTINGS` frame with one setting
+    fn setting(id: u16, value: u32) -> Vec<u8> {
+        let mut payload = id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&value.to_be_bytes());
+        payload
+    }
+
+    /// Header block of a `GET` request for `path`, with `extra` fields
+    fn request_block(path: &str, extra: &[(&str, &str)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        let fields = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+        ];
+        for (name, value) in fields.iter().chain(extra) {
+            hpack::encode_field(&mut block, name.as_bytes(), value.as_bytes());
+        }
+        block
+    }
+
+    impl Client {
+        /// Serve a new connection, without sending anything yet
+        fn connect(handler: Arc<Files>, options: Options) -> Client {
+            let (sender, server_receiver) = mpsc::channel();
+            let (server_sender, receiver) = mpsc::channel();
+            let pipe = Pipe {
+                receiver: server_receiver,
+                received: Vec::new(),
+                sender: server_sender,
+            };
+            let connection = thread::spawn(move || serve(pipe, &*handler, options));
+            Client {
+                sender: Some(sender),
+                receiver,
+                input: Vec::new(),
+                decoder: hpack::Decoder::default(),
+                connection,
+            }
+        }
+
+        /// Connect, then exchange the preface and settings
+        fn start(handler: Arc<Files>, options: Options) -> Client {
+            let mut client = Client::connect(handler, options);
+            client.send(PREFACE);
+            client.frame(SETTINGS, 0, 0, &[]);
+            let settings = client.read_frame();
+            assert_eq!((settings.kind, settings.flags), (SETTINGS, 0));
+            client.expect_settings_ack();
+            client
+        }
+
+        fn send(&self, bytes: &[u8]) {
+            let _ = self.sender.as_ref().unwrap().send(bytes.to_vec());
+        }
+
+        fn frame(&self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+            let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+            frame.push(kind);
+            frame.push(flags);
+            frame.extend_from_slice(&stream_id.to_be_bytes());
+            frame.extend_from_slice(payload);
+            self.send(&frame);
+        }
+
+        /// Send a `GET` request for `path` in a single `HEADERS` frame
+        fn get(&self, stream_id: u32, path: &str, flags: u8) {
+            let block = request_block(path, &[]);
+            self.frame(HEADERS, END_HEADERS | flags, stream_id, &block);
+        }
+
+        /// Next frame sent within `wait`, `None` if none was or the connection closed
+        fn read_frame_within(&mut self, wait: Duration) -> Option<Frame> {
+            loop {
+                if self.input.len() >= FRAME_HEADER_SIZE {
+                    let length =
+                        u32::from_be_bytes([0, self.input[0], self.input[1], self.input[2]]);
+                    let end = FRAME_HEADER_SIZE + length as usize;
+                    if self.input.len() >= end {
+                        let frame: Vec<u8> = self.input.drain(..end).collect();
+                        return Some(Frame {
+                            kind: frame[3],
+                            flags: frame[4],
+                            stream_id: u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]),
+                            payload: frame[FRAME_HEADER_SIZE..].to_vec(),
+                        });
+                    }
+                }
+                match self.receiver.recv_timeout(wait) {
+                    Ok(bytes) => self.input.extend_from_slice(&bytes),
+                    Err(_) => return None,
+                }
+            }
+        }
+
+        fn read_frame(&mut self) -> Frame {
+            self.read_frame_within(TIMEOUT)
+                .expect("no frame from the server")
+        }
+
+        fn expect_settings_ack(&mut self) {
+            let ack = self.read_frame();
+            assert_eq!((ack.kind, ack.flags), (SETTINGS, ACK), "{:?}", ack);
+        }
+
+        fn expect_quiet(&mut self) {
+            let frame = self.read_frame_within(QUIET);
+            assert!(frame.is_none(), "unexpected {:?}", frame);
+        }
+
+        /// Read frames until `GOAWAY`, and check its error code
+        fn expect_go_away(&mut self, code: u32) {
+            loop {
+                let frame = self.read_frame();
+                if frame.kind == GOAWAY {
+                    assert_eq!(frame.payload[4..8], code.to_be_bytes(), "{:?}", frame);
+                    return;
+                }
+            }
+        }
+
+        /// Read frames until `RST_STREAM` on `stream_id`, and check its error code
+        fn expect_reset(&mut self, stream_id: u32, code: u32) {
+            loop {
+                let frame = self.read_frame();
+                if frame.kind == RST_STREAM && frame.stream_id == stream_id {
+                    assert_eq!(frame.payload, code.to_be_bytes());
+                    return;
+                }
+            }
+        }
+
+        /// Decode a `HEADERS` frame sent in full by the server
+        fn decode_headers(&mut self, frame: &Frame) -> Vec<(String, String)> {
+            assert_eq!(frame.kind, HEADERS, "{:?}", frame);
+            assert!(frame.flags & END_HEADERS != 0);
+            self.decoder
+                .decode(&frame.payload, usize::MAX)
+                .unwrap()
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        String::from_utf8(name).unwrap(),
+                        String::from_utf8(value).unwrap(),
+                    )
+                })
+                .collect()
+        }
+
+        /// Read a `HEADERS` frame and decode it
+        fn read_headers(&mut self) -> Vec<(String, String)> {
+            let frame = self.read_frame();
+            self.decode_headers(&frame)
+        }
+
+        /// Read the response on `stream_id`, ignoring the frames of other streams
+        fn response(&mut self, stream_id: u32) -> Reply {
+            let mut reply: Option<Reply> = None;
+            loop {
+                let frame = self.read_frame();
+                if frame.stream_id != stream_id {
+                    continue;
+                }
+                let end_stream = frame.flags & END_STREAM != 0;
+                match &mut reply {
+                    None => {
+                        let mut headers = self.decode_headers(&frame);
+                        let (_, status) = headers.remove(0);
+                        reply = Some(Reply {
+                            status,
+                            headers,
+                            body: Vec::new(),
+                        });
+                    }
+                    Some(reply) => {
+                        assert_eq!(frame.kind, DATA, "{:?}", frame);
+                        reply.body.extend_from_slice(&frame.payload);
+                    }
+                }
+                if end_stream {
+                    return reply.unwrap();
+                }
+            }
+        }
+
+        /// Read `DATA` frames on `stream_id` totalling `length` bytes. Returns whether
+        /// the last one ended the stream.
+        fn read_data(&mut self, stream_id: u32, length: usize) -> bool {
+            let mut received = 0;
+            loop {
+                let frame = self.read_frame();
+                assert_eq!((frame.kind, frame.stream_id), (DATA, stream_id));
+                received += frame.payload.len();
+                assert!(
+                    received <= length,
+                    "{} bytes instead of {}",
+                    received,
+                    length
+                );
+                if received == length {
+                    return frame.flags & END_STREAM != 0;
+                }
+            }
+        }
+
+        /// Close the connection and wait for the server
+        fn finish(mut self) -> io::Result<()> {
+            self.sender = None;
+            self.connection.join().unwrap()
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_prefaces() {
+        let mut client = Client::connect(Arc::default(), options());
+        client.send(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        client.expect_go_away(PROTOCOL_ERROR);
+        assert_eq!(client.finish().unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // The connection closes before the preface is complete
+        let client = Client::connect(Arc::default(), options());
+        client.send(&PREFACE[..10]);
+        assert_eq!(
+            client.finish().unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn serves_requests() {
+        let mut client = Client::start(Arc::default(), options());
+        client.get(1, "/", END_STREAM);
+        let reply = client.response(1);
+        assert_eq!(reply.status, "200");
+        assert_eq!(
+            reply.headers,
+            vec![
+                (String::from("content-type"), String::from("text/plain")),
+                (String::from("content-length"), String::from("5")),
+            ]
+        );
+        assert_eq!(reply.body, b"hello");
+
+        // Request bodies are discarded, their window given back
+        client.get(3, "/", 0);
+        client.frame(DATA, END_STREAM, 3, b"body");
+        let update = client.read_frame();
+        assert_eq!((update.kind, update.stream_id), (WINDOW_UPDATE, 0));
+        assert_eq!(update.payload, 4u32.to_be_bytes());
+        assert_eq!(client.response(3).body, b"hello");
+
+        client.frame(PING, 0, 0, b"12345678");
+        let pong = client.read_frame();
+        assert_eq!((pong.kind, pong.flags), (PING, ACK));
+        assert_eq!(pong.payload, b"12345678");
+
+        client.frame(GOAWAY, 0, 0, &[0; 8]);
+        client.finish().unwrap();
+    }
+
+    #[test]
+    fn blocked_responses_do_not_hold_up_other_streams() {
+        let (release, receiver) = mpsc::channel();
+        let handler = Files {
+            release: Mutex::new(Some(receiver)),
+            ..Files::default()
+        };
+        let mut client = Client::start(Arc::new(handler), options());
+        client.get(1, "/blocked", END_STREAM);
+        client.get(3, "/", END_STREAM);
+        assert_eq!(client.response(3).body, b"hello");
+        client.frame(PING, 0, 0, b"12345678");
+        let pong = client.read_frame();
+        assert_eq!((pong.kind, pong.flags), (PING, ACK));
+
+        // Resetting the blocked stream drops its response. Frames are handled in
+        // order, so the reset is known once the PING is answered.
+        client.get(5, "/blocked", END_STREAM);
+        client.frame(RST_STREAM, 0, 5, &CANCEL.to_be_bytes());
+        client.frame(PING, 0, 0, b"12345678");
+        assert_eq!(client.read_frame().kind, PING);
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        let mut body = Vec::new();
+        loop {
+            let frame = client.read_frame();
+            assert_eq!(frame.stream_id, 1, "{:?}", frame);
+            if frame.kind == DATA {
+                body.extend_from_slice(&frame.payload);
+            }
+            if frame.flags & END_STREAM != 0 {
+                break;
+            }
+        }
+        assert_eq!(body, b"released");
+        client.expect_quiet();
+        client.finish().unwrap();
+    }
+
+    #[test]
+    fn sends_within_flow_control_windows() {
+        let mut client = Client::start(Arc::default(), options());
+        client.frame(SETTINGS, 0, 0, &setting(SETTINGS_INITIAL_WINDOW_SIZE, 100));
+        client.expect_settings_ack();
+
+        // The stream window is exhausted after 100 bytes
+        client.get(1, "/bytes/1000", END_STREAM);
+        client.read_headers();
+        assert!(!client.read_data(1, 100));
+        client.expect_quiet();
+        client.frame(WINDOW_UPDATE, 0, 1, &900u32.to_be_bytes());
+        assert!(client.read_data(1, 900));
+
+        // So is the connection window, 1000 bytes short of its initial size
+        client.frame(
+            SETTINGS,
+            0,
+            0,
+            &setting(SETTINGS_INITIAL_WINDOW_SIZE, 1 << 20),
+        );
+        client.expect_settings_ack();
+        client.get(3, "/bytes/70000", END_STREAM);
+        client.read_headers();
+        assert!(!client.read_data(3, 65535 - 1000));
+        client.expect_quiet();
+        client.frame(WINDOW_UPDATE, 0, 0, &10000u32.to_be_bytes());
+        assert!(client.read_data(3, 70000 - 65535 + 1000));
+
+        // Windows may not exceed 2^31 - 1 bytes
+        client.frame(WINDOW_UPDATE, 0, 0, &0x7fff_ffffu32.to_be_bytes());
+        client.expect_go_away(FLOW_CONTROL_ERROR);
+        assert!(client.finish().is_err());
+    }
+
+    #[test]
+    fn header_blocks_continue_without_interruption() {
+        let block = request_block("/", &[]);
+        let mut client = Client::start(Arc::default(), options());
+        client.frame(HEADERS, END_STREAM, 1, &block[..5]);
+        client.frame(CONTINUATION, 0, 1, &block[5..10]);
+        client.frame(CONTINUATION, END_HEADERS, 1, &block[10..]);
+        assert_eq!(client.response(1).body, b"hello");
+        client.finish().unwrap();
+
+        let interruptions: &[(u8, u32, &[u8])] = &[
+            (PING, 0, &[0; 8]),
+            (HEADERS, 5, &block),
+            (CONTINUATION, 5, &block[5..]),
+            (DATA, 3, b""),
+        ];
+        for (kind, stream_id, payload) in interruptions {
+            let mut client = Client::start(Arc::default(), options());
+            client.frame(HEADERS, END_STREAM, 3, &block[..5]);
+            client.frame(*kind, END_HEADERS, *stream_id, payload);
+            client.expect_go_away(PROTOCOL_ERROR);
+            assert!(client.finish().is_err(), "{}", kind);
+        }
+
+        let mut client = Client::start(Arc::default(), options());
+        client.frame(CONTINUATION, END_HEADERS, 1, &block);
+        client.expect_go_away(PROTOCOL_ERROR);
+        assert!(client.finish().is_err());
+    }
+
+    #[test]
+    fn refuses_large_header_lists() {
+        let mut options = options();
+        options.limits.max_header_size = 512;
+        options.limits.max_headers = 8;
+        let mut client = Client::start(Arc::default(), options);
+
+        let long = "x".repeat(600);
+        let block = request_block("/", &[("x-long", &long)]);
+        client.frame(HEADERS, END_HEADERS | END_STREAM, 1, &block);
+        assert_eq!(client.response(1).status, "431");
+
+        let many = [("x-a", "1"); 5];
+        let block = request_block("/", &many);
+        client.frame(HEADERS, END_HEADERS | END_STREAM, 3, &block);
+        assert_eq!(client.response(3).status, "431");
+
+        // The connection goes on, with the decoder in sync
+        let block = request_block("/", &[("x-a", "1"); 4]);
+        client.frame(HEADERS, END_HEADERS | END_STREAM, 5, &block);
+        assert_eq!(client.response(5).status, "200");
+
+        // Blocks continued past twice the limit end the connection
+        client.frame(HEADERS, END_STREAM, 7, &[0; 600]);
+        client.frame(CONTINUATION, 0, 7, &[0; 600]);
+        client.expect_go_away(ENHANCE_YOUR_CALM);
+        assert!(client.finish().is_err());
+    }
+
+    #[test]
+    fn limits_concurrent_streams() {
+        let mut client = Client::start(Arc::default(), options());
+        let ids: Vec<u32> = (0..MAX_CONCURRENT_STREAMS as u32)
+            .map(|i| 2 * i + 1)
+            .collect();
+        for id in &ids {
+            client.get(*id, "/", 0);
+        }
+        let next = 2 * MAX_CONCURRENT_STREAMS as u32 + 1;
+        client.get(next, "/", END_STREAM);
+        client.expect_reset(next, REFUSED_STREAM);
+
+        // Streams reset by the client make room for new ones
+        client.frame(RST_STREAM, 0, 1, &CANCEL.to_be_bytes());
+        client.get(next + 2, "/", END_STREAM);
+        assert_eq!(client.response(next + 2).status, "200");
+        client.frame(DATA, END_STREAM, 3, b"");
+        assert_eq!(client.response(3).status, "200");
+
+        // Reset streams may not be reopened
+        client.get(1, "/", END_STREAM);
+        client.expect_go_away(PROTOCOL_ERROR);
+        assert!(client.finish().is_err());
+    }
+
+    #[test]
+    fn reset_streams_stop_sending() {
+        let mut client = Client::start(Arc::default(), options());
+        client.frame(SETTINGS, 0, 0, &setting(SETTINGS_INITIAL_WINDOW_SIZE, 100));
+        client.expect_settings_ack();
+        client.get(1, "/bytes/1000", END_STREAM);
+        client.read_headers();
+        client.read_data(1, 100);
+
+        client.frame(RST_STREAM, 0, 1, &CANCEL.to_be_bytes());
+        client.frame(WINDOW_UPDATE, 0, 1, &900u32.to_be_bytes());
+        client.expect_quiet();
+        client.get(3, "/", END_STREAM);
+        assert_eq!(client.response(3).body, b"hello");
+        client.finish().unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_header_blocks() {
+        let blocks: &[&[u8]] = &[
+            &[0x80],
+            &[0x00, 0x03],
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0x0f],
+        ];
+        for block in blocks {
+            let mut client = Client::start(Arc::default(), options());
+            client.frame(HEADERS, END_HEADERS | END_STREAM, 1, block);
+            client.expect_go_away(COMPRESSION_ERROR);
+            assert_eq!(
+                client.finish().unwrap_err().kind(),
+                ErrorKind::InvalidData,
+                "{:?}",
+                block
+            );
+        }
+
+        // Requests without pseudo-headers are reset, the connection goes on
+        let mut client = Client::start(Arc::default(), options());
+        let mut block = Vec::new();
+        hpack::encode_field(&mut block, b"host", b"localhost");
+        client.frame(HEADERS, END_HEADERS | END_STREAM, 1, &block);
+        client.expect_reset(1, PROTOCOL_ERROR);
+        client.get(3, "/", END_STREAM);
+        assert_eq!(client.response(3).status, "200");
+        client.finish().unwrap();
+    }
+
+    #[test]
+    fn idle_connections_make_way_for_waiting_ones() {
+        let handler = Arc::new(Files::default());
+        let mut client = Client::start(Arc::clone(&handler), options());
+
+        // An open event stream leaves the connection idle
+        client.get(1, reload::EVENTS_PATH, END_STREAM);
+        assert_eq!(
+            client.read_headers()[1],
+            (
+                String::from("content-type"),
+                String::from("text/event-stream")
+            )
+        );
+        handler.events.lock().unwrap()[0]
+            .write_all(b"data: 1\n\n")
+            .unwrap();
+        let event = client.read_frame();
+        assert_eq!((event.kind, event.payload), (DATA, b"data: 1\n\n".to_vec()));
+
+        // Responses in progress are finished first
+        client.frame(SETTINGS, 0, 0, &setting(SETTINGS_INITIAL_WINDOW_SIZE, 100));
+        client.expect_settings_ack();
+        client.get(3, "/bytes/1000", END_STREAM);
+        client.read_headers();
+        client.read_data(3, 100);
+        handler.busy.store(true, Ordering::SeqCst);
+        client.expect_quiet();
+        client.frame(WINDOW_UPDATE, 0, 3, &900u32.to_be_bytes());
+        assert!(client.read_data(3, 900));
+        client.expect_go_away(NO_ERROR);
+        client.finish().unwrap();
+    }
+
+    #[test]
+    fn event_streams_do_not_keep_connections_open() {
+        let mut options = options();
+        options.idle_timeout = Duration::from_millis(300);
+        let handler = Arc::new(Files::default());
+        let mut client = Client::start(Arc::clone(&handler), options);
+        client.get(1, reload::EVENTS_PATH, END_STREAM);
+        client.read_frame();
+
+        // Events sent to the client are not activity
+        let started = Instant::now();
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(100));
+            let _ = handler.events.lock().unwrap()[0].write_all(b": ping\n\n");
+        }
+        client.expect_go_away(NO_ERROR);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        client.finish().unwrap();
+    }
+}
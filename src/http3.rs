@@ -0,0 +1,454 @@
+//! Experimental HTTP/3 (RFC 9114) over QUIC, behind the `http3` feature.
+//!
+//! The listener shares the port number and certificate of the TLS listener over UDP,
+//! and answers requests with the same `Handler` as HTTP/2. QUIC needs an async
+//! runtime, so connections are served on a small tokio runtime of their own, while
+//! file reads happen on its blocking threads.
+
+use std::convert::{TryFrom, TryInto};
+use std::error::Error as StdError;
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+use h3::error::ConnectionError;
+use h3::server::RequestResolver;
+use log::warn;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use rustls::pki_types::CertificateDer;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+use tokio::task;
+
+use crate::error::{Error, Result};
+use crate::http2::{self, EventStream, Handler};
+use crate::request::{Limits, Request};
+use crate::response::Response;
+use crate::stream;
+use crate::tls;
+
+/// Protocol name of HTTP/3 in ALPN and `Alt-Svc`
+pub const ALPN: &[u8] = b"h3";
+
+/// Application error code closing connections normally (`H3_NO_ERROR`)
+const NO_ERROR: u32 = 0x100;
+/// Size of the DATA frames of response bodies
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Interval at which event streams are checked for new events
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Threads of the runtime driving QUIC connections
+const WORKER_THREADS: usize = 2;
+/// Time given to close frames to be sent once connections are closed
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Settings of the listener
+pub struct Options {
+    /// Limits on the size of request headers
+    pub limits: Limits,
+    /// Time after which idle connections are closed
+    pub idle_timeout: Duration,
+}
+
+/// Connections accepted and still open
+#[derive(Default)]
+struct Counters {
+    accepted: AtomicUsize,
+    open: AtomicUsize,
+}
+
+/// HTTP/3 listener, serving until `close` and `drain` are called
+pub struct Listener {
+    endpoint: quinn::Endpoint,
+    runtime: Runtime,
+    /// Set once the listener closes, asking connections to finish
+    closing: watch::Sender<bool>,
+    counters: Arc<Counters>,
+}
+
+impl Listener {
+    /// Bind `address` over UDP and serve connections with the certificate and client
+    /// verification of `tls`, answering requests with `handler`
+    pub fn bind<H>(
+        address: SocketAddr,
+        tls: &rustls::ServerConfig,
+        handler: Arc<H>,
+        options: Options,
+    ) -> Result<Listener>
+    where
+        H: Handler + Send + Sync + 'static,
+    {
+        let mut crypto = tls.clone();
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicServerConfig::try_from(crypto)
+            .map_err(|e| Error::Tls(format!("TLS configuration unusable for QUIC: {}", e)))?;
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let mut transport = quinn::TransportConfig::default();
+        transport.max_idle_timeout(options.idle_timeout.try_into().ok());
+        config.transport_config(Arc::new(transport));
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(WORKER_THREADS)
+            .thread_name("syserve-http3")
+            .enable_all()
+            .build()?;
+        let endpoint = {
+            let _guard = runtime.enter();
+            quinn::Endpoint::server(config, address).map_err(|e| Error::Bind(address, e))?
+        };
+
+        let (closing, _) = watch::channel(false);
+        let counters = Arc::new(Counters::default());
+        runtime.spawn(accept(
+            endpoint.clone(),
+            handler,
+            closing.subscribe(),
+            Arc::clone(&counters),
+            options.limits,
+        ));
+
+        Ok(Listener {
+            endpoint,
+            runtime,
+            closing,
+            counters,
+        })
+    }
+
+    /// Address the listener is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Number of connections accepted
+    pub fn accepted(&self) -> usize {
+        self.counters.accepted.load(Ordering::SeqCst)
+    }
+
+    /// Number of open connections
+    pub fn open(&self) -> usize {
+        self.counters.open.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting connections, and have open ones finish their requests in progress
+    /// and close, see `drain`
+    pub fn close(&self) {
+        self.endpoint.set_server_config(None);
+        let _ = self.closing.send(true);
+    }
+
+    /// Wait until open connections are closed, or `deadline`, after which those left
+    /// are closed. Returns the number of connections closed early.
+    pub fn drain(self, deadline: Instant) -> usize {
+        self.close();
+        let counters = Arc::clone(&self.counters);
+        let endpoint = self.endpoint.clone();
+        let aborted = self.runtime.block_on(async move {
+            while counters.open.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+                tokio::time::sleep(EVENT_POLL_INTERVAL).await;
+            }
+            let aborted = counters.open.load(Ordering::SeqCst);
+            endpoint.close(NO_ERROR.into(), b"shutting down");
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, endpoint.wait_idle()).await;
+            aborted
+        });
+        self.runtime.shutdown_background();
+        aborted
+    }
+}
+
+/// Accept connections until the endpoint is closed
+async fn accept<H>(
+    endpoint: quinn::Endpoint,
+    handler: Arc<H>,
+    closing: watch::Receiver<bool>,
+    counters: Arc<Counters>,
+    limits: Limits,
+) where
+    H: Handler + Send + Sync + 'static,
+{
+    while let Some(incoming) = endpoint.accept().await {
+        counters.accepted.fetch_add(1, Ordering::SeqCst);
+        counters.open.fetch_add(1, Ordering::SeqCst);
+        let handler = Arc::clone(&handler);
+        let closing = closing.clone();
+        let counters = Arc::clone(&counters);
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(incoming, handler, closing, limits).await {
+                warn!("HTTP/3 connection closed: {}", e);
+            }
+            counters.open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Serve the requests of a connection until it is closed, or the listener closes
+/// and the requests in progress are answered
+async fn serve_connection<H>(
+    incoming: quinn::Incoming,
+    handler: Arc<H>,
+    mut closing: watch::Receiver<bool>,
+    limits: Limits,
+) -> std::result::Result<(), BoxError>
+where
+    H: Handler + Send + Sync + 'static,
+{
+    let connection = incoming.await?;
+    let client_subject = client_subject(&connection);
+    let mut h3 = h3::server::builder()
+        .max_field_section_size(limits.max_header_size as u64)
+        .build(h3_quinn::Connection::new(connection))
+        .await?;
+
+    let mut going_away = false;
+    loop {
+        tokio::select! {
+            resolver = h3.accept() => match resolver {
+                Ok(Some(resolver)) => {
+                    let handler = Arc::clone(&handler);
+                    let client_subject = client_subject.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_request(resolver, handler, client_subject).await {
+                            warn!("HTTP/3 request failed: {}", e);
+                        }
+                    });
+                }
+                Ok(None) => return Ok(()),
+                Err(e) if e.is_h3_no_error() || matches!(e, ConnectionError::Timeout { .. }) => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e.into()),
+            },
+            _ = closing.changed(), if !going_away => {
+                // No new requests, `accept` returns `None` once those in progress are done
+                going_away = true;
+                h3.shutdown(0).await?;
+            }
+        }
+    }
+}
+
+async fn serve_request<H>(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    handler: Arc<H>,
+    client_subject: Option<String>,
+) -> std::result::Result<(), BoxError>
+where
+    H: Handler + Send + Sync + 'static,
+{
+    let (head, mut stream) = resolver.resolve_request().await?;
+    // Request bodies are not used
+    while stream.recv_data().await?.is_some() {}
+    let req = request(head, client_subject);
+
+    if handler.is_event_stream(&req) {
+        let head = http::Response::builder()
+            .status(200)
+            .header("content-type", "text/event-stream")
+            .header("cache-control", "no-cache")
+            .body(())?;
+        stream.send_response(head).await?;
+
+        let (events, receiver): (EventStream, _) = EventStream::new();
+        handler.attach(events);
+        loop {
+            match receiver.try_recv() {
+                Ok(bytes) => stream.send_data(Bytes::from(bytes)).await?,
+                Err(TryRecvError::Empty) => tokio::time::sleep(EVENT_POLL_INTERVAL).await,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        return Ok(stream.finish().await?);
+    }
+
+    // Responses may wait for a build and read files, which must not block the runtime
+    let head_only = req.method == "HEAD";
+    let response = task::block_in_place(|| handler.respond(&req));
+    stream.send_response(response_head(&response)?).await?;
+
+    if !head_only {
+        let mut reader = response.body.into_reader();
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = task::block_in_place(|| reader.read(&mut buf))?;
+            if n == 0 {
+                break;
+            }
+            stream.send_data(Bytes::copy_from_slice(&buf[..n])).await?;
+        }
+    }
+    Ok(stream.finish().await?)
+}
+
+/// Request from the decoded head of an HTTP/3 request. The authority is turned into
+/// a `host` header.
+fn request(head: http::Request<()>, client_subject: Option<String>) -> Request {
+    let (parts, ()) = head.into_parts();
+    let mut headers: Vec<(String, String)> = parts
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_str().to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect();
+    if !headers.iter().any(|(name, _)| name == "host") {
+        if let Some(authority) = parts.uri.authority() {
+            headers.push((String::from("host"), authority.to_string()));
+        }
+    }
+
+    Request {
+        method: parts.method.as_str().to_string(),
+        path: parts
+            .uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_string(),
+        version: 1,
+        headers,
+        body: Vec::new(),
+        client_subject,
+    }
+}
+
+/// Status and headers of a response. `content-length` is that of the full body,
+/// as in HTTP/1.1 and HTTP/2, and left out for streamed bodies.
+fn response_head(response: &Response) -> std::result::Result<http::Response<()>, BoxError> {
+    let status: u16 = response.status[..3].parse()?;
+    let mut head = http::Response::builder().status(status);
+    for (name, value) in &response.headers {
+        let name = name.to_ascii_lowercase();
+        if !http2::is_connection_header(&name) {
+            head = head.header(name, value.as_str());
+        }
+    }
+    if let (Some(length), false) = (response.body.len(), response.status.starts_with("304")) {
+        head = head.header("content-length", length);
+    }
+    Ok(head.body(())?)
+}
+
+/// Subject of the certificate the client authenticated with, if any
+fn client_subject(connection: &quinn::Connection) -> Option<String> {
+    let identity = connection.peer_identity()?;
+    let certs = identity.downcast_ref::<Vec<CertificateDer<'static>>>()?;
+    stream::certificate_subject(certs.first()?)
+}
+
+/// Response received by `fetch`
+pub struct Fetched {
+    /// Status code, e.g. `200`
+    pub status: u16,
+    /// Header names and values, in order
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Fetched {
+    /// Value of the first header named `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Minimal HTTP/3 client for testing the listener on localhost: send a `method` request
+/// for `url` (`https://host:port/path`), trusting the PEM certificates in `ca`,
+/// e.g. those of the local CA
+pub fn fetch(method: &str, url: &str, ca: &str) -> Result<Fetched> {
+    let invalid = |message: String| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, message));
+    let uri: http::Uri = url
+        .parse()
+        .map_err(|e| invalid(format!("invalid URL {:?}: {}", url, e)))?;
+    let host = uri
+        .host()
+        .ok_or_else(|| invalid(format!("no host in {:?}", url)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let address = (host.as_str(), uri.port_u16().unwrap_or(443))
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid(format!("could not resolve {}", host)))?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in tls::parse_certs(ca)? {
+        roots
+            .add(cert)
+            .map_err(|e| Error::Tls(format!("invalid CA certificate: {}", e)))?;
+    }
+    let mut crypto = rustls::ClientConfig::builder_with_provider(tls::provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| Error::Tls(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(crypto).map_err(|e| Error::Tls(e.to_string()))?;
+    let config = quinn::ClientConfig::new(Arc::new(crypto));
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let local: SocketAddr = if address.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+
+    runtime
+        .block_on(async {
+            let mut endpoint = quinn::Endpoint::client(local)?;
+            endpoint.set_default_client_config(config);
+            let connection = endpoint.connect(address, &host)?.await?;
+            let (mut driver, mut send_request) =
+                h3::client::new(h3_quinn::Connection::new(connection)).await?;
+            let driver =
+                tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+            let req = http::Request::builder().method(method).uri(uri).body(())?;
+            let mut stream = send_request.send_request(req).await?;
+            stream.finish().await?;
+            let head = stream.recv_response().await?;
+            let mut body = Vec::new();
+            while let Some(mut chunk) = stream.recv_data().await? {
+                while chunk.has_remaining() {
+                    let bytes = chunk.chunk();
+                    body.extend_from_slice(bytes);
+                    let n = bytes.len();
+                    chunk.advance(n);
+                }
+            }
+
+            drop(send_request);
+            driver.abort();
+            endpoint.close(NO_ERROR.into(), b"done");
+            endpoint.wait_idle().await;
+
+            let headers = head
+                .headers()
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        name.as_str().to_string(),
+                        String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                    )
+                })
+                .collect();
+            Ok(Fetched {
+                status: head.status().as_u16(),
+                headers,
+                body,
+            })
+        })
+        .map_err(|e: BoxError| Error::Io(io::Error::other(e)))
+}
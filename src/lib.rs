@@ -3,10 +3,35 @@
 //! Inspired from [devserver](https://github.com/kettle11/devserver) and the final project from the [rust book](https://doc.rust-lang.org/stable/book/) as a simple, minimal development server tailored to yew and yew router apps.
 //!
 //! This crate does not utilize any large server libraries like actix or rocket and is written using `std::net` with the `httparse` and `threadpool` libraries.
-//! Syserve is meant to be a minimal but featureful development server with HTTPS support (intended) and customization over file serving.
+//! Syserve is meant to be a minimal but featureful development server with HTTPS support (`tls` feature) and customization over file serving.
 //!
 //! **This should not be used in production.**
 
+pub mod build;
+#[cfg(feature = "tls")]
+pub mod ca;
 pub mod cli;
+pub mod compress;
+pub mod conditional;
+pub mod diagnostics;
+pub mod error;
+pub mod hpack;
+pub mod http2;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod listing;
+pub mod range;
+pub mod reload;
+pub mod request;
+pub mod response;
+pub mod sandbox;
 pub mod server;
+pub mod shutdown;
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod url;
 pub mod utils;
+pub mod watch;
+
+pub use error::Error;
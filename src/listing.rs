@@ -0,0 +1,275 @@
+//! HTML and JSON listings of directories without an index file
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde_json::{json, Value};
+
+use crate::sandbox::Sandbox;
+use crate::url::{self, Url};
+use crate::utils::{self, escape_html};
+
+/// A file or directory in a listing
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Size in bytes, `0` for directories
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// MIME type from `utils::extension_to_mime`, `None` for directories
+    pub mime: Option<&'static str>,
+}
+
+/// Column a listing is sorted by
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    /// Parse the `sort` query parameter, defaulting to `Name`
+    pub fn from_param(param: Option<&str>) -> SortKey {
+        match param {
+            Some("size") => SortKey::Size,
+            Some("mtime") => SortKey::Modified,
+            _ => SortKey::Name,
+        }
+    }
+
+    fn param(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "mtime",
+        }
+    }
+}
+
+/// Read the entries of the directory at URL path `segments`.
+/// Hidden entries (starting with `.`) are left out unless `show_hidden` is set, and so are
+/// entries refused by the sandbox, e.g. symbolic links leaving the served directory.
+pub fn read_entries(
+    sandbox: &Sandbox,
+    segments: &[String],
+    show_hidden: bool,
+) -> io::Result<Vec<Entry>> {
+    let dir = sandbox
+        .resolve(segments)
+        .map_err(|v| io::Error::new(io::ErrorKind::PermissionDenied, v.to_string()))?;
+
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let name = match dir_entry?.file_name().into_string() {
+            Ok(name) => name,
+            // Names that are not UTF-8 cannot be requested through a URL
+            Err(_) => continue,
+        };
+        if name.starts_with('.') && !show_hidden {
+            continue;
+        }
+
+        let mut entry_segments = segments.to_vec();
+        entry_segments.push(name.clone());
+        let path = match sandbox.resolve(&entry_segments) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        // Follows symbolic links allowed by the sandbox; broken links are left out
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        let is_dir = metadata.is_dir();
+        entries.push(Entry {
+            mime: if is_dir {
+                None
+            } else {
+                Some(utils::extension_to_mime(
+                    Path::new(&name)
+                        .extension()
+                        .and_then(std::ffi::OsStr::to_str),
+                ))
+            },
+            name,
+            is_dir,
+            size: if is_dir { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Sort entries, directories first
+pub fn sort(entries: &mut [Entry], key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        };
+        let order = if descending { order.reverse() } else { order };
+        b.is_dir
+            .cmp(&a.is_dir)
+            .then(order)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+}
+
+/// HTML page for the directory at `url`, with column headers linking to sorted listings.
+/// Entries link to absolute paths, as the directory may be listed without a trailing
+/// slash (`TrailingSlash::Ignore`).
+pub fn to_html(url: &Url, entries: &[Entry], key: SortKey, descending: bool) -> String {
+    let header = |label: &str, column: SortKey| {
+        let desc = column == key && !descending;
+        let arrow = match (column == key, descending) {
+            (true, false) => " &#9650;",
+            (true, true) => " &#9660;",
+            _ => "",
+        };
+        format!(
+            "<th><a href=\"?sort={}&amp;order={}\">{}</a>{}</th>",
+            column.param(),
+            if desc { "desc" } else { "asc" },
+            label,
+            arrow
+        )
+    };
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {0}</title>\n\
+         <style>body{{font-family:sans-serif}}td,th{{padding:0.2em 1em;text-align:left}}</style>\n\
+         </head>\n<body>\n<h1>Index of {0}</h1>\n<table>\n<tr>{1}{2}{3}<th>Type</th></tr>\n",
+        escape_html(&url.path()),
+        header("Name", SortKey::Name),
+        header("Size", SortKey::Size),
+        header("Modified", SortKey::Modified),
+    );
+
+    let encoded: Vec<String> = url
+        .segments
+        .iter()
+        .map(|s| url::percent_encode(s))
+        .collect();
+    let dir = dir_href(&encoded);
+    if let Some((_, parents)) = encoded.split_last() {
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">../</a></td><td></td><td></td><td></td></tr>\n",
+            escape_html(&dir_href(parents))
+        ));
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&format!("{}{}", dir, url::percent_encode(&entry.name))),
+            slash,
+            escape_html(&entry.name),
+            slash,
+            if entry.is_dir {
+                String::from("-")
+            } else {
+                entry.size.to_string()
+            },
+            entry
+                .modified
+                .map(httpdate::fmt_http_date)
+                .unwrap_or_default(),
+            escape_html(entry.mime.unwrap_or("directory")),
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    html
+}
+
+/// Absolute path of the directory with the given percent-encoded segments,
+/// with a trailing slash
+fn dir_href(segments: &[String]) -> String {
+    if segments.is_empty() {
+        String::from("/")
+    } else {
+        format!("/{}/", segments.join("/"))
+    }
+}
+
+/// JSON document for the directory at `path`
+pub fn to_json(path: &str, entries: &[Entry]) -> String {
+    let entries: Vec<Value> = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "size": entry.size,
+                "modified": entry
+                    .modified
+                    .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+                "mime": entry.mime,
+            })
+        })
+        .collect();
+
+    format!("{}\n", json!({ "path": path, "entries": entries }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, is_dir: bool) -> Entry {
+        Entry {
+            name: String::from(name),
+            is_dir,
+            size: 0,
+            modified: None,
+            mime: None,
+        }
+    }
+
+    #[test]
+    fn links_are_absolute() {
+        let entries = [entry("a b&c", true), entry("f.txt", false)];
+        for target in ["/sub/dir", "/sub/dir/"] {
+            let html = to_html(&Url::parse(target).unwrap(), &entries, SortKey::Name, false);
+            assert!(html.contains("<a href=\"/sub/\">../</a>"), "{}", html);
+            assert!(
+                html.contains("<a href=\"/sub/dir/a%20b&amp;c/\">"),
+                "{}",
+                html
+            );
+            assert!(html.contains("<a href=\"/sub/dir/f.txt\">"), "{}", html);
+        }
+
+        let html = to_html(&Url::parse("/").unwrap(), &entries, SortKey::Name, false);
+        assert!(!html.contains(">../</a>"));
+        assert!(html.contains("<a href=\"/f.txt\">"));
+    }
+
+    #[test]
+    fn escapes_json() {
+        let mut file = entry("quote\"\n.txt", false);
+        file.size = 3;
+        file.mime = Some("text/plain; charset=utf8");
+        let json: Value = serde_json::from_str(&to_json("/a\\b", &[file])).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "path": "/a\\b",
+                "entries": [{
+                    "name": "quote\"\n.txt",
+                    "type": "file",
+                    "size": 3,
+                    "modified": null,
+                    "mime": "text/plain; charset=utf8",
+                }],
+            })
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use syserve::cli::Args;
+use syserve::cli::{Args, CaArgs};
 use syserve::server::serve;
 
 #[macro_use]
@@ -12,6 +12,16 @@ fn main() {
         .write_style_or("SYSERVE_LOG_STYLE", "always");
     env_logger::init_from_env(env);
 
+    if std::env::args().nth(1).as_deref() == Some("ca") {
+        let mut args = CaArgs::new();
+        args.store(std::env::args().skip(2).collect());
+        if let Err(e) = ca(args) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut args = Args::new();
 
     if let Err(s) = args.parse() {
@@ -21,5 +31,24 @@ fn main() {
 
     info!("Starting server...");
 
-    serve(args);
+    match serve(args) {
+        Ok(summary) => std::process::exit(summary.exit_code()),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `syserve ca` subcommand, managing the local certificate authority
+#[cfg(feature = "tls")]
+fn ca(args: CaArgs) -> syserve::error::Result<()> {
+    syserve::ca::run(args)
+}
+
+#[cfg(not(feature = "tls"))]
+fn ca(_: CaArgs) -> syserve::error::Result<()> {
+    Err(syserve::error::Error::Config(String::from(
+        "the local CA requires syserve to be built with the `tls` feature",
+    )))
 }
@@ -0,0 +1,165 @@
+//! Byte-range requests (`Range` header)
+
+use std::ops::Range;
+
+use crate::response::Segment;
+
+/// Result of evaluating a `Range` header against a resource length
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    /// No usable range was requested, the full resource is sent
+    Full,
+    /// One or more satisfiable ranges, with exclusive ends
+    Partial(Vec<Range<u64>>),
+    /// None of the requested ranges overlap the resource
+    Unsatisfiable,
+}
+
+/// Upper bound on the number of ranges answered in a single response.
+/// Requests with more ranges are served in full.
+const MAX_RANGES: usize = 32;
+
+/// Parse a `Range` header value for a resource of `len` bytes.
+/// Syntactically invalid headers and units other than `bytes` are ignored,
+/// as allowed by RFC 7233.
+pub fn parse(header: &str, len: u64) -> Ranges {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return Ranges::Full,
+    };
+
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+    if specs.is_empty() {
+        return Ranges::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (start, end) = match spec.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => return Ranges::Full,
+        };
+
+        let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // `first-last`
+            (Ok(first), Ok(last)) if first <= last => first..last.saturating_add(1).min(len),
+            // `first-`
+            (Ok(first), Err(_)) if end.is_empty() => first..len,
+            // `-suffix`
+            (Err(_), Ok(suffix)) if start.is_empty() => len.saturating_sub(suffix)..len,
+            _ => return Ranges::Full,
+        };
+
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        Ranges::Full
+    } else if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Partial(ranges)
+    }
+}
+
+/// `Content-Range` value of a satisfiable range
+pub fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// `multipart/byteranges` body with one part per range of a file of `len` bytes
+pub fn multipart_segments(
+    len: u64,
+    ranges: &[Range<u64>],
+    content_type: &str,
+    boundary: &str,
+) -> Vec<Segment> {
+    let mut segments = Vec::new();
+
+    for (i, range) in ranges.iter().enumerate() {
+        // Each part is preceded by the CRLF ending the previous one
+        let delimiter = if i == 0 { "" } else { "\r\n" };
+        segments.push(Segment::Bytes(
+            format!(
+                "{}--{}\r\nContent-type: {}\r\nContent-Range: {}\r\n\r\n",
+                delimiter,
+                boundary,
+                content_type,
+                content_range(range, len)
+            )
+            .into_bytes(),
+        ));
+        segments.push(Segment::Range(range.clone()));
+    }
+    segments.push(Segment::Bytes(
+        format!("\r\n--{}--\r\n", boundary).into_bytes(),
+    ));
+
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn parses_ranges() {
+        let cases: &[(&str, Ranges)] = &[
+            ("bytes=0-9", Ranges::Partial(vec![0..10])),
+            ("bytes=10-", Ranges::Partial(vec![10..100])),
+            ("bytes=-10", Ranges::Partial(vec![90..100])),
+            ("bytes=-1000", Ranges::Partial(vec![0..100])),
+            ("bytes=90-1000", Ranges::Partial(vec![90..100])),
+            (" bytes= 0-0 , 99-99 ", Ranges::Partial(vec![0..1, 99..100])),
+            // Overlapping ranges are answered as requested, in order
+            ("bytes=0-49,25-74", Ranges::Partial(vec![0..50, 25..75])),
+            ("bytes=50-,0-9", Ranges::Partial(vec![50..100, 0..10])),
+            // Unsatisfiable ranges are skipped
+            ("bytes=100-,0-9", Ranges::Partial(vec![0..10])),
+            ("bytes=100-", Ranges::Unsatisfiable),
+            ("bytes=200-299", Ranges::Unsatisfiable),
+            ("bytes=-0", Ranges::Unsatisfiable),
+            // Invalid headers are ignored
+            ("bytes=9-0", Ranges::Full),
+            ("bytes=-", Ranges::Full),
+            ("bytes=a-b", Ranges::Full),
+            ("bytes=0-9,x", Ranges::Full),
+            ("bytes=", Ranges::Full),
+            ("items=0-9", Ranges::Full),
+            ("0-9", Ranges::Full),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(&parse(header, 100), expected, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn too_many_ranges_are_served_in_full() {
+        let ranges = |count: u64| {
+            (0..count)
+                .map(|i| format!("{}-{}", i, i))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let header = format!("bytes={}", ranges(MAX_RANGES as u64));
+        assert!(matches!(parse(&header, 100), Ranges::Partial(r) if r.len() == MAX_RANGES));
+        let header = format!("bytes={}", ranges(MAX_RANGES as u64 + 1));
+        assert_eq!(parse(&header, 100), Ranges::Full);
+        // The same range repeated counts every time
+        let header = format!("bytes={}", vec!["0-"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&header, 100), Ranges::Full);
+    }
+
+    #[test]
+    fn formats_content_range() {
+        assert_eq!(content_range(&(0..10), 100), "bytes 0-9/100");
+        assert_eq!(content_range(&(99..100), 100), "bytes 99-99/100");
+    }
+}
@@ -0,0 +1,393 @@
+//! Live reload: change notifications sent to browsers as Server-Sent Events,
+//! and the client script injected into HTML pages
+
+use std::io::{self, Write};
+use std::net;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::stream::Stream;
+
+/// Path of the event stream the injected script connects to
+pub const EVENTS_PATH: &str = "/__syserve/events";
+
+/// Time after which a client not accepting an event is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Client script. On `change` events, stylesheets are swapped if only CSS files changed
+/// and the page is reloaded otherwise.
+/// New stylesheets replace the old ones once loaded, avoiding a flash of unstyled content.
+/// `build-error` events show the error overlay of a failed build, see
+/// `diagnostics::overlay`, and `build-ok` events remove it.
+const SCRIPT: &str = r#"<script>
+(function () {
+  var source = new EventSource("/__syserve/events");
+
+  function removeOverlay() {
+    var overlay = document.getElementById("__syserve-overlay");
+    if (overlay) overlay.remove();
+  }
+  source.addEventListener("build-error", function (event) {
+    removeOverlay();
+    document.body.insertAdjacentHTML("beforeend", event.data);
+  });
+  source.addEventListener("build-ok", removeOverlay);
+
+  source.addEventListener("change", function (event) {
+    var paths = JSON.parse(event.data);
+    var onlyCss = paths.every(function (path) {
+      return /\.css$/i.test(path);
+    });
+    if (!onlyCss) {
+      location.reload();
+      return;
+    }
+
+    var links = document.querySelectorAll('link[rel="stylesheet"][href]');
+    Array.prototype.forEach.call(links, function (link) {
+      var url = new URL(link.href, location.href);
+      if (url.origin !== location.origin) return;
+      if (paths.indexOf(decodeURIComponent(url.pathname)) === -1) return;
+
+      url.searchParams.set("syserve", Date.now());
+      var swapped = link.cloneNode();
+      swapped.href = url.href;
+      swapped.onload = swapped.onerror = function () {
+        link.remove();
+      };
+      link.parentNode.insertBefore(swapped, link.nextSibling);
+    });
+  });
+})();
+</script>
+"#;
+
+/// Connection of a browser to the event stream, after the response head was sent
+pub trait Client: Write + Send {
+    /// End the event stream, e.g. on shutdown
+    fn close(&mut self);
+}
+
+impl Client for Stream {
+    fn close(&mut self) {
+        let _ = self.tcp().shutdown(net::Shutdown::Both);
+    }
+}
+
+/// Client shared between `LiveReload` and the broadcasts writing to it
+struct SharedClient {
+    client: Mutex<Box<dyn Client>>,
+    /// Messages not written yet, as the client is being written to by another broadcast
+    pending: Mutex<Vec<u8>>,
+    /// Whether a write failed, after which the client is dropped
+    failed: AtomicBool,
+}
+
+impl SharedClient {
+    /// Queue `message` and write everything queued, unless another broadcast is
+    /// writing to the client, in which case it writes `message` once done
+    fn send(&self, message: &[u8]) {
+        self.pending.lock().unwrap().extend_from_slice(message);
+        loop {
+            let mut client = match self.client.try_lock() {
+                Ok(client) => client,
+                Err(_) => return,
+            };
+            loop {
+                let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+                if pending.is_empty() || self.failed.load(Ordering::SeqCst) {
+                    break;
+                }
+                if client
+                    .write_all(&pending)
+                    .and_then(|_| client.flush())
+                    .is_err()
+                {
+                    self.failed.store(true, Ordering::SeqCst);
+                }
+            }
+            drop(client);
+            // Messages queued while the lock was being released
+            if self.pending.lock().unwrap().is_empty() || self.failed.load(Ordering::SeqCst) {
+                return;
+            }
+        }
+    }
+}
+
+/// Browsers connected to the event stream
+#[derive(Default)]
+pub struct LiveReload {
+    /// Only locked to update the list, writes happen without holding it, so that
+    /// a stalled client does not block new clients or other broadcasts
+    clients: Mutex<Vec<Arc<SharedClient>>>,
+}
+
+impl LiveReload {
+    /// Answer a request for `EVENTS_PATH` over HTTP/1.1, keeping the stream open for
+    /// later events. `initial` is sent to the new client only, e.g. the error overlay
+    /// of a failed build.
+    pub fn attach(&self, mut stream: Stream, initial: Option<(&str, &str)>) -> io::Result<()> {
+        stream.tcp().set_read_timeout(None)?;
+        stream.tcp().set_write_timeout(Some(WRITE_TIMEOUT))?;
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Connection: keep-alive\r\n\r\n",
+        )?;
+        self.add(Box::new(stream), initial)
+    }
+
+    /// Start sending events to a client whose response head was sent, see `attach`
+    pub fn add(
+        &self,
+        mut client: Box<dyn Client>,
+        initial: Option<(&str, &str)>,
+    ) -> io::Result<()> {
+        client.write_all(b"retry: 1000\n\n")?;
+        if let Some((event, data)) = initial {
+            client.write_all(message(event, data).as_bytes())?;
+        }
+        client.flush()?;
+
+        self.clients.lock().unwrap().push(Arc::new(SharedClient {
+            client: Mutex::new(client),
+            pending: Mutex::new(Vec::new()),
+            failed: AtomicBool::new(false),
+        }));
+        Ok(())
+    }
+
+    /// Number of connected clients
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send an event to all clients, dropping the ones that cannot be written to
+    pub fn notify(&self, event: &str, data: &str) {
+        self.send(message(event, data).as_bytes());
+    }
+
+    /// Send a comment to all clients, detecting the ones that disconnected
+    pub fn ping(&self) {
+        self.send(b": ping\n\n");
+    }
+
+    /// Close all event streams, e.g. on shutdown
+    pub fn close_all(&self) {
+        let clients: Vec<Arc<SharedClient>> = self.clients.lock().unwrap().drain(..).collect();
+        for shared in clients {
+            shared.client.lock().unwrap().close();
+        }
+    }
+
+    /// Write `message` to a snapshot of the clients, then drop the ones that failed.
+    /// Clients still busy with an earlier message get it queued instead.
+    fn send(&self, message: &[u8]) {
+        let clients = self.clients.lock().unwrap().clone();
+        for client in &clients {
+            client.send(message);
+        }
+
+        if clients.iter().any(|c| c.failed.load(Ordering::SeqCst)) {
+            self.clients
+                .lock()
+                .unwrap()
+                .retain(|client| !client.failed.load(Ordering::SeqCst));
+        }
+    }
+}
+
+/// Event stream message for `event`, with one `data` field per line of `data`
+fn message(event: &str, data: &str) -> String {
+    let mut message = format!("event: {}\n", event);
+    for line in data.split('\n') {
+        message.push_str(&format!("data: {}\n", line));
+    }
+    message.push('\n');
+    message
+}
+
+/// JSON array of the URL paths of changed files under `root`, e.g. `["/css/app.css"]`,
+/// sent as the data of `change` events
+pub fn changes_json(root: &Path, paths: &[PathBuf]) -> String {
+    let urls: Vec<String> = paths
+        .iter()
+        .filter_map(|path| path.strip_prefix(root).ok())
+        .map(|relative| {
+            let segments: Vec<_> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            format!("/{}", segments.join("/"))
+        })
+        .collect();
+
+    serde_json::to_string(&urls).unwrap_or_default()
+}
+
+/// Insert the client script into an HTML page, before `</body>` or at its end
+pub fn inject(html: &[u8]) -> Vec<u8> {
+    let position = html
+        .windows(b"</body>".len())
+        .rposition(|w| w.eq_ignore_ascii_case(b"</body>"))
+        .unwrap_or(html.len());
+
+    let mut injected = Vec::with_capacity(html.len() + SCRIPT.len());
+    injected.extend_from_slice(&html[..position]);
+    injected.extend_from_slice(SCRIPT.as_bytes());
+    injected.extend_from_slice(&html[position..]);
+    injected
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+
+    use super::*;
+
+    /// Client recording what is written to it
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Client for Recorder {
+        fn close(&mut self) {}
+    }
+
+    /// Client whose writes block until released, then fail
+    struct Stalled {
+        started: Sender<()>,
+        release: Mutex<Receiver<()>>,
+        /// The first write, of the `retry` field, goes through
+        attached: bool,
+    }
+
+    impl Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.attached {
+                self.attached = true;
+                return Ok(buf.len());
+            }
+            let _ = self.started.send(());
+            let _ = self.release.lock().unwrap().recv();
+            Err(io::ErrorKind::TimedOut.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Client for Stalled {
+        fn close(&mut self) {}
+    }
+
+    #[test]
+    fn stalled_clients_do_not_block_others() {
+        let reload = Arc::new(LiveReload::default());
+        let (started, started_rx) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let stalled = Stalled {
+            started,
+            release: Mutex::new(release_rx),
+            attached: false,
+        };
+        reload.add(Box::new(stalled), None).unwrap();
+
+        let broadcast = {
+            let reload = Arc::clone(&reload);
+            thread::spawn(move || reload.notify("change", "[]"))
+        };
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The broadcast is stuck writing to the stalled client
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let (done, done_rx) = mpsc::channel();
+        {
+            let reload = Arc::clone(&reload);
+            let recorder = Recorder(Arc::clone(&written));
+            thread::spawn(move || {
+                reload.add(Box::new(recorder), None).unwrap();
+                reload.ping();
+                let _ = done.send(reload.len());
+            });
+        }
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)), Ok(2));
+        assert_eq!(&*written.lock().unwrap(), b"retry: 1000\n\n: ping\n\n");
+
+        release.send(()).unwrap();
+        broadcast.join().unwrap();
+        assert_eq!(reload.len(), 1);
+        reload.notify("build-ok", "");
+        assert!(written
+            .lock()
+            .unwrap()
+            .ends_with(b"event: build-ok\ndata: \n\n"));
+    }
+
+    #[test]
+    fn formats_messages() {
+        assert_eq!(
+            message("build-error", "line 1\nline 2"),
+            "event: build-error\ndata: line 1\ndata: line 2\n\n"
+        );
+    }
+
+    #[test]
+    fn lists_changes_as_url_paths() {
+        let root = Path::new("/srv/dist");
+        let paths = [
+            root.join("index.html"),
+            root.join("css/app \"dark\".css"),
+            PathBuf::from("/elsewhere/file.txt"),
+        ];
+        assert_eq!(
+            changes_json(root, &paths),
+            r#"["/index.html","/css/app \"dark\".css"]"#
+        );
+        assert_eq!(changes_json(root, &[]), "[]");
+    }
+
+    #[test]
+    fn injects_the_script_before_the_end_of_the_body() {
+        let cases: &[(&str, &str, &str)] = &[
+            ("<body>app</body></html>", "<body>app", "</body></html>"),
+            ("<BODY>app</BODY>", "<BODY>app", "</BODY>"),
+            // The last `</body>` counts, e.g. after one in a script
+            (
+                "<body><script>'</body>'</script></body>",
+                "<body><script>'</body>'</script>",
+                "</body>",
+            ),
+            ("<p>fragment</p>", "<p>fragment</p>", ""),
+            ("", "", ""),
+        ];
+        for (html, before, after) in cases {
+            let injected = String::from_utf8(inject(html.as_bytes())).unwrap();
+            assert_eq!(
+                injected,
+                format!("{}{}{}", before, SCRIPT, after),
+                "{}",
+                html
+            );
+        }
+    }
+}
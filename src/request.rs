@@ -0,0 +1,318 @@
+//! Incremental reading of HTTP/1.x requests from a stream
+
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+
+extern crate httparse;
+
+/// Limits applied while reading a request head
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Maximum size in bytes of the request line and headers
+    pub max_header_size: usize,
+    /// Maximum number of headers
+    pub max_headers: usize,
+    /// Maximum size in bytes of a request body, decoded if it was sent chunked
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_header_size: 16 * 1024,
+            max_headers: 64,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// An owned, fully read request
+pub struct Request {
+    /// Request method, e.g. `GET`
+    pub method: String,
+    /// Request target as sent by the client
+    pub path: String,
+    /// Minor HTTP version (`0` for HTTP/1.0, `1` for HTTP/1.1)
+    pub version: u8,
+    /// Header names and values in the order they were received
+    pub headers: Vec<(String, String)>,
+    /// Request body, decoded if it was sent chunked
+    pub body: Vec<u8>,
+    /// Subject of the verified client certificate with mutual TLS, e.g. `CN=alice`.
+    /// Set by the server, see `stream::Stream::client_subject`
+    pub client_subject: Option<String>,
+}
+
+impl Request {
+    /// Value of the first header named `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the comma separated header `name` contains `token` (case-insensitive)
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Whether the client wants the connection kept open after this request.
+    /// HTTP/1.1 defaults to persistent connections, HTTP/1.0 must opt in.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            1 => !self.has_token("connection", "close"),
+            _ => self.has_token("connection", "keep-alive"),
+        }
+    }
+}
+
+/// Reasons a request could not be read
+#[derive(Debug)]
+pub enum ReadError {
+    /// The client closed the connection between requests
+    Closed,
+    /// The connection stayed idle for longer than the read timeout
+    TimedOut,
+    /// The request head exceeded `Limits`
+    HeadersTooLarge,
+    /// The request head could not be parsed
+    Malformed(httparse::Error),
+    /// `Content-Length` or the chunked body encoding is invalid
+    BadBody,
+    /// The request body exceeded `Limits::max_body_size`
+    BodyTooLarge,
+    /// Any other I/O error, including the client hanging up mid-request
+    Io(io::Error),
+}
+
+/// Reads consecutive requests from a stream, keeping bytes of pipelined requests
+/// that arrive early in an internal buffer.
+pub struct RequestReader<S: Read> {
+    stream: S,
+    limits: Limits,
+    /// Bytes received but not yet consumed by a request
+    buf: Vec<u8>,
+}
+
+impl<S: Read> RequestReader<S> {
+    pub fn new(stream: S, limits: Limits) -> RequestReader<S> {
+        RequestReader {
+            stream,
+            limits,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Underlying stream, e.g. for writing responses
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Underlying stream, giving up the connection, e.g. to keep an event stream open.
+    /// Bytes of pipelined requests still buffered are discarded
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Wait for bytes of the next request, returning how many are buffered: right away
+    /// for pipelined requests, else after one read from the stream, which may time out.
+    /// `0` means the client closed the connection.
+    pub fn poll(&mut self) -> Result<usize, ReadError> {
+        if self.buf.is_empty() {
+            self.read_more()?;
+        }
+        Ok(self.buf.len())
+    }
+
+    /// Read the next request, including its body
+    pub fn next_request(&mut self) -> Result<Request, ReadError> {
+        let (mut req, consumed) = self.read_head()?;
+        self.buf.drain(..consumed);
+
+        if req.has_token("transfer-encoding", "chunked") {
+            req.body = self.read_chunked()?;
+        } else if let Some(len) = req.header("content-length") {
+            let len: usize = len.trim().parse().map_err(|_| ReadError::BadBody)?;
+            if len > self.limits.max_body_size {
+                return Err(ReadError::BodyTooLarge);
+            }
+            self.fill(len)?;
+            req.body = self.buf.drain(..len).collect();
+        }
+
+        Ok(req)
+    }
+
+    /// Read until a complete request head is buffered and parse it.
+    /// Returns the request and the number of bytes the head occupies.
+    fn read_head(&mut self) -> Result<(Request, usize), ReadError> {
+        loop {
+            let mut headers = vec![httparse::EMPTY_HEADER; self.limits.max_headers];
+            let mut req = httparse::Request::new(&mut headers);
+            match req.parse(&self.buf) {
+                Ok(httparse::Status::Complete(n)) if n <= self.limits.max_header_size => {
+                    let request = Request {
+                        method: req.method.unwrap_or_default().to_string(),
+                        path: req.path.unwrap_or_default().to_string(),
+                        version: req.version.unwrap_or_default(),
+                        headers: req
+                            .headers
+                            .iter()
+                            .map(|h| {
+                                (
+                                    h.name.to_string(),
+                                    String::from_utf8_lossy(h.value).into_owned(),
+                                )
+                            })
+                            .collect(),
+                        body: Vec::new(),
+                        client_subject: None,
+                    };
+                    return Ok((request, n));
+                }
+                Ok(httparse::Status::Complete(_)) => return Err(ReadError::HeadersTooLarge),
+                Ok(httparse::Status::Partial) => {
+                    if self.buf.len() >= self.limits.max_header_size {
+                        return Err(ReadError::HeadersTooLarge);
+                    }
+                }
+                Err(httparse::Error::TooManyHeaders) => return Err(ReadError::HeadersTooLarge),
+                Err(e) => return Err(ReadError::Malformed(e)),
+            }
+
+            if self.read_more()? == 0 {
+                return Err(if self.buf.is_empty() {
+                    ReadError::Closed
+                } else {
+                    ReadError::Io(ErrorKind::UnexpectedEof.into())
+                });
+            }
+        }
+    }
+
+    /// Decode a chunked body, consuming it and any trailers from the buffer
+    fn read_chunked(&mut self) -> Result<Vec<u8>, ReadError> {
+        let mut body = Vec::new();
+
+        loop {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| ReadError::BadBody)?;
+
+            if size == 0 {
+                // Trailers are read and discarded up to the final empty line
+                while !self.read_line()?.is_empty() {}
+                return Ok(body);
+            }
+            if size > self.limits.max_body_size - body.len() {
+                return Err(ReadError::BodyTooLarge);
+            }
+
+            self.fill(size.checked_add(2).ok_or(ReadError::BadBody)?)?;
+            if &self.buf[size..size + 2] != b"\r\n" {
+                return Err(ReadError::BadBody);
+            }
+            body.extend(self.buf.drain(..size));
+            self.buf.drain(..2);
+        }
+    }
+
+    /// Read and consume one CRLF terminated line, without the line ending
+    fn read_line(&mut self) -> Result<String, ReadError> {
+        loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..pos]).into_owned();
+                self.buf.drain(..pos + 2);
+                return Ok(line);
+            }
+            if self.buf.len() > self.limits.max_header_size {
+                return Err(ReadError::BadBody);
+            }
+            if self.read_more()? == 0 {
+                return Err(ReadError::Io(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    /// Read until at least `len` bytes are buffered
+    fn fill(&mut self, len: usize) -> Result<(), ReadError> {
+        while self.buf.len() < len {
+            if self.read_more()? == 0 {
+                return Err(ReadError::Io(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read more bytes from the stream into the buffer, returning how many were read
+    fn read_more(&mut self) -> Result<usize, ReadError> {
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                {
+                    return Err(ReadError::TimedOut)
+                }
+                Err(e) => return Err(ReadError::Io(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the first request of `input` with a 16 bytes body limit
+    fn read(input: &[u8]) -> Result<Request, ReadError> {
+        let limits = Limits {
+            max_body_size: 16,
+            ..Limits::default()
+        };
+        RequestReader::new(input, limits).next_request()
+    }
+
+    #[test]
+    fn bodies_within_the_limit_are_read() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc", b"abc"),
+            (b"POST / HTTP/1.1\r\nContent-Length: 16\r\n\r\n0123456789abcdef", b"0123456789abcdef"),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n01234567\r\n8\r\n89abcdef\r\n0\r\n\r\n",
+                b"0123456789abcdef",
+            ),
+        ];
+        for (input, body) in cases {
+            assert_eq!(read(input).unwrap().body, *body);
+        }
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_refused() {
+        let cases: &[&[u8]] = &[
+            b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 100000000000\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n11\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nFFFFFFFFFFFFFFFF\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n01234567\r\n9\r\n",
+        ];
+        for input in cases {
+            assert!(
+                matches!(read(input), Err(ReadError::BodyTooLarge)),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! HTTP responses, written in HTTP/1.1 format or read back for HTTP/2
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, ErrorKind, SeekFrom};
+use std::ops::Range;
+
+/// Size of the buffer used to stream file bodies
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Part of a body streamed from a file
+pub enum Segment {
+    /// Bytes held in memory, e.g. multipart headers
+    Bytes(Vec<u8>),
+    /// A byte range of the file
+    Range(Range<u64>),
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            Segment::Bytes(bytes) => bytes.len() as u64,
+            Segment::Range(range) => range.end - range.start,
+        }
+    }
+}
+
+/// Body of a response
+pub enum Body {
+    /// Body held in memory
+    Bytes(Vec<u8>),
+    /// Body streamed from an open file in bounded chunks
+    File(File, Vec<Segment>),
+    /// Body of unknown length produced while it is sent, e.g. compressed on the fly.
+    /// Sent with the chunked transfer coding in HTTP/1.1
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Body {
+    /// Length of the body in bytes, `None` for streamed bodies
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File(_, segments) => Some(segments.iter().map(Segment::len).sum()),
+            Body::Stream(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Reader of the body contents, e.g. to send them in frames
+    pub fn into_reader(self) -> BodyReader {
+        BodyReader {
+            body: self,
+            segment: 0,
+            offset: 0,
+        }
+    }
+}
+
+/// Reader of a body, see `Body::into_reader`
+pub struct BodyReader {
+    body: Body,
+    /// Current segment of a file body
+    segment: usize,
+    /// Bytes already read from the body (bytes) or current segment (file)
+    offset: u64,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.body {
+            Body::Bytes(bytes) => {
+                let mut rest = &bytes[self.offset as usize..];
+                let n = rest.read(buf)?;
+                self.offset += n as u64;
+                Ok(n)
+            }
+            Body::File(file, segments) => loop {
+                let segment = match segments.get(self.segment) {
+                    Some(segment) => segment,
+                    None => return Ok(0),
+                };
+                let remaining = segment.len() - self.offset;
+                if remaining == 0 {
+                    self.segment += 1;
+                    self.offset = 0;
+                    continue;
+                }
+
+                let want = remaining.min(buf.len() as u64) as usize;
+                let n = match segment {
+                    Segment::Bytes(bytes) => {
+                        let start = self.offset as usize;
+                        buf[..want].copy_from_slice(&bytes[start..start + want]);
+                        want
+                    }
+                    Segment::Range(range) => {
+                        file.seek(SeekFrom::Start(range.start + self.offset))?;
+                        match file.read(&mut buf[..want])? {
+                            // The file was truncated
+                            0 => return Err(ErrorKind::UnexpectedEof.into()),
+                            n => n,
+                        }
+                    }
+                };
+                self.offset += n as u64;
+                return Ok(n);
+            },
+            Body::Stream(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// A response with a status line, headers and a body
+pub struct Response {
+    /// Status code and reason, e.g. `200 OK`
+    pub status: &'static str,
+    /// Header names and values, written in order
+    pub headers: Vec<(String, String)>,
+    /// Response body
+    pub body: Body,
+}
+
+impl Response {
+    /// Empty-bodied response with the given status
+    pub fn new(status: &'static str) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// Add a header
+    pub fn header<V: ToString>(mut self, name: &str, value: V) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set the body and its `Content-type`
+    pub fn body(self, body: Body, content_type: &str) -> Response {
+        let mut response = self.header("Content-type", content_type);
+        response.body = body;
+        response
+    }
+
+    /// Serialize the status line and headers. `Content-Length` is always that of the
+    /// full body, even for responses to `HEAD` requests, and streamed bodies are sent
+    /// with `Transfer-Encoding: chunked` instead.
+    /// `304 Not Modified` responses carry no `Content-Length` as they describe the
+    /// representation the client already has.
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("HTTP/1.1 {}\r\n", self.status).into_bytes();
+        for (name, value) in &self.headers {
+            bytes.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if !self.status.starts_with("304") {
+            match self.body.len() {
+                Some(len) => {
+                    bytes.extend_from_slice(format!("Content-Length: {}\r\n", len).as_bytes())
+                }
+                None => bytes.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+            }
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes
+    }
+
+    /// Write the response to `w`, leaving out the body if `head_only` is set
+    /// (responses to `HEAD` requests). File and streamed bodies are sent in chunks of
+    /// `CHUNK_SIZE`.
+    pub fn write_to<W: Write>(&mut self, w: &mut W, head_only: bool) -> io::Result<()> {
+        w.write_all(&self.head_bytes())?;
+        if head_only {
+            return w.flush();
+        }
+
+        match &mut self.body {
+            Body::Bytes(bytes) => w.write_all(bytes)?,
+            Body::File(file, segments) => {
+                let mut buf = vec![0; CHUNK_SIZE];
+                for segment in segments.iter() {
+                    match segment {
+                        Segment::Bytes(bytes) => w.write_all(bytes)?,
+                        Segment::Range(range) => copy_range(file, range, w, &mut buf)?,
+                    }
+                }
+            }
+            Body::Stream(reader) => {
+                let mut buf = vec![0; CHUNK_SIZE];
+                loop {
+                    let n = match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    write!(w, "{:x}\r\n", n)?;
+                    w.write_all(&buf[..n])?;
+                    w.write_all(b"\r\n")?;
+                }
+                w.write_all(b"0\r\n\r\n")?;
+            }
+        }
+        w.flush()
+    }
+
+    /// Like `write_to`, but file ranges are sent with `sendfile(2)`
+    /// without copying them through userspace.
+    #[cfg(target_os = "linux")]
+    pub fn send_to(&mut self, stream: &mut std::net::TcpStream, head_only: bool) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let (file, segments) = match &self.body {
+            Body::File(file, segments) if !head_only => (file, segments),
+            _ => return self.write_to(stream, head_only),
+        };
+
+        stream.write_all(&self.head_bytes())?;
+        for segment in segments {
+            match segment {
+                Segment::Bytes(bytes) => stream.write_all(bytes)?,
+                Segment::Range(range) => {
+                    let mut offset = range.start as libc::off_t;
+                    let end = range.end as libc::off_t;
+                    while offset < end {
+                        let count = (end - offset).min(isize::MAX as libc::off_t) as usize;
+                        // Safety: both descriptors are open for the duration of the call and
+                        // `offset` is a valid pointer, updated by the kernel.
+                        let sent = unsafe {
+                            libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, count)
+                        };
+                        match sent {
+                            0 => return Err(ErrorKind::UnexpectedEof.into()),
+                            n if n > 0 => {}
+                            _ => {
+                                let e = io::Error::last_os_error();
+                                if e.kind() != ErrorKind::Interrupted {
+                                    return Err(e);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        stream.flush()
+    }
+}
+
+/// Copy a byte range of `file` to `w` through `buf`.
+/// Fails if the file ends before the range does, e.g. because it was truncated.
+fn copy_range<W: Write>(
+    file: &mut File,
+    range: &Range<u64>,
+    w: &mut W,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    file.seek(SeekFrom::Start(range.start))?;
+
+    let mut remaining = range.end - range.start;
+    while remaining > 0 {
+        let want = remaining.min(buf.len() as u64) as usize;
+        let n = match file.read(&mut buf[..want]) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        w.write_all(&buf[..n])?;
+        remaining -= n as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! Confining file access to the served directory
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// How symbolic links under the served directory are treated
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Follow all symbolic links, even to targets outside the served directory
+    Follow,
+    /// Follow symbolic links whose target is inside the served directory
+    #[default]
+    FollowWithinRoot,
+    /// Refuse any path that goes through a symbolic link
+    Deny,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "within-root" => Ok(SymlinkPolicy::FollowWithinRoot),
+            "deny" => Ok(SymlinkPolicy::Deny),
+            _ => Err(format!(
+                "invalid symlink policy {:?}, expected follow, within-root or deny",
+                s
+            )),
+        }
+    }
+}
+
+/// Reasons a path is refused
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    /// A segment is not a plain file name, e.g. `..`, `C:` or contains a separator
+    InvalidSegment(String),
+    /// The resolved path leaves the served directory
+    OutsideRoot,
+    /// The path goes through a symbolic link while `SymlinkPolicy::Deny` is set
+    Symlink(PathBuf),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::InvalidSegment(s) => write!(f, "invalid path segment {:?}", s),
+            Violation::OutsideRoot => write!(f, "path resolves outside the served directory"),
+            Violation::Symlink(path) => write!(f, "{:?} is a symbolic link", path),
+        }
+    }
+}
+
+/// The served directory, in canonical form
+pub struct Sandbox {
+    root: PathBuf,
+    policy: SymlinkPolicy,
+}
+
+impl Sandbox {
+    pub fn new(dir: &Path, policy: SymlinkPolicy) -> io::Result<Sandbox> {
+        Ok(Sandbox {
+            root: dir.canonicalize()?,
+            policy,
+        })
+    }
+
+    /// Canonical path of the served directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolve decoded URL path segments to a path under the served directory.
+    /// Paths that do not exist are returned as is, once the part of them that does exist
+    /// passed the checks.
+    pub fn resolve<S: AsRef<str>>(&self, segments: &[S]) -> Result<PathBuf, Violation> {
+        let mut path = self.root.clone();
+        for segment in segments {
+            let segment = segment.as_ref();
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) if name == segment => path.push(segment),
+                _ => return Err(Violation::InvalidSegment(segment.to_string())),
+            }
+        }
+
+        match self.policy {
+            SymlinkPolicy::Follow => {}
+            SymlinkPolicy::FollowWithinRoot => {
+                if !existing_ancestor(&path)?.starts_with(&self.root) {
+                    return Err(Violation::OutsideRoot);
+                }
+            }
+            SymlinkPolicy::Deny => {
+                let mut current = self.root.clone();
+                for segment in segments {
+                    current.push(segment.as_ref());
+                    match fs::symlink_metadata(&current) {
+                        Ok(metadata) if metadata.file_type().is_symlink() => {
+                            return Err(Violation::Symlink(current))
+                        }
+                        Ok(_) => {}
+                        Err(_) => break,
+                    }
+                }
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+/// Canonical form of the longest prefix of `path` that exists
+fn existing_ancestor(path: &Path) -> Result<PathBuf, Violation> {
+    path.ancestors()
+        .find_map(|p| p.canonicalize().ok())
+        .ok_or(Violation::OutsideRoot)
+}
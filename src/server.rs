@@ -1,42 +1,243 @@
 //! Serving files from a directory
 
-use std::fs;
-use std::io::prelude::*;
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 extern crate env_logger;
 extern crate httparse;
 extern crate threadpool;
 
-use httparse::Request;
 use log::{error, info, warn};
 use threadpool::ThreadPool;
 
+use crate::build::Builder;
+#[cfg(feature = "tls")]
+use crate::ca::{self, LocalCa};
 use crate::cli;
+use crate::compress::{self, AcceptEncoding, Encoding};
+use crate::conditional::{self, Precondition, Validators};
+use crate::error::{Error, Result};
+use crate::http2;
+#[cfg(feature = "http3")]
+use crate::http3;
+use crate::listing;
+use crate::range::{self, Ranges};
+use crate::reload::{self, LiveReload};
+use crate::request::{self, ReadError, Request, RequestReader};
+use crate::response::{Body, Response, Segment};
+use crate::sandbox::Sandbox;
+use crate::shutdown::{Connections, Shutdown};
+use crate::stream::Stream;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::url::{TrailingSlash, Url};
 use crate::utils;
+use crate::watch;
 
 /// Basic configuration for server. Derived from `cli::Args`
 pub struct Config {
-    /// Path of directory to be served.
-    dir: PathBuf,
-    /// Index file name in `dir`.
+    /// Directory to be served, with the policy for symbolic links inside it.
+    sandbox: Sandbox,
+    /// Index file name in the served directory.
     index: String,
-    /// 404 file name in `dir`. If `None`, the 404 error message is returned
+    /// Index file names looked up in requested directories, in order
+    dir_indexes: Vec<String>,
+    /// Handling of trailing slashes on directories and files
+    trailing_slash: TrailingSlash,
+    /// List directories without an index file
+    listing: bool,
+    /// Include hidden files (starting with `.`) in listings
+    show_hidden: bool,
+    /// 404 page in the served directory, served with a 404 status.
+    /// If `None`, an empty 404 response is returned
     not_found: Option<String>,
+    /// Serve `index` for navigations to missing paths (client-side routes)
+    spa: bool,
+    /// Globs of paths always treated as client-side routes when `spa` is set
+    spa_routes: Vec<String>,
     /// Address for serving
     address: SocketAddr,
+    /// Idle time after which a persistent connection is closed. `None` disables keep-alive
+    keep_alive: Option<Duration>,
+    /// Limits on the size of request heads
+    limits: request::Limits,
+    /// Send file bodies with `sendfile(2)` (Linux only)
+    sendfile: bool,
+    /// Minimum size in bytes of files compressed on the fly. `None` disables compression
+    compress_min_size: Option<u64>,
+    /// Time given to requests in progress to finish on shutdown
+    grace_period: Duration,
+    /// Watch the served directory and inject the live reload script into HTML pages
+    live_reload: bool,
+    /// Command run when `watch` changes
+    build: Option<String>,
+    /// Sources watched for `build`, in canonical form
+    watch: Vec<PathBuf>,
+    /// TLS configuration. Connections are plain TCP if `None`
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    /// Certificate of the local CA in PEM and DER, offered for download at `CA_PEM_PATH`
+    /// and `CA_DER_PATH` when certificates are issued by the CA
+    ca_certificate: Option<(String, Vec<u8>)>,
+    /// Answer requests without a verified client certificate with 403 Forbidden,
+    /// see `cli::ClientAuth::RequireHttp`
+    require_client_cert: bool,
+    /// Address of the plain HTTP listener redirecting to HTTPS, and the status line of
+    /// its redirects
+    redirect: Option<(SocketAddr, &'static str)>,
+    /// Value of the `Strict-Transport-Security` header sent over HTTPS
+    hsts: Option<String>,
+    /// Accept HTTP/2 with prior knowledge on plain TCP connections
+    h2c: bool,
+    /// Value of the `Alt-Svc` header advertising the HTTP/3 listener, which is only
+    /// bound if set
+    alt_svc: Option<String>,
 }
 
 impl Config {
-    pub fn new(args: cli::Args) -> Config {
-        Config {
-            dir: args.dir,
+    /// Fails if the directory to be served cannot be canonicalized or TLS cannot be set up
+    pub fn new(args: cli::Args) -> Result<Config> {
+        #[cfg(feature = "tls")]
+        let (tls, ca_certificate) = if args.tls || args.ca {
+            let mut names = args.sans.clone();
+            if !args.address.ip().is_unspecified() && !args.address.ip().is_loopback() {
+                names.push(args.address.ip().to_string());
+            }
+            let ca = if args.ca {
+                let dir = match &args.ca_dir {
+                    Some(dir) => dir.clone(),
+                    None => ca::default_dir()?,
+                };
+                Some(LocalCa::open_or_create(&dir)?.0)
+            } else {
+                None
+            };
+            let ca_certificate = ca
+                .as_ref()
+                .map(|ca| (ca.cert_pem().to_string(), ca.cert_der().to_vec()));
+
+            let tls = tls::server_config(tls::Options {
+                cert_key: args.cert.clone().zip(args.key.clone()),
+                names,
+                cert_dir: args.cert_dir.clone(),
+                ca,
+                client_auth: args
+                    .client_ca
+                    .clone()
+                    .map(|bundle| (bundle, args.client_auth)),
+                http2: args.http2,
+            })?;
+            (Some(tls), ca_certificate)
+        } else {
+            (None, None)
+        };
+        #[cfg(not(feature = "tls"))]
+        if args.tls || args.ca {
+            return Err(Error::Config(String::from(
+                "HTTPS requires syserve to be built with the `tls` feature",
+            )));
+        }
+        #[cfg(not(feature = "tls"))]
+        let ca_certificate = None;
+        #[cfg(not(feature = "http3"))]
+        if args.http3 {
+            return Err(Error::Config(String::from(
+                "HTTP/3 requires syserve to be built with the `http3` feature",
+            )));
+        }
+        let alt_svc = if args.http3 {
+            Some(format!("h3=\":{}\"; ma=86400", args.address.port()))
+        } else {
+            None
+        };
+
+        let sandbox =
+            Sandbox::new(&args.dir, args.symlinks).map_err(|e| Error::Fs(args.dir.clone(), e))?;
+
+        let mut watch = args.watch;
+        if watch.is_empty() {
+            watch = vec![PathBuf::from("src"), PathBuf::from("Cargo.toml")];
+            watch.retain(|path| path.exists());
+        }
+        let watch = watch
+            .into_iter()
+            .map(|path| path.canonicalize().map_err(|e| Error::Fs(path, e)))
+            .collect::<Result<_>>()?;
+
+        let mut dir_indexes = args.dir_indexes;
+        if dir_indexes.is_empty() {
+            dir_indexes = vec![args.index.clone(), String::from("index.htm")];
+        }
+
+        let redirect_status = match args.redirect_status {
+            301 => "301 MOVED PERMANENTLY",
+            _ => "308 PERMANENT REDIRECT",
+        };
+        let ip = args.address.ip();
+        let redirect = args
+            .redirect_port
+            .map(|port| (SocketAddr::new(ip, port), redirect_status));
+
+        Ok(Config {
+            sandbox,
+            dir_indexes,
+            trailing_slash: args.trailing_slash,
+            listing: args.listing,
+            show_hidden: args.show_hidden,
             index: args.index,
             not_found: args.not_found,
+            spa: args.spa,
+            spa_routes: args.spa_routes,
             address: args.address,
+            keep_alive: match args.keep_alive {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            limits: request::Limits {
+                max_header_size: args.max_header_size,
+                max_headers: args.max_headers,
+                max_body_size: args.max_body_size,
+            },
+            sendfile: args.sendfile,
+            compress_min_size: if args.compress {
+                Some(args.compress_min_size)
+            } else {
+                None
+            },
+            grace_period: Duration::from_secs(args.grace_period),
+            live_reload: args.live_reload,
+            build: args.build,
+            watch,
+            #[cfg(feature = "tls")]
+            tls,
+            ca_certificate,
+            require_client_cert: args.client_ca.is_some()
+                && args.client_auth == cli::ClientAuth::RequireHttp,
+            redirect,
+            hsts: args.hsts.map(|max_age| format!("max-age={}", max_age)),
+            h2c: args.h2c,
+            alt_svc,
+        })
+    }
+}
+
+impl Config {
+    /// URL scheme of the listener, `https` if TLS is set up
+    pub fn scheme(&self) -> &'static str {
+        #[cfg(feature = "tls")]
+        {
+            if self.tls.is_some() {
+                return "https";
+            }
         }
+        "http"
     }
 }
 
@@ -45,105 +246,1288 @@ pub enum RequestState {
     NotProcessed,
     ParseError,
     BadRequest,
+    HeadersTooLarge,
+    BodyTooLarge,
+    MethodNotAllowed,
+    Options,
+    Redirect(String),
+    /// Download of the local CA certificate, in DER or PEM
+    CaCertificate {
+        der: bool,
+    },
+    Forbidden,
+    Listing(Url),
     FileNotFound,
+    NotFoundPage(PathBuf),
     FileFound(PathBuf),
 }
 
-/// Main serve function for the binary from `cli::Args`
-pub fn serve(args: cli::Args) {
-    let config = Arc::new(Config::new(args));
+/// State shared by the listener and all connection handlers
+pub struct Server {
+    config: Config,
+    shutdown: Shutdown,
+    connections: Connections,
+    /// Browsers waiting for live reload events
+    reload: LiveReload,
+    /// Runs `Config::build`, if set
+    builder: Option<Builder>,
+    /// Number of connections accepted
+    accepted: AtomicUsize,
+    /// Number of requests answered
+    requests: AtomicUsize,
+    /// Number of accepted connections waiting for a worker
+    queued: AtomicUsize,
+}
+
+impl Server {
+    pub fn new(config: Config, shutdown: Shutdown) -> Server {
+        let builder = config.build.clone().map(|command| {
+            // Build output is picked up by the live reload watcher instead
+            Builder::new(command, vec![config.sandbox.root().to_path_buf()])
+        });
+
+        Server {
+            builder,
+            config,
+            shutdown,
+            connections: Connections::default(),
+            reload: LiveReload::default(),
+            accepted: AtomicUsize::new(0),
+            requests: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Error response to a request that could not be read, counted as answered like
+    /// the responses of `http2::Handler::respond`
+    fn refuse(&self, state: RequestState) -> Response {
+        let response = https_headers(build_response(state, None, &self.config), &self.config);
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        response
+    }
+}
+
+impl http2::Handler for Server {
+    fn respond(&self, req: &Request) -> Response {
+        // Requests wait for a build in progress rather than getting stale files, for a
+        // bounded time as they hold a worker
+        if let Some(builder) = &self.builder {
+            if !builder.wait(BUILD_WAIT) {
+                warn!(
+                    "Build still running after {:?}, serving files as they are",
+                    BUILD_WAIT
+                );
+            }
+        }
+        let response = https_headers(respond(req, &self.config), &self.config);
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        response
+    }
 
-    let listener = TcpListener::bind(&config.address).unwrap();
+    fn is_event_stream(&self, req: &Request) -> bool {
+        self.config.live_reload && is_event_stream(req) && is_authorized(req, &self.config)
+    }
+
+    fn attach(&self, events: http2::EventStream) {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let failure = self.builder.as_ref().and_then(Builder::failure);
+        let initial = failure.as_deref().map(|overlay| ("build-error", overlay));
+        if let Err(e) = self.reload.add(Box::new(events), initial) {
+            warn!("Could not open event stream: {}", e);
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// Statistics reported once the server stopped
+pub struct Summary {
+    /// Number of connections accepted
+    pub connections: usize,
+    /// Number of requests answered
+    pub requests: usize,
+    /// Number of connections still open when the grace period ended
+    pub aborted: usize,
+}
+
+impl Summary {
+    /// Process exit code: `0` if all connections were drained, `1` otherwise
+    pub fn exit_code(&self) -> i32 {
+        if self.aborted == 0 {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+/// Interval at which the listener checks for shutdown while no connections arrive
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Interval at which idle persistent connections check for connections waiting for
+/// a worker, see `wait_for_request`
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Main serve function for the binary from `cli::Args`.
+/// Serves until `SIGINT` or `SIGTERM` is received.
+pub fn serve(args: cli::Args) -> Result<Summary> {
+    let shutdown = Shutdown::new();
+    if let Err(e) = shutdown.register_signals() {
+        warn!("Error registering signal handlers: {}", e);
+    }
+
+    serve_until(args, shutdown)
+}
+
+/// Serve until `shutdown` is triggered.
+///
+/// On shutdown, no more connections are accepted, connections waiting for a request
+/// are closed and requests being answered get `Config::grace_period` to finish
+/// before their connections are closed as well.
+pub fn serve_until(args: cli::Args, shutdown: Shutdown) -> Result<Summary> {
+    let server = Arc::new(Server::new(Config::new(args)?, shutdown));
+    let config = &server.config;
+
+    let listener = TcpListener::bind(config.address).map_err(|e| Error::Bind(config.address, e))?;
+    listener.set_nonblocking(true)?;
+    let redirect_listener = match config.redirect {
+        Some((address, _)) => {
+            let listener = TcpListener::bind(address).map_err(|e| Error::Bind(address, e))?;
+            listener.set_nonblocking(true)?;
+            Some(listener)
+        }
+        None => None,
+    };
+    #[cfg(feature = "http3")]
+    let http3 = match (&config.tls, &config.alt_svc) {
+        (Some(tls), Some(_)) => {
+            let options = http3::Options {
+                limits: config.limits,
+                idle_timeout: config.keep_alive.unwrap_or(DEFAULT_READ_TIMEOUT),
+            };
+            let handler = Arc::clone(&server);
+            Some(http3::Listener::bind(
+                config.address,
+                tls,
+                handler,
+                options,
+            )?)
+        }
+        _ => None,
+    };
     let pool = ThreadPool::new(10);
 
     info!(
-        "Serving files at {} with {} workers",
+        "Serving files at {}://{} with {} workers",
+        config.scheme(),
         config.address,
         pool.max_count()
     );
+    if let Some((address, _)) = config.redirect {
+        info!("Redirecting http://{} to HTTPS", address);
+    }
+    #[cfg(feature = "http3")]
+    if http3.is_some() {
+        info!("Serving HTTP/3 at udp://{} (experimental)", config.address);
+    }
+    if config.ca_certificate.is_some() {
+        info!(
+            "Local CA certificate available at {scheme}://{addr}{} and {scheme}://{addr}{}",
+            CA_PEM_PATH,
+            CA_DER_PATH,
+            scheme = config.scheme(),
+            addr = config.address
+        );
+    }
+
+    if config.build.is_some() {
+        if config.watch.is_empty() {
+            warn!("No sources to watch for the build command, building once");
+        } else {
+            info!("Watching {:?} for the build command", config.watch);
+        }
+        let changes = watch::watch(config.watch.clone(), watch::DEBOUNCE);
+        let cloned_server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Some(builder) = &cloned_server.builder {
+                builder.run_on_changes(changes, |failure| match failure {
+                    Some(overlay) => cloned_server.reload.notify("build-error", overlay),
+                    None => cloned_server.reload.notify("build-ok", ""),
+                });
+            }
+        });
+    }
+
+    if config.live_reload {
+        let root = config.sandbox.root().to_path_buf();
+        info!("Live reload enabled, watching {:?}", root);
+        let changes = watch::watch(vec![root], watch::DEBOUNCE);
+        let cloned_server = Arc::clone(&server);
+        thread::spawn(move || notify_changes(changes, cloned_server));
+    }
+
+    while !server.shutdown.is_triggered() {
+        let mut idle = accept_next(&listener, &server, &pool, handle_connection);
+        if let Some(redirect_listener) = &redirect_listener {
+            idle &= accept_next(redirect_listener, &server, &pool, handle_redirect);
+        }
+        if idle {
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+    }
+    drop(listener);
+    drop(redirect_listener);
+    #[allow(unused_mut)]
+    let mut draining = server.connections.len();
+    #[cfg(feature = "http3")]
+    if let Some(http3) = &http3 {
+        http3.close();
+        draining += http3.open();
+    }
+    server.reload.close_all();
+
+    info!("Shutting down, draining {} connections", draining);
+    server.connections.shutdown_all(net::Shutdown::Read);
+
+    let deadline = Instant::now() + config.grace_period;
+    while pool.active_count() + pool.queued_count() > 0 && Instant::now() < deadline {
+        thread::sleep(ACCEPT_POLL_INTERVAL);
+    }
+
+    #[cfg(feature = "http3")]
+    let (http3_connections, http3_aborted) = match http3 {
+        Some(http3) => (http3.accepted(), http3.drain(deadline)),
+        None => (0, 0),
+    };
+    #[cfg(not(feature = "http3"))]
+    let (http3_connections, http3_aborted) = (0, 0);
+    if http3_aborted > 0 {
+        warn!(
+            "Grace period ended, closed {} HTTP/3 connections with requests in progress",
+            http3_aborted
+        );
+    }
+
+    let aborted = server.connections.len();
+    if aborted == 0 {
+        pool.join();
+    } else {
+        warn!(
+            "Grace period ended, closing {} connections with requests in progress",
+            aborted
+        );
+        server.connections.shutdown_all(net::Shutdown::Both);
+    }
+
+    let summary = Summary {
+        connections: server.accepted.load(Ordering::SeqCst) + http3_connections,
+        requests: server.requests.load(Ordering::SeqCst),
+        aborted: aborted + http3_aborted,
+    };
+    info!(
+        "Served {} requests over {} connections, {} aborted",
+        summary.requests, summary.connections, summary.aborted
+    );
+
+    Ok(summary)
+}
+
+/// Interval of comments sent on idle event streams, detecting departed clients
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Interval at which changes held back during a build are checked for release
+const BUILD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Accept a pending connection on `listener`, if any, and have `handle` answer it
+/// on the pool. Returns whether no connection was pending.
+fn accept_next<F>(
+    listener: &TcpListener,
+    server: &Arc<Server>,
+    pool: &ThreadPool,
+    handle: F,
+) -> bool
+where
+    F: FnOnce(TcpStream, Arc<Server>) -> Result<()> + Send + 'static,
+{
+    match listener.accept() {
+        Ok((stream, _)) => {
+            // Accepted sockets may inherit non-blocking mode from the listener
+            if let Err(e) = stream.set_nonblocking(false) {
+                error!("Error configuring connection: {}", e);
+                return false;
+            }
+            server.accepted.fetch_add(1, Ordering::SeqCst);
+
+            let cloned_server = Arc::clone(server);
+            server.queued.fetch_add(1, Ordering::SeqCst);
+            pool.execute(move || {
+                cloned_server.queued.fetch_sub(1, Ordering::SeqCst);
+                if let Err(e) = handle(stream, cloned_server) {
+                    warn!("Connection closed: {}", e);
+                }
+            });
+            false
+        }
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => true,
+        Err(e) => {
+            error!("Error accepting connection: {}", e);
+            false
+        }
+    }
+}
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let cloned_config = Arc::clone(&config);
+/// Tell live reload clients about batches of changes until the server shuts down.
+/// With a build command, changes are held back until the build finishes, and dropped
+/// if it fails.
+fn notify_changes(changes: Receiver<Vec<PathBuf>>, server: Arc<Server>) {
+    let mut pending: Vec<PathBuf> = Vec::new();
 
-        pool.execute(move || handle_connection(stream, cloned_config));
+    while !server.shutdown.is_triggered() {
+        let timeout = if pending.is_empty() {
+            PING_INTERVAL
+        } else {
+            BUILD_POLL_INTERVAL
+        };
+        match changes.recv_timeout(timeout) {
+            Ok(paths) => pending.extend(paths),
+            Err(RecvTimeoutError::Timeout) if pending.is_empty() => server.reload.ping(),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if pending.is_empty() {
+            continue;
+        }
+        match &server.builder {
+            Some(builder) if builder.is_building() => continue,
+            Some(builder) if !builder.last_succeeded() => {
+                warn!("Not reloading, the last build failed");
+                pending.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        pending.sort();
+        pending.dedup();
+        info!(
+            "{} files changed, notifying {} clients",
+            pending.len(),
+            server.reload.len()
+        );
+        let changes = reload::changes_json(server.config.sandbox.root(), &pending);
+        server.reload.notify("change", &changes);
+        pending.clear();
     }
+}
 
-    pool.join();
+/// Whether the client speaks HTTP/2 on this connection: agreed on with ALPN over TLS,
+/// or announced with the connection preface over plain TCP if `Config::h2c` is set
+fn is_http2(stream: &mut Stream, config: &Config) -> io::Result<bool> {
+    if stream.is_tls() {
+        stream.handshake()?;
+        return Ok(stream.alpn_protocol() == Some(b"h2"));
+    }
+    if config.h2c {
+        return http2::has_preface(stream.tcp());
+    }
+    Ok(false)
 }
 
-/// Handle connection/request. Spawned as part of a thread.
-/// Returns 4 possible responses:
-/// - Internal Server Error (500) if request parsing fails
-/// - Bad Request (400) if the request has no path
-/// - Not Found (404) if the requested path is not found and if `Config::not_found` is `None`
-/// or if the `Config::not_found` file does not exist in `Config::dir`
-/// - Ok (200) if the requested path is found or if the `Config::not_found` file exists in `Config::dir`
+/// Handle connection. Spawned as part of a thread.
+///
+/// Requests are read and answered in order until the client asks for the connection
+/// to be closed (`Connection: close` or HTTP/1.0 without `Connection: keep-alive`),
+/// the connection stays idle for longer than `Config::keep_alive` or while other
+/// connections wait for a worker (see `wait_for_request`), the client hangs up or the
+/// server shuts down.
+/// Pipelined requests already in the buffer are answered without waiting for more data.
+/// Requests for the live reload event stream hand the connection over to `Server::reload`.
+///
+/// Returns an error if the connection failed or a request could not be parsed,
+/// in which case a 400 response is sent before closing it.
+pub fn handle_connection(stream: TcpStream, server: Arc<Server>) -> Result<()> {
+    let config = &server.config;
 
-pub fn handle_connection(mut stream: TcpStream, config: Arc<Config>) {
-    // Store state/stage of request processing
-    let mut state = RequestState::NotProcessed;
+    let timeout = config.keep_alive.unwrap_or(DEFAULT_READ_TIMEOUT);
+    stream.set_read_timeout(Some(timeout))?;
 
-    // Reading stream
-    let mut buf = [0; 1024];
-    stream.read(&mut buf).unwrap();
+    let _registration = server.connections.register(&stream)?;
+    // Shutdown may have closed open connections before this one was registered
+    if server.shutdown.is_triggered() {
+        return Ok(());
+    }
 
-    // Parse request using httparse
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut req = Request::new(&mut headers);
-    if let Err(e) = req.parse(&buf) {
-        error!("Error parsing request: {}", e);
-        state = RequestState::ParseError;
+    let mut stream = accept(stream, config)?;
+    if is_http2(&mut stream, config)? {
+        let options = http2::Options {
+            limits: config.limits,
+            idle_timeout: timeout,
+            shutdown: server.shutdown.clone(),
+            client_subject: stream.client_subject(),
+        };
+        return Ok(http2::serve(stream, &*server, options)?);
     }
+    let mut reader = RequestReader::new(stream, config.limits);
+
+    loop {
+        if !wait_for_request(&mut reader, &server, timeout)? {
+            return Ok(());
+        }
+        let next = reader.next_request().map(|mut req| {
+            req.client_subject = reader.get_mut().client_subject();
+            req
+        });
+        let (response, keep_alive, head_only) = match next {
+            Ok(req) if http2::Handler::is_event_stream(&*server, &req) => {
+                server.requests.fetch_add(1, Ordering::SeqCst);
+                let failure = server.builder.as_ref().and_then(Builder::failure);
+                let initial = failure.as_deref().map(|overlay| ("build-error", overlay));
+                return Ok(server.reload.attach(reader.into_inner(), initial)?);
+            }
+            Ok(req) => (
+                http2::Handler::respond(&*server, &req),
+                config.keep_alive.is_some() && req.keep_alive() && !server.shutdown.is_triggered(),
+                req.method == "HEAD",
+            ),
+            Err(ReadError::Closed) | Err(ReadError::TimedOut) => return Ok(()),
+            Err(ReadError::HeadersTooLarge) => {
+                warn!("Request header fields too large");
+                (server.refuse(RequestState::HeadersTooLarge), false, false)
+            }
+            Err(ReadError::BodyTooLarge) => {
+                warn!("Request body too large");
+                (server.refuse(RequestState::BodyTooLarge), false, false)
+            }
+            Err(ReadError::Malformed(e)) => {
+                let mut response = server
+                    .refuse(RequestState::ParseError)
+                    .header("Connection", "close");
+                send_response(reader.get_mut(), &mut response, false, config)?;
+                return Err(Error::Parse(e));
+            }
+            Err(ReadError::BadBody) => {
+                warn!("Invalid request body");
+                (server.refuse(RequestState::BadRequest), false, false)
+            }
+            Err(ReadError::Io(e)) => return Err(Error::Io(e)),
+        };
 
-    // Incomplete Request
-    if let None = req.path {
-        warn!("Bad Request");
-        state = RequestState::BadRequest;
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        let mut response = response.header("Connection", connection);
+        send_response(reader.get_mut(), &mut response, head_only, config)?;
+
+        if !keep_alive {
+            return Ok(());
+        }
     }
+}
+
+/// Wait up to `timeout` for the next request on a connection, polling every
+/// `IDLE_POLL_INTERVAL`. Returns `false` if the connection should be closed: the client
+/// hung up or stayed idle, or other connections are waiting for a worker, which idle
+/// persistent connections would otherwise hold for the whole keep-alive timeout.
+fn wait_for_request(
+    reader: &mut RequestReader<Stream>,
+    server: &Server,
+    timeout: Duration,
+) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    reader
+        .get_mut()
+        .tcp()
+        .set_read_timeout(Some(IDLE_POLL_INTERVAL))?;
 
-    // Preprocess path and obtain file path
-    let url_path = req.path.unwrap().replace("../", "").replace("%20", " ");
-    let file_path: PathBuf = match url_path.as_str() {
-        "/" => config.dir.join(&config.index),
-        s => config.dir.join(&s[1..]),
+    let ready = loop {
+        match reader.poll() {
+            Ok(0) => break false,
+            Ok(_) => break true,
+            Err(ReadError::TimedOut) => {}
+            Err(ReadError::Io(e)) => return Err(Error::Io(e)),
+            Err(_) => break false,
+        }
+        if Instant::now() >= deadline {
+            break false;
+        }
+        if server.queued.load(Ordering::SeqCst) > 0 {
+            info!("Closing idle connection, other connections are waiting for a worker");
+            break false;
+        }
     };
 
-    info!("Requesting {:?}", file_path);
+    reader.get_mut().tcp().set_read_timeout(Some(timeout))?;
+    Ok(ready)
+}
 
-    // Check if path exists
-    if file_path.exists() {
-        state = RequestState::FileFound(file_path);
-    } else if !config.not_found.is_none() {
-        state = RequestState::FileFound(config.dir.join(config.not_found.as_ref().unwrap()));
-    } else {
-        warn!("File not found");
-        state = RequestState::FileNotFound;
+/// Handle a connection to the redirect listener, see `Config::redirect`.
+/// Its first request is answered with a redirect to the same path and query over HTTPS,
+/// then the connection is closed.
+pub fn handle_redirect(stream: TcpStream, server: Arc<Server>) -> Result<()> {
+    let config = &server.config;
+    stream.set_read_timeout(Some(DEFAULT_READ_TIMEOUT))?;
+
+    let _registration = server.connections.register(&stream)?;
+    if server.shutdown.is_triggered() {
+        return Ok(());
+    }
+
+    let mut reader = RequestReader::new(Stream::Plain(stream), config.limits);
+    let (response, head_only) = match reader.next_request() {
+        Ok(req) => (redirect_response(&req, config), req.method == "HEAD"),
+        Err(ReadError::Closed) | Err(ReadError::TimedOut) => return Ok(()),
+        Err(ReadError::Io(e)) => return Err(Error::Io(e)),
+        Err(_) => (
+            build_response(RequestState::BadRequest, None, config),
+            false,
+        ),
+    };
+
+    let mut response = response.header("Connection", "close");
+    send_response(reader.get_mut(), &mut response, head_only, config)?;
+    server.requests.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Redirect to the HTTPS listener with the path and query of `req`, keeping the host
+/// name the client used
+fn redirect_response(req: &Request, config: &Config) -> Response {
+    let url = match Url::parse(&req.path) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Bad Request: {}", e);
+            return build_response(RequestState::BadRequest, None, config);
+        }
+    };
+
+    let host = req
+        .header("Host")
+        .map(host_name)
+        .filter(|host| {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._:[]".contains(c))
+        })
+        .map(String::from)
+        .unwrap_or_else(|| match config.address.ip() {
+            ip if ip.is_unspecified() || ip.is_loopback() => String::from("localhost"),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+            ip => ip.to_string(),
+        });
+    let port = match config.address.port() {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+    let location = format!("https://{}{}{}", host, port, url.href(url.trailing_slash));
+    info!("Redirecting {:?} to {}", req.path, location);
+
+    let status = config
+        .redirect
+        .map_or("308 PERMANENT REDIRECT", |(_, status)| status);
+    Response::new(status).header("Location", location)
+}
+
+/// Host name of a `Host` header, without the port
+fn host_name(host: &str) -> &str {
+    match host.find(']') {
+        Some(end) if host.starts_with('[') => &host[..=end],
+        _ => host.split(':').next().unwrap_or(host),
+    }
+}
+
+/// Wrap an accepted connection in TLS if `Config::tls` is set.
+/// The handshake happens when the first request is read.
+fn accept(stream: TcpStream, config: &Config) -> Result<Stream> {
+    #[cfg(feature = "tls")]
+    {
+        if let Some(tls) = &config.tls {
+            let connection = rustls::ServerConnection::new(Arc::clone(tls))
+                .map_err(|e| Error::Tls(e.to_string()))?;
+            return Ok(Stream::Tls(Box::new(rustls::StreamOwned::new(
+                connection, stream,
+            ))));
+        }
+    }
+    #[cfg(not(feature = "tls"))]
+    let _ = config;
+
+    Ok(Stream::Plain(stream))
+}
+
+/// Add the headers of responses sent over HTTPS, if enabled: `Strict-Transport-Security`
+/// and the `Alt-Svc` advertising HTTP/3
+fn https_headers(mut response: Response, config: &Config) -> Response {
+    if let Some(hsts) = &config.hsts {
+        response = response.header("Strict-Transport-Security", hsts);
+    }
+    if let Some(alt_svc) = &config.alt_svc {
+        response = response.header("Alt-Svc", alt_svc);
+    }
+    response
+}
+
+/// Write a response, using `sendfile(2)` for file bodies of plain TCP connections
+/// if `Config::sendfile` is set
+fn send_response(
+    stream: &mut Stream,
+    response: &mut Response,
+    head_only: bool,
+    config: &Config,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if let (Stream::Plain(tcp), true) = (&mut *stream, config.sendfile) {
+            return response.send_to(tcp, head_only);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = config;
+
+    response.write_to(stream, head_only)?;
+    stream.flush()
+}
+
+/// Longest time a request waits for a build in progress, see `Config::build`
+const BUILD_WAIT: Duration = Duration::from_secs(5);
+
+/// Read timeout used for the first request when keep-alive is disabled
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Methods answered by the server, as listed in `Allow` headers
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Path of the local CA certificate in PEM, see `Config::ca_certificate`
+const CA_PEM_PATH: &str = "/__syserve/ca.pem";
+
+/// Path of the local CA certificate in DER, the format expected by mobile devices
+const CA_DER_PATH: &str = "/__syserve/ca.crt";
+
+/// Whether a request is for the live reload event stream
+fn is_event_stream(req: &Request) -> bool {
+    req.method == "GET" && req.path.split('?').next() == Some(reload::EVENTS_PATH)
+}
+
+/// Whether a request may be answered, i.e. comes with a verified client certificate
+/// if `Config::require_client_cert` is set
+fn is_authorized(req: &Request, config: &Config) -> bool {
+    !config.require_client_cert || req.client_subject.is_some()
+}
+
+/// Answer a successfully read request
+fn respond(req: &Request, config: &Config) -> Response {
+    build_response(dispatch(req, config), Some(req), config)
+}
+
+/// Dispatch a request on its method.
+/// `GET` and `HEAD` are resolved to a file, `OPTIONS` lists the allowed methods
+/// and any other method is not allowed.
+/// Requests without a required client certificate are forbidden.
+fn dispatch(req: &Request, config: &Config) -> RequestState {
+    if !is_authorized(req, config) {
+        warn!("Refusing {:?}: no client certificate", req.path);
+        return RequestState::Forbidden;
+    }
+
+    match req.method.as_str() {
+        "GET" | "HEAD" => resolve_request(req, config),
+        "OPTIONS" => RequestState::Options,
+        method => {
+            warn!("Method not allowed: {}", method);
+            RequestState::MethodNotAllowed
+        }
     }
+}
 
-    let response = match state {
-        RequestState::ParseError | RequestState::NotProcessed => {
-            b"HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\n".to_vec()
+/// Map a parsed request to the file to be served.
+/// Returns 7 possible states:
+/// - `CaCertificate` for `CA_PEM_PATH` and `CA_DER_PATH` if `Config::ca_certificate` is set
+/// - `BadRequest` if the request target is not a valid URL, see `url::Url::parse`
+/// - `Forbidden` if the path escapes the served directory or violates the symlink policy,
+///   see `sandbox::Sandbox::resolve`
+/// - `Redirect` if the path is found but has (files) or lacks (directories) a trailing slash
+///   and `Config::trailing_slash` is `TrailingSlash::Redirect`
+/// - `FileFound` if the requested path is found, a directory with one of
+///   `Config::dir_indexes` in it, or with `Config::index` if `Config::spa`
+///   is set and the request is a navigation, see `is_navigation`
+/// - `NotFoundPage` with the `Config::not_found` file if the path is not found
+/// - `FileNotFound` if the path is not found and `Config::not_found` is `None`
+fn resolve_request(req: &Request, config: &Config) -> RequestState {
+    if config.ca_certificate.is_some() {
+        match req.path.split('?').next() {
+            Some(CA_PEM_PATH) => return RequestState::CaCertificate { der: false },
+            Some(CA_DER_PATH) => return RequestState::CaCertificate { der: true },
+            _ => {}
+        }
+    }
+
+    let url = match Url::parse(&req.path) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Bad Request: {}", e);
+            return RequestState::BadRequest;
+        }
+    };
+
+    // Obtain file path from the normalized path segments
+    let file_path = match config.sandbox.resolve(&url.segments) {
+        Ok(path) => path,
+        Err(violation) => {
+            warn!("Refusing {:?}: {}", req.path, violation);
+            return RequestState::Forbidden;
+        }
+    };
+
+    match &req.client_subject {
+        Some(subject) => info!("Requesting {:?} as {}", file_path, subject),
+        None => info!("Requesting {:?}", file_path),
+    }
+
+    // Check if path exists, redirecting to or from the path with a trailing slash
+    let is_root = url.segments.is_empty();
+    if file_path.is_dir() {
+        match (config.trailing_slash, url.trailing_slash || is_root) {
+            (TrailingSlash::Redirect, false) => return RequestState::Redirect(url.href(true)),
+            (TrailingSlash::Strict, false) => {}
+            _ => return resolve_directory(url, config),
+        }
+    } else if file_path.exists() {
+        match (config.trailing_slash, url.trailing_slash) {
+            (TrailingSlash::Redirect, true) => return RequestState::Redirect(url.href(false)),
+            (TrailingSlash::Strict, true) => {}
+            _ => return RequestState::FileFound(file_path),
+        }
+    }
+
+    if config.spa && is_navigation(req, &url, &config.spa_routes) {
+        info!("Serving index for route {:?}", url.path());
+        return match config.sandbox.resolve(&file_segments(&config.index)) {
+            Ok(path) => RequestState::FileFound(path),
+            Err(violation) => {
+                warn!("Refusing index file {:?}: {}", config.index, violation);
+                RequestState::Forbidden
+            }
+        };
+    }
+
+    warn!("File not found");
+    match &config.not_found {
+        Some(not_found) => match config.sandbox.resolve(&file_segments(not_found)) {
+            Ok(path) => RequestState::NotFoundPage(path),
+            Err(violation) => {
+                warn!("Refusing 404 file {:?}: {}", not_found, violation);
+                RequestState::FileNotFound
+            }
+        },
+        None => RequestState::FileNotFound,
+    }
+}
+
+/// Resolve a request for a directory to the first of `Config::dir_indexes` inside it.
+/// Directories without an index are listed if `Config::listing` is set and forbidden otherwise.
+fn resolve_directory(url: Url, config: &Config) -> RequestState {
+    for index in &config.dir_indexes {
+        let mut segments: Vec<&str> = url.segments.iter().map(String::as_str).collect();
+        segments.extend(file_segments(index));
+
+        match config.sandbox.resolve(&segments) {
+            Ok(path) if path.is_file() => return RequestState::FileFound(path),
+            Ok(_) => {}
+            Err(violation) => {
+                warn!(
+                    "Refusing index {:?} of {:?}: {}",
+                    index,
+                    url.path(),
+                    violation
+                );
+                return RequestState::Forbidden;
+            }
+        }
+    }
+
+    if config.listing {
+        return RequestState::Listing(url);
+    }
+
+    warn!("No index file in directory {:?}", url.path());
+    RequestState::Forbidden
+}
+
+/// Whether a request for a missing path is a navigation to a client-side route,
+/// rather than a request for an asset.
+/// Navigations have no file extension and accept `text/html`, as browsers following
+/// links do, so that `fetch` calls to missing API paths still get a 404. Paths matching
+/// one of `spa_routes` are navigations regardless.
+fn is_navigation(req: &Request, url: &Url, spa_routes: &[String]) -> bool {
+    let path = url.path();
+    if spa_routes
+        .iter()
+        .any(|route| utils::glob_match(route, &path))
+    {
+        return true;
+    }
+
+    let has_extension = url
+        .segments
+        .last()
+        .is_some_and(|s| Path::new(s).extension().is_some());
+    let accepts_html = req.header("accept").is_some_and(|accept| {
+        accept
+            .split(',')
+            .any(|media| media.split(';').next().unwrap_or_default().trim() == "text/html")
+    });
+    !has_extension && accepts_html
+}
+
+/// Segments of a file name relative to the served directory, e.g. `Config::index`
+fn file_segments(name: &str) -> Vec<&str> {
+    name.split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect()
+}
+
+/// Build the response for a request state
+/// - Internal Server Error (500) if the request was not processed
+/// - Bad Request (400) if request parsing fails or the request has no path
+/// - Request Header Fields Too Large (431) if the request head exceeds `Config::limits`
+/// - Moved Permanently (301) with a `Location` to add or remove a trailing slash
+/// - Forbidden (403) if the path is refused by the sandbox, is a directory without index
+///   and listings are disabled, or the client lacks a certificate required by
+///   `Config::require_client_cert`
+/// - Ok (200) with a listing of a directory without index, see `listing_response`
+/// - Method Not Allowed (405) with an `Allow` header for methods other than `GET`, `HEAD` and `OPTIONS`
+/// - Ok (200) with an `Allow` header for `OPTIONS`
+/// - Ok (200) with the local CA certificate as an attachment, see `ca_certificate_response`
+/// - Not Found (404) if no file could be found, with the `Config::not_found` page if set
+/// - The file contents otherwise, see `file_response`
+fn build_response(state: RequestState, req: Option<&Request>, config: &Config) -> Response {
+    match state {
+        RequestState::NotProcessed => Response::new("500 INTERNAL SERVER ERROR"),
+        RequestState::ParseError | RequestState::BadRequest => Response::new("400 BAD REQUEST"),
+        RequestState::HeadersTooLarge => Response::new("431 REQUEST HEADER FIELDS TOO LARGE"),
+        RequestState::BodyTooLarge => Response::new("413 CONTENT TOO LARGE"),
+        RequestState::MethodNotAllowed => {
+            Response::new("405 METHOD NOT ALLOWED").header("Allow", ALLOWED_METHODS)
+        }
+        RequestState::Options => Response::new("200 OK").header("Allow", ALLOWED_METHODS),
+        RequestState::Redirect(location) => {
+            Response::new("301 MOVED PERMANENTLY").header("Location", location)
+        }
+        RequestState::CaCertificate { der } => ca_certificate_response(der, config),
+        RequestState::Forbidden => Response::new("403 FORBIDDEN"),
+        RequestState::Listing(url) => listing_response(&url, req, config),
+        RequestState::FileNotFound => Response::new("404 NOT FOUND"),
+        RequestState::NotFoundPage(path) => {
+            page_response("404 NOT FOUND", &path, config).unwrap_or_else(error_response)
         }
-        RequestState::BadRequest => b"HTTP/1.1 400 BAD REQUEST\r\n\r\n".to_vec(),
-        RequestState::FileNotFound => b"HTTP/1.1 404 NOT FOUND\r\n\r\n".to_vec(),
         RequestState::FileFound(path) => {
-            let mut contents = fs::read(&path).unwrap();
-            let contents_len = contents.len();
-            let contents_type =
-                utils::extension_to_mime(path.extension().and_then(std::ffi::OsStr::to_str));
-            let mut bytes: Vec<u8> = format!(
-                "HTTP/1.1 200 OK\r\nContent-type: {}\r\nContent-Length: {}\r\n\r\n",
-                contents_type, contents_len
+            file_response(&path, req, config).unwrap_or_else(error_response)
+        }
+    }
+}
+
+/// Download of the local CA certificate, see `Config::ca_certificate`
+fn ca_certificate_response(der: bool, config: &Config) -> Response {
+    let (pem, der_bytes) = match &config.ca_certificate {
+        Some(certificate) => certificate,
+        None => return Response::new("404 NOT FOUND"),
+    };
+    let (body, content_type, file_name) = if der {
+        (
+            der_bytes.clone(),
+            "application/x-x509-ca-cert",
+            "syserve-ca.crt",
+        )
+    } else {
+        (
+            pem.clone().into_bytes(),
+            "application/x-pem-file",
+            "syserve-ca.pem",
+        )
+    };
+
+    Response::new("200 OK")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .header("Cache-Control", "no-cache")
+        .body(Body::Bytes(body), content_type)
+}
+
+/// Response with the contents of a file, carrying `ETag` and `Last-Modified` validators
+/// - Not Modified (304) if the cached representation of the client is current
+/// - Precondition Failed (412) if `If-Match` or `If-Unmodified-Since` fails
+/// - Partial Content (206) with the requested ranges if a `GET` request has a satisfiable
+///   `Range` header and its `If-Range` precondition (if any) holds. Multiple ranges are
+///   sent as `multipart/byteranges`
+/// - Range Not Satisfiable (416) if none of the requested ranges overlap the file
+/// - Ok (200) with the whole file otherwise
+///
+/// HTML files get the live reload script injected if `Config::live_reload` is set,
+/// in which case ranges are not supported.
+///
+/// Full responses are served from a precompressed sidecar (see `find_sidecars`) with
+/// the coding the client prefers if there is one. Otherwise they are compressed on the
+/// fly with the coding negotiated from `Accept-Encoding` if the MIME type is
+/// compressible and the file is at least `Config::compress_min_size` bytes long.
+/// Ranges and HTTP/1.0 responses, which cannot use the chunked transfer coding, are
+/// left uncompressed.
+fn file_response(path: &Path, req: Option<&Request>, config: &Config) -> Result<Response> {
+    let fs_error = |e| Error::Fs(path.to_path_buf(), e);
+    let file = File::open(path).map_err(fs_error)?;
+    let metadata = file.metadata().map_err(fs_error)?;
+    if metadata.is_dir() {
+        return Err(Error::IsDirectory(path.to_path_buf()));
+    }
+    let contents_type =
+        utils::extension_to_mime(path.extension().and_then(std::ffi::OsStr::to_str));
+
+    let inject_reload = config.live_reload && is_html(contents_type);
+    // The live reload script cannot be injected into precompressed pages
+    let sidecars = if inject_reload {
+        Vec::new()
+    } else {
+        find_sidecars(path, &metadata, config)
+    };
+    let compressible = config
+        .compress_min_size
+        .is_some_and(|min_size| metadata.len() >= min_size)
+        && compress::is_compressible(contents_type);
+    let accepted = match req {
+        Some(req) if req.header("range").is_none() => {
+            req.header("accept-encoding").map(AcceptEncoding::parse)
+        }
+        _ => None,
+    };
+
+    let sidecar = accepted
+        .as_ref()
+        .and_then(|accepted| open_sidecar(&sidecars, accepted));
+    let encoding = match req {
+        Some(req) if compressible && sidecar.is_none() && req.version >= 1 => {
+            accepted.as_ref().and_then(Encoding::negotiate)
+        }
+        _ => None,
+    };
+    let (mut file, metadata, precompressed) = match sidecar {
+        Some((file, metadata, coding)) => (file, metadata, Some(coding)),
+        None => (file, metadata, None),
+    };
+
+    let mut validators = Validators::new(&metadata);
+    if inject_reload {
+        validators = validators.variant("reload");
+    }
+    if let Some(coding) = precompressed.or(encoding.map(Encoding::name)) {
+        validators = validators.variant(coding);
+    }
+
+    let mut response = Response::new("200 OK").header("ETag", &validators.etag);
+    if let Some(last_modified) = validators.last_modified_header() {
+        response = response.header("Last-Modified", last_modified);
+    }
+    if compressible || !sidecars.is_empty() {
+        response = response.header("Vary", "Accept-Encoding");
+    }
+
+    match req.map_or(Precondition::Proceed, |req| {
+        conditional::evaluate(req, &validators)
+    }) {
+        Precondition::Proceed => {}
+        Precondition::NotModified => {
+            return Ok(Response {
+                status: "304 NOT MODIFIED",
+                ..response
+            })
+        }
+        Precondition::Failed => {
+            warn!("Precondition failed");
+            return Ok(Response::new("412 PRECONDITION FAILED"));
+        }
+    }
+
+    if inject_reload {
+        let mut html = Vec::new();
+        file.read_to_end(&mut html).map_err(fs_error)?;
+        let html = reload::inject(&html);
+        return Ok(match encoding {
+            Some(encoding) => {
+                compressed_body(response, io::Cursor::new(html), encoding, contents_type)
+            }
+            None => response.body(Body::Bytes(html), contents_type),
+        });
+    }
+
+    let response = response.header("Accept-Ranges", "bytes");
+    if let Some(encoding) = encoding {
+        return Ok(compressed_body(response, file, encoding, contents_type));
+    }
+    let response = match precompressed {
+        Some(coding) => response.header("Content-Encoding", coding),
+        None => response,
+    };
+
+    let contents_len = metadata.len();
+    let ranges = match req {
+        Some(req) if req.method == "GET" && conditional::if_range_holds(req, &validators) => req
+            .header("range")
+            .map_or(Ranges::Full, |r| range::parse(r, contents_len)),
+        _ => Ranges::Full,
+    };
+
+    let response = match ranges {
+        Ranges::Full => response.body(
+            Body::File(file, vec![Segment::Range(0..contents_len)]),
+            contents_type,
+        ),
+        Ranges::Unsatisfiable => {
+            warn!("Range not satisfiable");
+            Response {
+                status: "416 RANGE NOT SATISFIABLE",
+                ..response
+            }
+            .header("Content-Range", format!("bytes */{}", contents_len))
+        }
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+            Response {
+                status: "206 PARTIAL CONTENT",
+                ..response
+            }
+            .header("Content-Range", range::content_range(range, contents_len))
+            .body(
+                Body::File(file, vec![Segment::Range(range.clone())]),
+                contents_type,
             )
-            .as_bytes()
-            .to_vec();
-            bytes.append(&mut contents);
+        }
+        Ranges::Partial(ranges) => {
+            let boundary = multipart_boundary();
+            Response {
+                status: "206 PARTIAL CONTENT",
+                ..response
+            }
+            .body(
+                Body::File(
+                    file,
+                    range::multipart_segments(contents_len, &ranges, contents_type, &boundary),
+                ),
+                &format!("multipart/byteranges; boundary={}", boundary),
+            )
+        }
+    };
+
+    Ok(response)
+}
+
+/// Precompressed variant of a file next to it, e.g. `app.wasm.br` for `app.wasm`
+struct Sidecar {
+    path: PathBuf,
+    /// Content coding of the sidecar, e.g. `br`
+    coding: &'static str,
+}
 
-            bytes
+/// Sidecars of `path` with the extensions of `compress::SIDECARS`, in their order.
+/// They are resolved like requested files, and skipped if older than `path` as they
+/// are then stale, e.g. after a rebuild that did not compress its output.
+fn find_sidecars(path: &Path, metadata: &fs::Metadata, config: &Config) -> Vec<Sidecar> {
+    let segments = match path.strip_prefix(config.sandbox.root()) {
+        Ok(relative) => relative
+            .iter()
+            .map(|segment| segment.to_str().map(String::from))
+            .collect::<Option<Vec<_>>>(),
+        Err(_) => None,
+    };
+    let (mut segments, name) = match segments {
+        Some(mut segments) => match segments.pop() {
+            Some(name) => (segments, name),
+            None => return Vec::new(),
+        },
+        None => return Vec::new(),
+    };
+
+    let modified = metadata.modified().ok();
+    compress::SIDECARS
+        .iter()
+        .filter_map(|&(coding, extension)| {
+            segments.push(format!("{}.{}", name, extension));
+            let resolved = config.sandbox.resolve(&segments);
+            segments.pop();
+
+            let path = resolved.ok()?;
+            let sidecar = fs::metadata(&path).ok().filter(fs::Metadata::is_file)?;
+            if sidecar.modified().ok() < modified {
+                warn!("Ignoring {:?}, older than the file it compresses", path);
+                return None;
+            }
+            Some(Sidecar { path, coding })
+        })
+        .collect()
+}
+
+/// Open the sidecar with the coding preferred by the client, if any is acceptable
+fn open_sidecar(
+    sidecars: &[Sidecar],
+    accepted: &AcceptEncoding,
+) -> Option<(File, fs::Metadata, &'static str)> {
+    let coding = accepted.best(sidecars.iter().map(|sidecar| sidecar.coding))?;
+    let sidecar = sidecars.iter().find(|sidecar| sidecar.coding == coding)?;
+    let opened = File::open(&sidecar.path).and_then(|file| Ok((file.metadata()?, file)));
+    match opened {
+        Ok((metadata, file)) => {
+            info!("Serving precompressed {:?}", sidecar.path);
+            Some((file, metadata, coding))
+        }
+        Err(e) => {
+            warn!("Cannot open {:?}: {}", sidecar.path, e);
+            None
         }
+    }
+}
+
+/// Set the body to `contents` compressed with `encoding` while it is sent
+fn compressed_body<R: Read + Send + 'static>(
+    response: Response,
+    contents: R,
+    encoding: Encoding,
+    contents_type: &str,
+) -> Response {
+    response
+        .header("Content-Encoding", encoding.name())
+        .body(Body::Stream(encoding.encoder(contents)), contents_type)
+}
+
+/// Listing of a directory, as JSON if requested with `Accept: application/json` or
+/// `?format=json` and as HTML otherwise.
+/// The `sort` (`name`, `size` or `mtime`) and `order` (`asc` or `desc`) query parameters
+/// select the order of the entries.
+fn listing_response(url: &Url, req: Option<&Request>, config: &Config) -> Response {
+    let mut entries =
+        match listing::read_entries(&config.sandbox, &url.segments, config.show_hidden) {
+            Ok(entries) => entries,
+            Err(e) => return error_response(Error::Fs(PathBuf::from(url.path()), e)),
+        };
+
+    let key = listing::SortKey::from_param(url.param("sort"));
+    let descending = url.param("order") == Some("desc");
+    listing::sort(&mut entries, key, descending);
+
+    let wants_json = url.param("format") == Some("json")
+        || req
+            .and_then(|req| req.header("accept"))
+            .is_some_and(|accept| accept.contains("application/json"));
+
+    let (body, content_type) = if wants_json {
+        (
+            listing::to_json(&url.path(), &entries).into_bytes(),
+            "application/json",
+        )
+    } else {
+        let html = listing::to_html(url, &entries, key, descending).into_bytes();
+        (
+            if config.live_reload {
+                reload::inject(&html)
+            } else {
+                html
+            },
+            "text/html; charset=utf8",
+        )
     };
+    info!("Listing directory {:?}", url.path());
 
-    stream.write(&response).unwrap();
-    stream.flush().unwrap();
+    Response::new("200 OK")
+        .header("Vary", "Accept")
+        .body(Body::Bytes(body), content_type)
+}
+
+/// Response with a whole file as the body of an error page, without validators.
+/// HTML pages get the live reload script injected if `Config::live_reload` is set.
+fn page_response(status: &'static str, path: &Path, config: &Config) -> Result<Response> {
+    let fs_error = |e| Error::Fs(path.to_path_buf(), e);
+    let mut file = File::open(path).map_err(fs_error)?;
+    let len = file.metadata().map_err(fs_error)?.len();
+    let contents_type =
+        utils::extension_to_mime(path.extension().and_then(std::ffi::OsStr::to_str));
+
+    if config.live_reload && is_html(contents_type) {
+        let mut html = Vec::new();
+        file.read_to_end(&mut html).map_err(fs_error)?;
+        return Ok(Response::new(status).body(Body::Bytes(reload::inject(&html)), contents_type));
+    }
+
+    Ok(Response::new(status).body(
+        Body::File(file, vec![Segment::Range(0..len)]),
+        contents_type,
+    ))
+}
+
+/// Whether a MIME type from `utils::extension_to_mime` is HTML
+fn is_html(mime: &str) -> bool {
+    mime.starts_with("text/html")
+}
+
+/// Response for a file that could not be served: 403, 404 or 500 depending on the error
+fn error_response(e: Error) -> Response {
+    match e {
+        Error::Fs(_, ref io) if io.kind() == ErrorKind::NotFound => warn!("{}", e),
+        Error::IsDirectory(_) => warn!("{}", e),
+        _ => error!("{}", e),
+    }
+    Response::new(e.status())
+}
+
+/// Boundary separating the parts of a `multipart/byteranges` body
+fn multipart_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    format!("syserve-byteranges-{:08x}", nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn navigations_need_html_and_no_extension() {
+        const HTML: &str = "text/html,application/xhtml+xml,*/*;q=0.8";
+        let routes = [String::from("/app/**"), String::from("/docs/*.md")];
+        let cases: &[(&str, Option<&str>, bool)] = &[
+            ("/users/42", Some(HTML), true),
+            ("/users/42", Some("text/html; charset=utf-8"), true),
+            ("/", Some(HTML), true),
+            // `fetch` calls and assets get a 404
+            ("/api/users", Some("*/*"), false),
+            ("/api/users", Some("application/json"), false),
+            ("/api/users", None, false),
+            ("/pkg/missing.wasm", Some(HTML), false),
+            ("/users/42", Some("text/htmlx"), false),
+            // Routes are navigations regardless
+            ("/app/settings", Some("*/*"), true),
+            ("/app/v1.2/settings", None, true),
+            ("/docs/intro.md", None, true),
+            ("/docs/guide/intro.md", None, false),
+        ];
+        for (path, accept, expected) in cases {
+            let req = Request {
+                method: String::from("GET"),
+                path: path.to_string(),
+                version: 1,
+                headers: accept
+                    .map(|accept| vec![(String::from("Accept"), accept.to_string())])
+                    .unwrap_or_default(),
+                body: Vec::new(),
+                client_subject: None,
+            };
+            let url = Url::parse(path).unwrap();
+            assert_eq!(
+                is_navigation(&req, &url, &routes),
+                *expected,
+                "{} with {:?}",
+                path,
+                accept
+            );
+        }
+    }
 }
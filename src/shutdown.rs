@@ -0,0 +1,99 @@
+//! Graceful shutdown: triggering it and tracking connections to drain
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{self, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+extern crate signal_hook;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+/// Handle to stop a running server. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Trigger shutdown on `SIGINT` and `SIGTERM`.
+    /// A second signal after shutdown was triggered exits the process immediately.
+    pub fn register_signals(&self) -> io::Result<()> {
+        for signal in &[SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(
+                *signal,
+                1,
+                Arc::clone(&self.triggered),
+            )?;
+            signal_hook::flag::register(*signal, Arc::clone(&self.triggered))?;
+        }
+        Ok(())
+    }
+
+    /// Stop accepting connections and start draining
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+}
+
+/// Open connections, so idle ones can be closed on shutdown
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicUsize,
+    streams: Mutex<HashMap<usize, TcpStream>>,
+}
+
+impl Connections {
+    /// Track a connection until the returned guard is dropped
+    pub fn register(&self, stream: &TcpStream) -> io::Result<Registration<'_>> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let clone = stream.try_clone()?;
+        self.streams.lock().unwrap().insert(id, clone);
+        Ok(Registration {
+            connections: self,
+            id,
+        })
+    }
+
+    /// Number of open connections
+    pub fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Shut down the given half of every open connection.
+    /// Closing the read half wakes handlers waiting for a request while letting
+    /// responses being written finish.
+    pub fn shutdown_all(&self, how: net::Shutdown) {
+        for stream in self.streams.lock().unwrap().values() {
+            // The connection may already be closed by the client
+            let _ = stream.shutdown(how);
+        }
+    }
+}
+
+/// Guard keeping a connection in `Connections` while it is open
+pub struct Registration<'a> {
+    connections: &'a Connections,
+    id: usize,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = self.connections.streams.lock() {
+            streams.remove(&self.id);
+        }
+    }
+}
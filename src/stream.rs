@@ -0,0 +1,107 @@
+//! Client connections, over plain TCP or TLS
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// A client connection
+pub enum Stream {
+    Plain(TcpStream),
+    /// TLS connection. The handshake happens on the first read or write
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Underlying TCP connection, e.g. for timeouts and shutdown
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    /// Whether the connection is encrypted
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, Stream::Plain(_))
+    }
+
+    /// Complete the TLS handshake, if not done yet. A client closing the connection
+    /// during the handshake is not an error, the next read returns 0
+    pub fn handshake(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(_) => Ok(()),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => {
+                while stream.conn.is_handshaking() {
+                    match stream.conn.complete_io(&mut stream.sock) {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Protocol agreed on with ALPN during the TLS handshake, e.g. `h2`
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Stream::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.conn.alpn_protocol(),
+        }
+    }
+
+    /// Subject of the certificate the client authenticated with, e.g. `CN=alice, O=Example`.
+    /// Only known once the TLS handshake is done, on the first read
+    pub fn client_subject(&self) -> Option<String> {
+        match self {
+            Stream::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => certificate_subject(stream.conn.peer_certificates()?.first()?),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => match stream.read(buf) {
+                // Browsers often close connections without `close_notify`, which rustls
+                // reports as an error. Truncation does not matter for requests, as their
+                // length is always known.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                result => result,
+            },
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Subject of a DER certificate, e.g. `CN=alice, O=Example`
+#[cfg(feature = "tls")]
+pub(crate) fn certificate_subject(cert: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(cert.subject().to_string())
+}
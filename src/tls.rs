@@ -0,0 +1,220 @@
+//! TLS configuration: certificates loaded from PEM files, generated on startup or
+//! issued by the local CA
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::info;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+
+use crate::ca::{self, LocalCa};
+use crate::cli::ClientAuth;
+use crate::error::{Error, Result};
+
+/// Names always included in generated certificates
+const DEFAULT_NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+/// Where the certificate of the TLS listener comes from
+pub struct Options {
+    /// PEM certificate chain and private key. A certificate is generated if `None`
+    pub cert_key: Option<(PathBuf, PathBuf)>,
+    /// Host names and IP addresses added to generated certificates
+    pub names: Vec<String>,
+    /// Directory where the generated certificate is kept and reused on later runs
+    pub cert_dir: Option<PathBuf>,
+    /// Local CA issuing the certificates instead of generating a self-signed one
+    pub ca: Option<LocalCa>,
+    /// PEM bundle of the CAs client certificates are verified against, and the handling
+    /// of clients without a valid one. Clients are not asked for certificates if `None`
+    pub client_auth: Option<(PathBuf, ClientAuth)>,
+    /// Offer HTTP/2 with ALPN, preferred over HTTP/1.1
+    pub http2: bool,
+}
+
+/// Certificate chain and private key
+pub type Identity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// Server configuration for `options`, offering HTTP/1.1 and optionally HTTP/2
+pub fn server_config(options: Options) -> Result<Arc<ServerConfig>> {
+    let builder = match &options.client_auth {
+        Some((bundle, mode)) => {
+            builder()?.with_client_cert_verifier(client_verifier(bundle, *mode)?)
+        }
+        None => builder()?.with_no_client_auth(),
+    };
+    let mut config = match options.ca {
+        Some(ca) if options.cert_key.is_none() => {
+            let names = names(&options.names);
+            builder.with_cert_resolver(Arc::new(ca::Resolver::new(ca, names)?))
+        }
+        _ => {
+            let (certs, key) = identity(&options)?;
+            builder
+                .with_single_cert(certs, key)
+                .map_err(|e| Error::Tls(format!("invalid certificate or key: {}", e)))?
+        }
+    };
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    if options.http2 {
+        config.alpn_protocols.insert(0, b"h2".to_vec());
+    }
+
+    Ok(Arc::new(config))
+}
+
+/// Configuration builder with the ring crypto provider and safe protocol versions
+fn builder() -> Result<rustls::ConfigBuilder<ServerConfig, rustls::WantsVerifier>> {
+    ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::Tls(e.to_string()))
+}
+
+pub(crate) fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Verifier of client certificates issued by the CAs in the PEM `bundle`.
+/// Clients presenting an invalid certificate are always refused with a TLS alert,
+/// clients without one only with `ClientAuth::Require`
+fn client_verifier(
+    bundle: &Path,
+    mode: ClientAuth,
+) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>> {
+    info!("Verifying client certificates against {:?}", bundle);
+    let pem = fs::read(bundle).map_err(|e| Error::Fs(bundle.to_path_buf(), e))?;
+    let mut roots = RootCertStore::empty();
+    for cert in parse_certs(pem)? {
+        roots
+            .add(cert)
+            .map_err(|e| Error::Tls(format!("invalid client CA certificate: {}", e)))?;
+    }
+
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+    let verifier = match mode {
+        ClientAuth::Require => verifier,
+        ClientAuth::Optional | ClientAuth::RequireHttp => verifier.allow_unauthenticated(),
+    };
+    verifier
+        .build()
+        .map_err(|e| Error::Tls(format!("invalid client CA bundle: {}", e)))
+}
+
+/// Load or generate the certificate chain and key for `options`
+pub fn identity(options: &Options) -> Result<Identity> {
+    if let Some((cert, key)) = &options.cert_key {
+        info!("Loading TLS certificate {:?} and key {:?}", cert, key);
+        return load_pem(cert, key);
+    }
+
+    let names = names(&options.names);
+    match &options.cert_dir {
+        Some(dir) => persisted(dir, names),
+        None => {
+            info!(
+                "Generating a self-signed certificate for {}",
+                names.join(", ")
+            );
+            let (cert_pem, key_pem) = self_signed(names)?;
+            parse_pem(&cert_pem, &key_pem)
+        }
+    }
+}
+
+/// `DEFAULT_NAMES` followed by `extra` names
+fn names(extra: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = DEFAULT_NAMES.iter().map(|s| s.to_string()).collect();
+    for name in extra {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+/// Certificate kept in `dir`, generated again if missing or issued for other names
+fn persisted(dir: &Path, names: Vec<String>) -> Result<Identity> {
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    let names_path = dir.join("names");
+    let fs_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |e| Error::Fs(path, e)
+    };
+
+    let saved_names = fs::read_to_string(&names_path).unwrap_or_default();
+    if cert_path.is_file() && key_path.is_file() && saved_names == names.join("\n") {
+        info!("Reusing the self-signed certificate in {:?}", dir);
+        return load_pem(&cert_path, &key_path);
+    }
+
+    info!(
+        "Generating a self-signed certificate for {} in {:?}",
+        names.join(", "),
+        dir
+    );
+    let joined = names.join("\n");
+    let (cert_pem, key_pem) = self_signed(names)?;
+    fs::create_dir_all(dir).map_err(fs_error(dir))?;
+    fs::write(&cert_path, &cert_pem).map_err(fs_error(&cert_path))?;
+    write_private(&key_path, &key_pem).map_err(fs_error(&key_path))?;
+    fs::write(&names_path, joined).map_err(fs_error(&names_path))?;
+
+    parse_pem(&cert_pem, &key_pem)
+}
+
+/// PEM certificate and key of a new self-signed certificate for `names`
+fn self_signed(names: Vec<String>) -> Result<(String, String)> {
+    let certified = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| Error::Tls(format!("could not generate a certificate: {}", e)))?;
+    Ok((certified.cert.pem(), certified.signing_key.serialize_pem()))
+}
+
+/// Write a private key, readable by the owner only where supported
+pub fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(contents.as_bytes())
+    }
+    #[cfg(not(unix))]
+    fs::write(path, contents)
+}
+
+/// Certificate chain and private key from PEM files
+pub fn load_pem(cert: &Path, key: &Path) -> Result<Identity> {
+    let cert_pem = fs::read(cert).map_err(|e| Error::Fs(cert.to_path_buf(), e))?;
+    let key_pem = fs::read(key).map_err(|e| Error::Fs(key.to_path_buf(), e))?;
+    parse_pem(&cert_pem, &key_pem)
+}
+
+fn parse_pem<C: AsRef<[u8]>, K: AsRef<[u8]>>(cert: C, key: K) -> Result<Identity> {
+    let certs = parse_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_slice(key.as_ref())
+        .map_err(|e| Error::Tls(format!("invalid private key: {}", e)))?;
+
+    Ok((certs, key))
+}
+
+/// Certificates in PEM, failing if there are none
+pub fn parse_certs<C: AsRef<[u8]>>(cert: C) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(cert.as_ref())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::Tls(format!("invalid certificate: {}", e)))?;
+    if certs.is_empty() {
+        return Err(Error::Tls(String::from("no certificate found")));
+    }
+    Ok(certs)
+}
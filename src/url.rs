@@ -0,0 +1,334 @@
+//! Parsing of request targets into a normalized path, query and fragment
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+/// How paths to directories and files are treated depending on a trailing slash
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TrailingSlash {
+    /// Redirect directories to their path with a slash and files to their path without one
+    #[default]
+    Redirect,
+    /// Serve directories and files with or without a trailing slash
+    Ignore,
+    /// Only serve directories with a trailing slash and files without one
+    Strict,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redirect" => Ok(TrailingSlash::Redirect),
+            "ignore" => Ok(TrailingSlash::Ignore),
+            "strict" => Ok(TrailingSlash::Strict),
+            _ => Err(format!(
+                "invalid trailing slash policy {:?}, expected redirect, ignore or strict",
+                s
+            )),
+        }
+    }
+}
+
+/// A parsed request target
+#[derive(Debug, PartialEq)]
+pub struct Url {
+    /// Percent-decoded path segments, with `.`, `..` and empty segments resolved
+    pub segments: Vec<String>,
+    /// Whether the path ended with a slash
+    pub trailing_slash: bool,
+    /// Raw query string, without the leading `?`
+    pub query: Option<String>,
+    /// Raw fragment, without the leading `#`. Browsers do not send it, but some clients do
+    pub fragment: Option<String>,
+    /// Decoded query parameters in the order they appear
+    pub params: Vec<(String, String)>,
+}
+
+/// Reasons a request target is rejected
+#[derive(Debug, PartialEq)]
+pub enum UrlError {
+    /// The target is neither an absolute path nor an absolute URL
+    NotAbsolute,
+    /// A `%` is not followed by two hex digits
+    InvalidEscape,
+    /// A path segment does not decode to UTF-8
+    InvalidUtf8,
+    /// A path segment decodes to a `/` or NUL byte
+    ReservedCharacter,
+}
+
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UrlError::NotAbsolute => write!(f, "target is not an absolute path"),
+            UrlError::InvalidEscape => write!(f, "invalid percent-encoding"),
+            UrlError::InvalidUtf8 => write!(f, "path is not valid UTF-8"),
+            UrlError::ReservedCharacter => write!(f, "path segment contains '/' or NUL"),
+        }
+    }
+}
+
+impl Url {
+    /// Parse a request target in origin form (`/path?query`) or absolute form
+    /// (`http://host/path?query`)
+    pub fn parse(target: &str) -> Result<Url, UrlError> {
+        let target = strip_authority(target).ok_or(UrlError::NotAbsolute)?;
+        let target = target.as_ref();
+
+        let (rest, fragment) = match target.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment.to_string())),
+            None => (target, None),
+        };
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (rest, None),
+        };
+
+        let mut segments: Vec<String> = Vec::new();
+        for raw in path.split('/') {
+            let segment = String::from_utf8(percent_decode(raw, false)?)
+                .map_err(|_| UrlError::InvalidUtf8)?;
+            if segment.contains(['/', '\0']) {
+                return Err(UrlError::ReservedCharacter);
+            }
+
+            match segment.as_str() {
+                "" | "." => {}
+                ".." => {
+                    segments.pop();
+                }
+                _ => segments.push(segment),
+            }
+        }
+
+        let params = query.as_deref().map(parse_query).unwrap_or_default();
+
+        Ok(Url {
+            segments,
+            trailing_slash: path.len() > 1 && path.ends_with('/'),
+            query,
+            fragment,
+            params,
+        })
+    }
+
+    /// Normalized, decoded path, starting with `/`
+    pub fn path(&self) -> String {
+        let mut path = format!("/{}", self.segments.join("/"));
+        if self.trailing_slash && !self.segments.is_empty() {
+            path.push('/');
+        }
+        path
+    }
+
+    /// Percent-encoded path and query, usable as a `Location`.
+    /// The path ends with a slash if `trailing_slash` is set.
+    pub fn href(&self, trailing_slash: bool) -> String {
+        let mut href = String::from("/");
+        href.push_str(
+            &self
+                .segments
+                .iter()
+                .map(|s| percent_encode(s))
+                .collect::<Vec<_>>()
+                .join("/"),
+        );
+        if trailing_slash && !self.segments.is_empty() {
+            href.push('/');
+        }
+        if let Some(query) = &self.query {
+            href.push('?');
+            href.push_str(query);
+        }
+        href
+    }
+
+    /// Value of the first query parameter named `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Path, query and fragment of a target, with the scheme and authority of
+/// absolute-form targets removed. `None` if the target has no absolute path.
+fn strip_authority(target: &str) -> Option<Cow<'_, str>> {
+    if target.starts_with('/') {
+        return Some(Cow::Borrowed(target));
+    }
+
+    let lower = target.to_ascii_lowercase();
+    let scheme_len = if lower.starts_with("http://") {
+        "http://".len()
+    } else if lower.starts_with("https://") {
+        "https://".len()
+    } else {
+        return None;
+    };
+
+    let rest = &target[scheme_len..];
+    match rest.find(['/', '?', '#']) {
+        Some(i) if rest[i..].starts_with('/') => Some(Cow::Borrowed(&rest[i..])),
+        // `http://host` and `http://host?query` have an empty path, i.e. `/`
+        Some(i) => Some(Cow::Owned(format!("/{}", &rest[i..]))),
+        None => Some(Cow::Borrowed("/")),
+    }
+}
+
+/// Decode `%XX` escapes, and `+` as a space if `plus_as_space` is set (query strings)
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Result<Vec<u8>, UrlError> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .ok_or(UrlError::InvalidEscape)?;
+                // Two ASCII hex digits always form a valid `u8`
+                let hex = std::str::from_utf8(hex).unwrap_or_default();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// Encode a path segment, leaving unreserved characters and sub-delimiters as is
+pub fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Split a query string into decoded `name=value` pairs.
+/// Pairs that fail to decode are kept undecoded.
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    let decode = |s: &str| match percent_decode(s, true) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => s.to_string(),
+    };
+
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (decode(name), decode(value)),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets() {
+        let cases: &[(&str, &[&str], bool, Option<&str>)] = &[
+            ("/", &[], false, None),
+            ("/a/b.txt", &["a", "b.txt"], false, None),
+            ("/a/b/", &["a", "b"], true, None),
+            ("/a//b/./c", &["a", "b", "c"], false, None),
+            ("/a/../../b", &["b"], false, None),
+            ("/a%20b/caf%C3%A9", &["a b", "café"], false, None),
+            ("/a+b", &["a+b"], false, None),
+            ("/%2e%2E/x", &["x"], false, None),
+            ("/docs?sort=size#top", &["docs"], false, Some("sort=size")),
+            ("http://localhost:7878/a/?q", &["a"], true, Some("q")),
+            ("HTTPS://host", &[], false, None),
+            ("http://host?q", &[], false, Some("q")),
+        ];
+        for (target, segments, trailing_slash, query) in cases {
+            let url = Url::parse(target).unwrap();
+            assert_eq!(url.segments, *segments, "{:?}", target);
+            assert_eq!(url.trailing_slash, *trailing_slash, "{:?}", target);
+            assert_eq!(url.query.as_deref(), *query, "{:?}", target);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_targets() {
+        let cases: &[(&str, UrlError)] = &[
+            ("", UrlError::NotAbsolute),
+            ("a/b", UrlError::NotAbsolute),
+            ("ftp://host/a", UrlError::NotAbsolute),
+            ("/a%", UrlError::InvalidEscape),
+            ("/a%2", UrlError::InvalidEscape),
+            ("/a%zz", UrlError::InvalidEscape),
+            ("/a%%41", UrlError::InvalidEscape),
+            ("/%C0%AE", UrlError::InvalidUtf8),
+            ("/%FF", UrlError::InvalidUtf8),
+            ("/..%2Fsecret", UrlError::ReservedCharacter),
+            ("/a%2fb", UrlError::ReservedCharacter),
+            ("/a%00.html", UrlError::ReservedCharacter),
+        ];
+        for (target, error) in cases {
+            assert_eq!(
+                Url::parse(target).err().as_ref(),
+                Some(error),
+                "{:?}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_query_parameters() {
+        let url = Url::parse("/?sort=size&order=desc&q=a+b%26c&flag&&bad=%zz").unwrap();
+        assert_eq!(url.param("sort"), Some("size"));
+        assert_eq!(url.param("q"), Some("a b&c"));
+        assert_eq!(url.param("flag"), Some(""));
+        assert_eq!(url.param("bad"), Some("%zz"));
+        assert_eq!(url.param("missing"), None);
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        let cases: &[(&str, &str)] = &[
+            ("a b", "a%20b"),
+            ("café", "caf%C3%A9"),
+            ("a?b#c%d", "a%3Fb%23c%25d"),
+            ("a+b=c", "a+b=c"),
+        ];
+        for (segment, encoded) in cases {
+            assert_eq!(percent_encode(segment), *encoded);
+            assert_eq!(percent_decode(encoded, false).unwrap(), segment.as_bytes());
+        }
+        assert_eq!(percent_decode("a+b", true).unwrap(), b"a b");
+    }
+
+    #[test]
+    fn builds_paths_and_hrefs() {
+        let url = Url::parse("/a%20b/c/?x=1").unwrap();
+        assert_eq!(url.path(), "/a b/c/");
+        assert_eq!(url.href(false), "/a%20b/c?x=1");
+        assert_eq!(url.href(true), "/a%20b/c/?x=1");
+        assert_eq!(Url::parse("/").unwrap().href(true), "/");
+    }
+}
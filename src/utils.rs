@@ -573,3 +573,72 @@ pub fn extension_to_mime(extension: Option<&str>) -> &'static str {
         _ => "application/octet-stream",
     }
 }
+
+/// Match a URL path against a glob pattern.
+/// `*` matches any characters within a path segment, `**` matches across segments
+/// and `?` matches a single character other than `/`.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    glob_match_from(&pattern, &path)
+}
+
+fn glob_match_from(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| glob_match_from(rest, &path[i..])),
+        ['*', rest @ ..] => {
+            let segment_len = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+            (0..=segment_len).any(|i| glob_match_from(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => {
+            matches!(path, [c, ..] if *c != '/') && glob_match_from(rest, &path[1..])
+        }
+        [c, rest @ ..] => path.first() == Some(c) && glob_match_from(rest, &path[1..]),
+    }
+}
+
+/// Escape text for HTML element contents and quoted attribute values
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("/app", "/app", true),
+            ("/app", "/app/", false),
+            ("/app/*", "/app/users", true),
+            ("/app/*", "/app/", true),
+            ("/app/*", "/app/users/42", false),
+            ("/app/**", "/app/users/42", true),
+            ("/app/**", "/app/", true),
+            ("/app/**", "/application", false),
+            ("/**/edit", "/users/42/edit", true),
+            ("/**/edit", "/edit", false),
+            ("/*.html", "/index.html", true),
+            ("/*.html", "/docs/index.html", false),
+            ("/user?", "/user1", true),
+            ("/user?", "/user/", false),
+            ("/user?", "/user", false),
+            ("**", "/anything/at/all", true),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(
+                glob_match(pattern, path),
+                *expected,
+                "{} against {}",
+                path,
+                pattern
+            );
+        }
+    }
+}
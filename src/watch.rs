@@ -0,0 +1,323 @@
+//! Watching directories for changes, with inotify on Linux and polling elsewhere
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+/// Quiet time after the last change before a batch of changes is reported
+pub const DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// Interval between scans of the polling watcher
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watch `roots` recursively. Every burst of changes is reported as one batch of
+/// changed paths, once no further change happened for `debounce`.
+///
+/// On Linux inotify is used, falling back to polling if it is not available.
+/// The watcher threads stop once the returned receiver is dropped and a change happens.
+pub fn watch(roots: Vec<PathBuf>, debounce: Duration) -> Receiver<Vec<PathBuf>> {
+    let (raw_tx, raw_rx) = mpsc::channel();
+    let (batch_tx, batch_rx) = mpsc::channel();
+
+    #[cfg(target_os = "linux")]
+    match inotify::Inotify::new(&roots) {
+        Ok(inotify) => {
+            thread::spawn(move || inotify.run(raw_tx));
+        }
+        Err(e) => {
+            warn!("inotify unavailable ({}), polling for changes", e);
+            thread::spawn(move || poll(roots, raw_tx));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    thread::spawn(move || poll(roots, raw_tx));
+
+    thread::spawn(move || debounce_changes(raw_rx, batch_tx, debounce));
+    batch_rx
+}
+
+/// Group raw changes into batches separated by at least `debounce` of quiet
+fn debounce_changes(raw: Receiver<PathBuf>, batches: Sender<Vec<PathBuf>>, debounce: Duration) {
+    while let Ok(first) = raw.recv() {
+        let mut batch = BTreeSet::new();
+        batch.insert(first);
+
+        loop {
+            match raw.recv_timeout(debounce) {
+                Ok(path) => {
+                    batch.insert(path);
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+
+        if batches.send(batch.into_iter().collect()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Modification time and size of every file under `roots`
+fn snapshot(roots: &[PathBuf]) -> HashMap<PathBuf, (Option<SystemTime>, u64)> {
+    let mut files = HashMap::new();
+    let mut dirs: Vec<PathBuf> = roots.to_vec();
+
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => {
+                // A watched file rather than a directory
+                if let Ok(metadata) = fs::metadata(&dir) {
+                    files.insert(dir, (metadata.modified().ok(), metadata.len()));
+                }
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => dirs.push(path),
+                Ok(metadata) => {
+                    files.insert(path, (metadata.modified().ok(), metadata.len()));
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    files
+}
+
+/// Polling watcher, comparing snapshots every `POLL_INTERVAL`
+fn poll(roots: Vec<PathBuf>, changes: Sender<PathBuf>) {
+    let mut previous = snapshot(&roots);
+
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let current = snapshot(&roots);
+
+        let modified = current
+            .iter()
+            .filter(|(path, state)| previous.get(*path) != Some(state))
+            .map(|(path, _)| path);
+        let removed = previous.keys().filter(|path| !current.contains_key(*path));
+
+        for path in modified.chain(removed) {
+            if changes.send(path.clone()).is_err() {
+                return;
+            }
+        }
+        previous = current;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::HashMap;
+    use std::ffi::{CString, OsStr};
+    use std::fs;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::Sender;
+
+    use log::error;
+
+    const MASK: u32 = libc::IN_CLOSE_WRITE
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_MODIFY;
+
+    /// Recursive inotify watch
+    pub struct Inotify {
+        fd: libc::c_int,
+        /// Watched directory of each watch descriptor
+        dirs: HashMap<libc::c_int, PathBuf>,
+    }
+
+    impl Inotify {
+        pub fn new(roots: &[PathBuf]) -> io::Result<Inotify> {
+            // Safety: plain system call without pointer arguments
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut inotify = Inotify {
+                fd,
+                dirs: HashMap::new(),
+            };
+            for root in roots {
+                inotify.add_recursive(root)?;
+            }
+            Ok(inotify)
+        }
+
+        /// Watch `dir` and all directories below it
+        fn add_recursive(&mut self, dir: &Path) -> io::Result<()> {
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // Safety: `path` is a valid NUL terminated string for the duration of the call
+            let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.dirs.insert(wd, dir.to_path_buf());
+
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                        // Directories may vanish while being added
+                        let _ = self.add_recursive(&entry.path());
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// Read events until `changes` is disconnected
+        pub fn run(mut self, changes: Sender<PathBuf>) {
+            let header = std::mem::size_of::<libc::inotify_event>();
+            let mut buf = vec![0u8; 64 * 1024];
+
+            loop {
+                // Safety: `buf` is valid for writes of its length
+                let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+                if n < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    error!("Error reading inotify events: {}", e);
+                    return;
+                }
+
+                let mut offset = 0;
+                while offset + header <= n as usize {
+                    // Safety: the kernel writes whole events, `read_unaligned` copes with
+                    // the byte buffer alignment
+                    let event: libc::inotify_event =
+                        unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const _) };
+                    let name_bytes = &buf[offset + header..offset + header + event.len as usize];
+                    let name_len = name_bytes
+                        .iter()
+                        .position(|&b| b == 0)
+                        .unwrap_or(name_bytes.len());
+                    offset += header + event.len as usize;
+
+                    let dir = match self.dirs.get(&event.wd) {
+                        Some(dir) => dir.clone(),
+                        None => continue,
+                    };
+                    if event.mask & libc::IN_IGNORED != 0 {
+                        self.dirs.remove(&event.wd);
+                        continue;
+                    }
+                    // Events on a watched file itself carry no name
+                    let path = match name_len {
+                        0 => dir,
+                        _ => dir.join(OsStr::from_bytes(&name_bytes[..name_len])),
+                    };
+
+                    let is_new_dir = event.mask & libc::IN_ISDIR != 0
+                        && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0;
+                    if is_new_dir {
+                        let _ = self.add_recursive(&path);
+                    }
+
+                    if changes.send(path).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            // Safety: `fd` is owned by this struct and closed only here
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a test file, which need not exist
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from("/watched").join(name)
+    }
+
+    #[test]
+    fn bursts_of_changes_are_coalesced() {
+        let (raw_tx, raw_rx) = mpsc::channel();
+        let (batch_tx, batch_rx) = mpsc::channel();
+        let debounce = Duration::from_millis(100);
+        thread::spawn(move || debounce_changes(raw_rx, batch_tx, debounce));
+
+        for name in &["b.rs", "a.rs", "b.rs", "c.rs"] {
+            raw_tx.send(path(name)).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        let batch = batch_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batch, vec![path("a.rs"), path("b.rs"), path("c.rs")]);
+        assert!(batch_rx.recv_timeout(2 * debounce).is_err());
+
+        // Changes after a quiet period start a new batch
+        raw_tx.send(path("a.rs")).unwrap();
+        let batch = batch_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batch, vec![path("a.rs")]);
+
+        // The debouncer stops with the watcher
+        drop(raw_tx);
+        assert_eq!(
+            batch_rx.recv_timeout(Duration::from_secs(5)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn polling_reports_modified_created_and_removed_files() {
+        let dir = std::env::temp_dir().join(format!("syserve-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("kept.txt"), "kept").unwrap();
+        fs::write(dir.join("removed.txt"), "removed").unwrap();
+
+        let (raw_tx, raw_rx) = mpsc::channel();
+        let (batch_tx, batch_rx) = mpsc::channel();
+        {
+            let dir = dir.clone();
+            thread::spawn(move || poll(vec![dir], raw_tx));
+        }
+        thread::spawn(move || debounce_changes(raw_rx, batch_tx, DEBOUNCE));
+        // Let the first snapshot be taken
+        thread::sleep(POLL_INTERVAL / 2);
+
+        fs::write(dir.join("kept.txt"), "changed").unwrap();
+        fs::write(dir.join("sub/new.txt"), "new").unwrap();
+        fs::remove_file(dir.join("removed.txt")).unwrap();
+
+        let batch = batch_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            batch,
+            vec![
+                dir.join("kept.txt"),
+                dir.join("removed.txt"),
+                dir.join("sub/new.txt")
+            ]
+        );
+        assert!(batch_rx.recv_timeout(2 * POLL_INTERVAL).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,179 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use syserve::cli::Args;
+use syserve::error::Result;
+use syserve::server::{serve_until, Summary};
+use syserve::shutdown::Shutdown;
+
+/// Temporary directory `<tmp>/syserve-<name>-<pid>`, emptied and populated with
+/// `files`, given as paths relative to it and their contents
+pub fn fixture(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let base = std::env::temp_dir().join(format!("syserve-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    for (path, contents) in files {
+        let path = base.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    base
+}
+
+/// Port nothing listens on over TCP or UDP, as assigned by the system
+pub fn free_port() -> u16 {
+    loop {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        // HTTP/3 listens on the same port over UDP
+        if UdpSocket::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
+
+/// Server running in a thread on a free port of localhost
+pub struct TestServer {
+    pub port: u16,
+    shutdown: Shutdown,
+    thread: JoinHandle<Result<Summary>>,
+}
+
+impl TestServer {
+    /// Serve `dir` with the default options, changed by `configure`
+    pub fn start<F: FnOnce(&mut Args)>(dir: &Path, configure: F) -> TestServer {
+        let port = free_port();
+        let mut args = Args::new();
+        args.dir = dir.to_path_buf();
+        args.port = port;
+        args.address = SocketAddr::from(([127, 0, 0, 1], port));
+        configure(&mut args);
+
+        let shutdown = Shutdown::new();
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || serve_until(args, shutdown))
+        };
+        TestServer {
+            port,
+            shutdown,
+            thread,
+        }
+    }
+
+    /// Send a request over a new connection, see `request`
+    pub fn request(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Reply {
+        request(self.port, method, path, headers)
+    }
+
+    /// Shut the server down and wait for it
+    pub fn stop(self) -> Summary {
+        self.shutdown.trigger();
+        self.thread.join().unwrap().unwrap()
+    }
+}
+
+/// Connect, retrying while the server starts
+pub fn connect(port: u16) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) {
+            return stream;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("server did not start on port {}", port);
+}
+
+/// Response read by `request`, with its body decoded if it was sent chunked
+pub struct Reply {
+    /// Status code
+    pub status: u16,
+    /// Header fields, in order
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    /// Value of the first header named `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Send an HTTP/1.1 request with `headers` over a new connection, closed by the
+/// server once it answered, and read the response
+pub fn request(port: u16, method: &str, path: &str, headers: &[(&str, &str)]) -> Reply {
+    let mut stream = connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n",
+        method, path
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    parse_reply(&response)
+}
+
+fn parse_reply(response: &[u8]) -> Reply {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("incomplete response head");
+    let head = String::from_utf8(response[..end].to_vec()).unwrap();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+    let headers: Vec<(String, String)> = lines
+        .map(|line| {
+            let (name, value) = line.split_once(':').unwrap();
+            (name.to_string(), value.trim().to_string())
+        })
+        .collect();
+
+    let mut reply = Reply {
+        status: status.parse().unwrap(),
+        headers,
+        body: response[end + 4..].to_vec(),
+    };
+    // Responses to HEAD requests announce the coding without a body
+    if reply.header("transfer-encoding") == Some("chunked") && !reply.body.is_empty() {
+        reply.body = dechunk(&reply.body);
+    }
+    reply
+}
+
+/// Body sent with the chunked transfer coding
+fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let line = chunked.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = std::str::from_utf8(&chunked[..line]).unwrap();
+        let size = usize::from_str_radix(size.split(';').next().unwrap(), 16).unwrap();
+        chunked = &chunked[line + 2..];
+        if size == 0 {
+            return body;
+        }
+        body.extend_from_slice(&chunked[..size]);
+        chunked = &chunked[size + 2..];
+    }
+}
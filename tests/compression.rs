@@ -0,0 +1,129 @@
+//! On-the-fly compression: negotiation, `Vary`, entity tags and ranges
+
+mod common;
+
+use std::fs;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+use common::TestServer;
+
+/// Compressible file, larger than the default minimum size
+fn script() -> String {
+    "console.log(\"syserve\");\n".repeat(200)
+}
+
+fn gunzip(body: &[u8]) -> String {
+    let mut decoded = String::new();
+    GzDecoder::new(body).read_to_string(&mut decoded).unwrap();
+    decoded
+}
+
+#[test]
+fn compresses_negotiated_responses() {
+    let script = script();
+    let base = common::fixture(
+        "compression",
+        &[("root/app.js", &script), ("root/small.js", "let small;")],
+    );
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let identity = server.request("GET", "/app.js", &[]);
+    assert_eq!(identity.status, 200);
+    assert_eq!(identity.header("vary"), Some("Accept-Encoding"));
+    assert_eq!(identity.header("content-encoding"), None);
+    assert_eq!(identity.body, script.as_bytes());
+
+    let gzip = server.request("GET", "/app.js", &[("Accept-Encoding", "gzip")]);
+    assert_eq!(gzip.status, 200);
+    assert_eq!(gzip.header("vary"), Some("Accept-Encoding"));
+    assert_eq!(gzip.header("content-encoding"), Some("gzip"));
+    assert_eq!(gzip.header("content-length"), None);
+    assert!(gzip.body.len() < script.len() / 10);
+    assert_eq!(gunzip(&gzip.body), script);
+
+    // Each coding is a representation with its own entity tag
+    let etag = gzip.header("etag").unwrap();
+    assert_ne!(Some(etag), identity.header("etag"));
+    let cached = server.request(
+        "GET",
+        "/app.js",
+        &[("Accept-Encoding", "gzip"), ("If-None-Match", etag)],
+    );
+    assert_eq!(cached.status, 304);
+    assert_eq!(cached.header("etag"), Some(etag));
+    assert_eq!(cached.header("vary"), Some("Accept-Encoding"));
+    let other = server.request(
+        "GET",
+        "/app.js",
+        &[
+            ("Accept-Encoding", "gzip"),
+            ("If-None-Match", identity.header("etag").unwrap()),
+        ],
+    );
+    assert_eq!(other.status, 200);
+
+    let head = server.request("HEAD", "/app.js", &[("Accept-Encoding", "gzip")]);
+    assert_eq!(head.header("content-encoding"), Some("gzip"));
+    assert!(head.body.is_empty());
+
+    // Files below the minimum size are sent as they are
+    let small = server.request("GET", "/small.js", &[("Accept-Encoding", "gzip")]);
+    assert_eq!(small.header("content-encoding"), None);
+    assert_eq!(small.header("vary"), None);
+    assert_eq!(small.body, b"let small;");
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[test]
+fn ranges_are_served_uncompressed() {
+    let script = script();
+    let base = common::fixture("compression-ranges", &[("root/app.js", &script)]);
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let identity = server.request("HEAD", "/app.js", &[]);
+    let reply = server.request(
+        "GET",
+        "/app.js",
+        &[("Accept-Encoding", "gzip"), ("Range", "bytes=10-19")],
+    );
+    assert_eq!(reply.status, 206);
+    assert_eq!(reply.header("content-encoding"), None);
+    assert_eq!(reply.header("vary"), Some("Accept-Encoding"));
+    assert_eq!(reply.header("etag"), identity.header("etag"));
+    assert_eq!(reply.body, &script.as_bytes()[10..20]);
+
+    // `If-Range` with the entity tag of the compressed representation does not match
+    let gzip = server.request("HEAD", "/app.js", &[("Accept-Encoding", "gzip")]);
+    let reply = server.request(
+        "GET",
+        "/app.js",
+        &[
+            ("Accept-Encoding", "gzip"),
+            ("Range", "bytes=10-19"),
+            ("If-Range", gzip.header("etag").unwrap()),
+        ],
+    );
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, script.as_bytes());
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[test]
+fn compression_can_be_disabled() {
+    let script = script();
+    let base = common::fixture("compression-disabled", &[("root/app.js", &script)]);
+    let server = TestServer::start(&base.join("root"), |args| args.compress = false);
+
+    let reply = server.request("GET", "/app.js", &[("Accept-Encoding", "gzip")]);
+    assert_eq!(reply.header("content-encoding"), None);
+    assert_eq!(reply.body, script.as_bytes());
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
@@ -0,0 +1,184 @@
+//! HTTP/2 listener, exercised with the `h2` client: with prior knowledge over plain
+//! TCP (`--h2c`) and negotiated with ALPN over TLS
+
+mod common;
+
+use std::fs;
+
+use bytes::Bytes;
+use h2::client::{self, SendRequest};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+use common::TestServer;
+
+/// Response read by `fetch`
+struct Fetched {
+    status: u16,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Fetched {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+/// Connect over plain TCP, retrying while the server starts
+fn connect(port: u16) -> TcpStream {
+    let stream = common::connect(port);
+    stream.set_nonblocking(true).unwrap();
+    TcpStream::from_std(stream).unwrap()
+}
+
+/// Start an HTTP/2 connection over `io`, driven in the background
+async fn handshake<T>(io: T) -> SendRequest<Bytes>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, connection) = client::handshake(io).await.unwrap();
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    client
+}
+
+async fn fetch(client: &SendRequest<Bytes>, method: &str, url: &str) -> Fetched {
+    let req = http::Request::builder()
+        .method(method)
+        .uri(url)
+        .body(())
+        .unwrap();
+    let mut client = client.clone().ready().await.unwrap();
+    let (response, _) = client.send_request(req, true).unwrap();
+    let response = response.await.unwrap();
+
+    let status = response.status().as_u16();
+    let headers = response.headers().clone();
+    let mut stream = response.into_body();
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.data().await {
+        let chunk = chunk.unwrap();
+        body.extend_from_slice(&chunk);
+        stream.flow_control().release_capacity(chunk.len()).unwrap();
+    }
+    Fetched {
+        status,
+        headers,
+        body,
+    }
+}
+
+#[test]
+fn serves_h2c_with_prior_knowledge() {
+    let script = "console.log(\"syserve\");\n".repeat(10_000);
+    let base = common::fixture(
+        "http2-h2c",
+        &[("root/index.html", "index"), ("root/app.js", &script)],
+    );
+    let server = TestServer::start(&base.join("root"), |args| args.h2c = true);
+    let port = server.port;
+
+    runtime().block_on(async {
+        let client = handshake(connect(port)).await;
+        let url = |path: &str| format!("http://localhost:{}{}", port, path);
+
+        let index = fetch(&client, "GET", &url("/")).await;
+        assert_eq!(index.status, 200);
+        assert_eq!(index.body, b"index");
+        assert_eq!(index.header("content-length"), Some("5"));
+        assert_eq!(
+            index.header("content-type"),
+            Some("text/html; charset=utf8")
+        );
+
+        let head = fetch(&client, "HEAD", &url("/index.html")).await;
+        assert_eq!(head.status, 200);
+        assert_eq!(head.header("content-length"), Some("5"));
+        assert!(head.body.is_empty());
+
+        assert_eq!(fetch(&client, "GET", &url("/missing.js")).await.status, 404);
+
+        // Requests on the same connection are answered concurrently
+        let (app_url, index_url) = (url("/app.js"), url("/index.html"));
+        let (app, index) = tokio::join!(
+            fetch(&client, "GET", &app_url),
+            fetch(&client, "GET", &index_url)
+        );
+        assert_eq!(app.status, 200);
+        assert_eq!(app.body, script.as_bytes());
+        assert_eq!(index.body, b"index");
+    });
+
+    let summary = server.stop();
+    assert_eq!(summary.connections, 1);
+    assert_eq!(summary.requests, 5);
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn negotiates_http2_with_alpn() {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    // The local CA issuing the server certificate is created in `<tmp>/ca`
+    let base = common::fixture("http2-alpn", &[("root/index.html", "index")]);
+    let ca_dir = base.join("ca");
+    let server = TestServer::start(&base.join("root"), |args| {
+        args.ca = true;
+        args.ca_dir = Some(ca_dir);
+    });
+    let port = server.port;
+    for _ in 0..50 {
+        if base.join("ca/ca.pem").is_file() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let mut roots = RootCertStore::empty();
+    let ca = fs::read(base.join("ca/ca.pem")).unwrap();
+    for cert in syserve::tls::parse_certs(ca).unwrap() {
+        roots.add(cert).unwrap();
+    }
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let connector = TlsConnector::from(Arc::new(config));
+
+    runtime().block_on(async {
+        let name = ServerName::try_from("localhost").unwrap();
+        let tls = connector.connect(name, connect(port)).await.unwrap();
+        assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+        let client = handshake(tls).await;
+        let url = format!("https://localhost:{}/", port);
+        let index = fetch(&client, "GET", &url).await;
+        assert_eq!(index.status, 200);
+        assert_eq!(index.body, b"index");
+    });
+
+    let summary = server.stop();
+    assert_eq!(summary.requests, 1);
+    let _ = fs::remove_dir_all(&base);
+}
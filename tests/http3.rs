@@ -0,0 +1,64 @@
+//! HTTP/3 listener, exercised on localhost with the bundled client
+#![cfg(feature = "http3")]
+
+mod common;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use syserve::http3::{self, Fetched};
+
+use common::TestServer;
+
+/// Fetch `path`, retrying while the server starts
+fn fetch(method: &str, port: u16, path: &str, ca: &str) -> Fetched {
+    let url = format!("https://localhost:{}{}", port, path);
+    for _ in 0..50 {
+        if let Ok(response) = http3::fetch(method, &url, ca) {
+            return response;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("no HTTP/3 response for {}", url);
+}
+
+#[test]
+fn serves_files_over_http3() {
+    // The local CA issuing the server certificate is created in `<tmp>/ca`
+    let base = common::fixture("http3", &[("root/index.html", "index")]);
+    let ca_dir = base.join("ca");
+    let server = TestServer::start(&base.join("root"), |args| {
+        args.ca = true;
+        args.ca_dir = Some(ca_dir);
+        args.http3 = true;
+    });
+    let port = server.port;
+    // The CA is created as the server starts
+    for _ in 0..50 {
+        if base.join("ca/ca.pem").is_file() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let ca = fs::read_to_string(base.join("ca/ca.pem")).unwrap();
+
+    let index = fetch("GET", port, "/", &ca);
+    assert_eq!(index.status, 200);
+    assert_eq!(index.body, b"index");
+    assert_eq!(index.header("content-length"), Some("5"));
+    let alt_svc = format!("h3=\":{}\"; ma=86400", port);
+    assert_eq!(index.header("alt-svc"), Some(alt_svc.as_str()));
+
+    let head = fetch("HEAD", port, "/index.html", &ca);
+    assert_eq!(head.status, 200);
+    assert!(head.body.is_empty());
+
+    assert_eq!(fetch("GET", port, "/missing.js", &ca).status, 404);
+    assert_eq!(fetch("GET", port, "/../secret.txt", &ca).status, 404);
+
+    let summary = server.stop();
+    assert_eq!(summary.requests, 4);
+    assert_eq!(summary.aborted, 0);
+    let _ = fs::remove_dir_all(&base);
+}
@@ -0,0 +1,60 @@
+//! Persistent connections must not starve the worker pool
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use common::{connect, TestServer};
+
+/// Number of workers of the server's pool
+const WORKERS: usize = 10;
+
+/// Send a request for `/` and read the response head and its 5 bytes body
+fn get(stream: &mut TcpStream) -> String {
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.ends_with(b"\r\n\r\nindex") {
+        match stream.read(&mut buf).unwrap() {
+            0 => break,
+            n => response.extend_from_slice(&buf[..n]),
+        }
+    }
+    String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn idle_connections_give_up_their_worker() {
+    let base = common::fixture("keep-alive", &[("root/index.html", "index")]);
+    let server = TestServer::start(&base.join("root"), |args| args.keep_alive = 30);
+    let port = server.port;
+
+    // Every worker ends up holding an idle persistent connection
+    let mut idle: Vec<TcpStream> = (0..WORKERS).map(|_| connect(port)).collect();
+    for stream in &mut idle {
+        assert!(get(stream).starts_with("HTTP/1.1 200"));
+    }
+
+    let start = Instant::now();
+    let mut stream = connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    assert!(get(&mut stream).starts_with("HTTP/1.1 200"));
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "waited {:?} for a worker",
+        start.elapsed()
+    );
+
+    drop(idle);
+    drop(stream);
+    let summary = server.stop();
+    assert_eq!(summary.requests, WORKERS + 1);
+    let _ = fs::remove_dir_all(&base);
+}
@@ -0,0 +1,51 @@
+//! Method-aware routing: `HEAD`, `OPTIONS` and 405 Method Not Allowed
+
+mod common;
+
+use std::fs;
+
+use common::TestServer;
+
+#[test]
+fn head_sends_the_headers_of_get_without_a_body() {
+    let base = common::fixture("methods-head", &[("root/app.js", "let app = 1;")]);
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let get = server.request("GET", "/app.js", &[]);
+    let head = server.request("HEAD", "/app.js", &[]);
+    assert_eq!(get.status, 200);
+    assert_eq!(get.body, b"let app = 1;");
+    assert_eq!(head.status, 200);
+    assert_eq!(head.header("content-length"), Some("12"));
+    assert_eq!(head.header("content-type"), get.header("content-type"));
+    assert_eq!(head.header("etag"), get.header("etag"));
+    assert!(head.body.is_empty());
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[test]
+fn other_methods_are_not_allowed() {
+    let base = common::fixture("methods-allow", &[("root/index.html", "index")]);
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    for method in &["POST", "PUT", "DELETE", "PATCH"] {
+        let reply = server.request(method, "/index.html", &[("Content-Length", "0")]);
+        assert_eq!(reply.status, 405, "{}", method);
+        assert_eq!(
+            reply.header("allow"),
+            Some("GET, HEAD, OPTIONS"),
+            "{}",
+            method
+        );
+    }
+
+    let options = server.request("OPTIONS", "/index.html", &[]);
+    assert_eq!(options.status, 200);
+    assert_eq!(options.header("allow"), Some("GET, HEAD, OPTIONS"));
+
+    let summary = server.stop();
+    assert_eq!(summary.requests, 5);
+    let _ = fs::remove_dir_all(&base);
+}
@@ -0,0 +1,85 @@
+//! Byte-range requests: 206 Partial Content, `multipart/byteranges` and 416
+
+mod common;
+
+use std::fs;
+
+use common::TestServer;
+
+const CONTENTS: &str = "0123456789abcdefghij";
+
+#[test]
+fn single_range_is_sent_as_is() {
+    let base = common::fixture("ranges-single", &[("root/data.txt", CONTENTS)]);
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let reply = server.request("GET", "/data.txt", &[("Range", "bytes=5-9")]);
+    assert_eq!(reply.status, 206);
+    assert_eq!(reply.header("content-range"), Some("bytes 5-9/20"));
+    assert_eq!(reply.header("content-length"), Some("5"));
+    assert_eq!(reply.body, b"56789");
+
+    let suffix = server.request("GET", "/data.txt", &[("Range", "bytes=-3")]);
+    assert_eq!(suffix.status, 206);
+    assert_eq!(suffix.header("content-range"), Some("bytes 17-19/20"));
+    assert_eq!(suffix.body, b"hij");
+
+    // Ranges only apply to GET
+    let head = server.request("HEAD", "/data.txt", &[("Range", "bytes=5-9")]);
+    assert_eq!(head.status, 200);
+    assert_eq!(head.header("content-length"), Some("20"));
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[test]
+fn multiple_ranges_are_sent_as_multipart() {
+    let base = common::fixture("ranges-multipart", &[("root/data.txt", CONTENTS)]);
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let content_type = server.request("HEAD", "/data.txt", &[]);
+    let content_type = content_type.header("content-type").unwrap();
+    let reply = server.request("GET", "/data.txt", &[("Range", "bytes=0-1,-2")]);
+    assert_eq!(reply.status, 206);
+    assert_eq!(reply.header("content-range"), None);
+    let boundary = reply
+        .header("content-type")
+        .and_then(|value| value.strip_prefix("multipart/byteranges; boundary="))
+        .expect("multipart content type");
+
+    let expected = format!(
+        "--{boundary}\r\nContent-type: {mime}\r\nContent-Range: bytes 0-1/20\r\n\r\n01\r\n\
+         --{boundary}\r\nContent-type: {mime}\r\nContent-Range: bytes 18-19/20\r\n\r\nij\r\n\
+         --{boundary}--\r\n",
+        boundary = boundary,
+        mime = content_type
+    );
+    assert_eq!(String::from_utf8(reply.body.clone()).unwrap(), expected);
+    assert_eq!(
+        reply.header("content-length"),
+        Some(expected.len().to_string().as_str())
+    );
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[test]
+fn unsatisfiable_ranges_are_refused() {
+    let base = common::fixture("ranges-unsatisfiable", &[("root/data.txt", CONTENTS)]);
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let reply = server.request("GET", "/data.txt", &[("Range", "bytes=20-30")]);
+    assert_eq!(reply.status, 416);
+    assert_eq!(reply.header("content-range"), Some("bytes */20"));
+    assert!(reply.body.is_empty());
+
+    // Invalid headers are ignored
+    let reply = server.request("GET", "/data.txt", &[("Range", "bytes=9-0")]);
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, CONTENTS.as_bytes());
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
@@ -0,0 +1,142 @@
+//! Precompressed sidecar files (`.br`, `.zst`, `.gz`) next to served files
+
+mod common;
+
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use common::TestServer;
+
+/// Contents of the original file, too small to be compressed on the fly
+const WASM: &str = "\0asm original";
+
+/// Served directory with `app.wasm` and its sidecars, all modified at the same time
+fn fixture(name: &str) -> std::path::PathBuf {
+    let base = common::fixture(
+        name,
+        &[
+            ("root/app.wasm", WASM),
+            ("root/app.wasm.br", "brotli"),
+            ("root/app.wasm.zst", "zstandard"),
+            ("root/app.wasm.gz", "gzip!"),
+        ],
+    );
+    let now = SystemTime::now();
+    for file in &["app.wasm", "app.wasm.br", "app.wasm.zst", "app.wasm.gz"] {
+        set_modified(&base.join("root").join(file), now);
+    }
+    base
+}
+
+fn set_modified(path: &Path, time: SystemTime) {
+    File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(time)
+        .unwrap();
+}
+
+#[test]
+fn serves_the_preferred_sidecar() {
+    let base = fixture("sidecars-order");
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let cases: &[(&str, Option<&str>, &str)] = &[
+        // Ties are resolved in the order br, zstd, gzip
+        ("gzip, zstd, br", Some("br"), "brotli"),
+        ("gzip, zstd", Some("zstd"), "zstandard"),
+        ("gzip", Some("gzip"), "gzip!"),
+        (
+            "br;q=0.5, zstd;q=0.8, gzip;q=0.1, identity;q=0.1",
+            Some("zstd"),
+            "zstandard",
+        ),
+        // The identity is acceptable with a quality of 1 unless listed
+        ("br;q=0.5, gzip", Some("gzip"), "gzip!"),
+        ("br;q=0.5", None, WASM),
+        ("*", Some("br"), "brotli"),
+        ("br;q=0, *", Some("zstd"), "zstandard"),
+        ("deflate", None, WASM),
+        ("", None, WASM),
+    ];
+    for (accept, coding, body) in cases {
+        let reply = server.request("GET", "/app.wasm", &[("Accept-Encoding", accept)]);
+        assert_eq!(reply.status, 200, "{}", accept);
+        assert_eq!(reply.header("content-encoding"), *coding, "{}", accept);
+        assert_eq!(reply.header("content-type"), Some("application/wasm"));
+        assert_eq!(reply.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(reply.body, body.as_bytes(), "{}", accept);
+    }
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[test]
+fn validators_come_from_the_sidecar() {
+    let base = fixture("sidecars-validators");
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let brotli = server.request("GET", "/app.wasm", &[("Accept-Encoding", "br")]);
+    let gzip = server.request("GET", "/app.wasm", &[("Accept-Encoding", "gzip")]);
+    let identity = server.request("GET", "/app.wasm", &[]);
+    assert_eq!(brotli.header("content-length"), Some("6"));
+    assert_eq!(gzip.header("content-length"), Some("5"));
+    assert_eq!(
+        identity.header("content-length"),
+        Some(WASM.len().to_string().as_str())
+    );
+
+    let etags = [
+        brotli.header("etag").unwrap(),
+        gzip.header("etag").unwrap(),
+        identity.header("etag").unwrap(),
+    ];
+    assert_ne!(etags[0], etags[1]);
+    assert_ne!(etags[0], etags[2]);
+    assert_ne!(etags[1], etags[2]);
+
+    let cached = server.request(
+        "GET",
+        "/app.wasm",
+        &[("Accept-Encoding", "br"), ("If-None-Match", etags[0])],
+    );
+    assert_eq!(cached.status, 304);
+    let stale = server.request(
+        "GET",
+        "/app.wasm",
+        &[("Accept-Encoding", "gzip"), ("If-None-Match", etags[0])],
+    );
+    assert_eq!(stale.status, 200);
+    assert_eq!(stale.body, b"gzip!");
+
+    // A rewritten sidecar gets a new entity tag
+    fs::write(base.join("root/app.wasm.br"), "brotli, rebuilt").unwrap();
+    let rebuilt = server.request("GET", "/app.wasm", &[("Accept-Encoding", "br")]);
+    assert_eq!(rebuilt.header("content-length"), Some("15"));
+    assert_ne!(rebuilt.header("etag"), Some(etags[0]));
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[test]
+fn sidecars_older_than_their_file_are_ignored() {
+    let base = fixture("sidecars-stale");
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    set_modified(&base.join("root/app.wasm.br"), an_hour_ago);
+    let server = TestServer::start(&base.join("root"), |_| {});
+
+    let reply = server.request("GET", "/app.wasm", &[("Accept-Encoding", "br, gzip")]);
+    assert_eq!(reply.header("content-encoding"), Some("gzip"));
+    assert_eq!(reply.body, b"gzip!");
+
+    let reply = server.request("GET", "/app.wasm", &[("Accept-Encoding", "br")]);
+    assert_eq!(reply.header("content-encoding"), None);
+    assert_eq!(reply.body, WASM.as_bytes());
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
@@ -0,0 +1,57 @@
+//! File bodies are streamed in full, with and without `sendfile(2)`
+
+mod common;
+
+use std::fs;
+
+use common::TestServer;
+
+/// Size of the served file, larger than socket buffers and `sendfile(2)` chunks
+const SIZE: usize = 5 * 1024 * 1024 + 7;
+
+/// Bytes that differ at every offset, so that skipped or repeated chunks show
+fn contents() -> Vec<u8> {
+    let mut state: u32 = 1;
+    (0..SIZE)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+fn serves_the_whole_file(name: &str, sendfile: bool) {
+    let base = common::fixture(name, &[]);
+    let contents = contents();
+    fs::create_dir_all(base.join("root")).unwrap();
+    fs::write(base.join("root/app.bin"), &contents).unwrap();
+    let server = TestServer::start(&base.join("root"), |args| args.sendfile = sendfile);
+
+    let reply = server.request("GET", "/app.bin", &[]);
+    assert_eq!(reply.status, 200);
+    assert_eq!(
+        reply.header("content-length"),
+        Some(SIZE.to_string().as_str())
+    );
+    assert!(reply.body == contents, "body differs from the file");
+
+    let range = server.request("GET", "/app.bin", &[("Range", "bytes=1000000-3999999")]);
+    assert_eq!(range.status, 206);
+    assert!(range.body[..] == contents[1_000_000..4_000_000]);
+
+    let head = server.request("HEAD", "/app.bin", &[]);
+    assert!(head.body.is_empty());
+
+    server.stop();
+    let _ = fs::remove_dir_all(&base);
+}
+
+#[test]
+fn streams_file_bodies() {
+    serves_the_whole_file("streaming-read", false);
+}
+
+#[test]
+fn sends_file_bodies_with_sendfile() {
+    serves_the_whole_file("streaming-sendfile", true);
+}
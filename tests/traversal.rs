@@ -0,0 +1,160 @@
+//! Regression tests for path traversal payloads
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use syserve::sandbox::{Sandbox, SymlinkPolicy, Violation};
+use syserve::url::Url;
+
+/// Temporary layout:
+/// - `<tmp>/root/index.html`
+/// - `<tmp>/root/assets/app.js`
+/// - `<tmp>/secret.txt`, outside the served directory
+/// - `<tmp>/root/inside` -> `assets/app.js` and `<tmp>/root/outside` -> `../secret.txt`
+fn fixture(name: &str) -> PathBuf {
+    let base = common::fixture(
+        &format!("traversal-{}", name),
+        &[
+            ("root/index.html", "index"),
+            ("root/assets/app.js", "app"),
+            ("secret.txt", "secret"),
+        ],
+    );
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink("assets/app.js", base.join("root/inside")).unwrap();
+        std::os::unix::fs::symlink("../secret.txt", base.join("root/outside")).unwrap();
+    }
+
+    base
+}
+
+/// Resolve a request target the way the server does
+fn resolve(sandbox: &Sandbox, target: &str) -> Option<PathBuf> {
+    let url = Url::parse(target).ok()?;
+    sandbox.resolve(&url.segments).ok()
+}
+
+const PAYLOADS: &[&str] = &[
+    "/../secret.txt",
+    "/../../../../etc/passwd",
+    "/assets/../../secret.txt",
+    "/..%2Fsecret.txt",
+    "/..%2fsecret.txt",
+    "/%2e%2e/secret.txt",
+    "/%2E%2E/%2E%2E/etc/passwd",
+    "/.%2e/secret.txt",
+    "/....//secret.txt",
+    "/..././secret.txt",
+    "/..\\secret.txt",
+    "/..%5Csecret.txt",
+    "/%252e%252e/secret.txt",
+    "//secret.txt",
+    "///etc/passwd",
+    "/%2Fetc%2Fpasswd",
+    "/secret.txt%00.html",
+    "/%C0%AE%C0%AE/secret.txt",
+    "http://localhost/../secret.txt",
+    "../secret.txt",
+    "\\..\\secret.txt",
+];
+
+#[test]
+fn payloads_stay_inside_root() {
+    let base = fixture("payloads");
+    let sandbox = Sandbox::new(&base.join("root"), SymlinkPolicy::default()).unwrap();
+
+    for payload in PAYLOADS {
+        if let Some(path) = resolve(&sandbox, payload) {
+            assert!(
+                path.starts_with(sandbox.root()),
+                "{:?} resolved to {:?}",
+                payload,
+                path
+            );
+            assert!(
+                !path.ends_with("secret.txt") || !path.exists(),
+                "{:?} reached the secret",
+                payload
+            );
+        }
+    }
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn regular_paths_resolve() {
+    let base = fixture("regular");
+    let sandbox = Sandbox::new(&base.join("root"), SymlinkPolicy::default()).unwrap();
+
+    let root = sandbox.root().to_path_buf();
+    assert_eq!(
+        resolve(&sandbox, "/assets/app.js"),
+        Some(root.join("assets/app.js"))
+    );
+    assert_eq!(
+        resolve(&sandbox, "/assets/./app.js?v=1"),
+        Some(root.join("assets/app.js"))
+    );
+    assert_eq!(
+        resolve(&sandbox, "/x/../index.html"),
+        Some(root.join("index.html"))
+    );
+    assert_eq!(
+        resolve(&sandbox, "/missing.js"),
+        Some(root.join("missing.js"))
+    );
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_policies() {
+    let base = fixture("symlinks");
+    let root = base.join("root");
+
+    let follow = Sandbox::new(&root, SymlinkPolicy::Follow).unwrap();
+    assert!(follow.resolve(&["inside"]).is_ok());
+    assert!(follow.resolve(&["outside"]).is_ok());
+
+    let within = Sandbox::new(&root, SymlinkPolicy::FollowWithinRoot).unwrap();
+    assert!(within.resolve(&["inside"]).is_ok());
+    assert_eq!(within.resolve(&["outside"]), Err(Violation::OutsideRoot));
+
+    let deny = Sandbox::new(&root, SymlinkPolicy::Deny).unwrap();
+    assert!(deny.resolve(&["assets", "app.js"]).is_ok());
+    assert!(matches!(
+        deny.resolve(&["inside"]),
+        Err(Violation::Symlink(_))
+    ));
+    assert!(matches!(
+        deny.resolve(&["outside"]),
+        Err(Violation::Symlink(_))
+    ));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn invalid_segments_are_refused() {
+    let base = fixture("segments");
+    let sandbox = Sandbox::new(&base.join("root"), SymlinkPolicy::default()).unwrap();
+
+    for segment in &["..", ".", "", "/etc", "a/b"] {
+        assert!(
+            matches!(
+                sandbox.resolve(&[segment]),
+                Err(Violation::InvalidSegment(_))
+            ),
+            "{:?} was accepted",
+            segment
+        );
+    }
+
+    fs::remove_dir_all(base).unwrap();
+}